@@ -0,0 +1,482 @@
+use database;
+use crypto::*;
+
+extern crate chrono;
+extern crate postgres;
+extern crate rand;
+extern crate byteorder;
+
+use self::chrono::*;
+
+use self::postgres::{Connection};
+
+use self::rand::{Rng};
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use util::{NBYTES_U64, canonical_ip};
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv6Addr};
+
+pub const NODE_NETWORK: u64 = 1;
+pub const NET_ADDR_LEN: usize = 16 + 2 + NBYTES_U64 + NBYTES_U64;
+pub const MAX_ADDRS: usize = 1000;
+pub const NEW_BUCKET_COUNT: usize = 256;
+pub const TRIED_BUCKET_COUNT: usize = 64;
+pub const BUCKET_SIZE: usize = 64;
+const NEW_BUCKETS_PER_SOURCE_GROUP: u64 = 32;
+const TRIED_BUCKETS_PER_GROUP: u64 = 8;
+const HORIZON: i64 = 30 * 24 * 60 * 60;
+const RETRY_DELAY: i64 = 10 * 60;
+const MAX_FAILURES: u32 = 3;
+const SELECT_TRIES: usize = 200;
+
+// AN ADDRESS AS GOSSIPED: IP AS 16 BYTES (IPV4 MAPPED INTO IPV6), PORT,
+// SERVICE FLAGS AND WHEN THE ADDRESS WAS LAST KNOWN TO BE ACTIVE
+#[derive(Clone, PartialEq, Debug)]
+pub struct NetAddr
+{
+    pub ip:         String,
+    pub port:       i32,
+    pub services:   u64,
+    pub timestamp:  i64
+}
+
+impl NetAddr
+{
+    pub fn from_slice(bytes: &[u8]) -> Option<NetAddr>
+    {
+        if bytes.len() != NET_ADDR_LEN { return None; }
+        let mut octets = [0; 16];
+        octets.clone_from_slice(&bytes[..16]);
+        let ip = canonical_ip(IpAddr::V6(Ipv6Addr::from(octets)));
+        let port = LittleEndian::read_u16(&bytes[16..18]);
+        if port == 0 || ip.is_unspecified() { return None; }
+        Some(NetAddr {
+            ip: ip.to_string(),
+            port: port as i32,
+            services: LittleEndian::read_u64(&bytes[18..18+NBYTES_U64]),
+            timestamp: LittleEndian::read_i64(&bytes[18+NBYTES_U64..])
+        })
+    }
+
+    pub fn to_vec(&self) -> Vec<u8>
+    {
+        let octets = match self.ip.parse::<IpAddr>()
+        {
+            Ok(IpAddr::V4(v4)) => { v4.to_ipv6_mapped().octets() }
+            Ok(IpAddr::V6(v6)) => { v6.octets() }
+            Err(_) => { [0; 16] }
+        };
+        let mut buf = [0; NET_ADDR_LEN];
+        buf[..16].clone_from_slice(&octets);
+        LittleEndian::write_u16(&mut buf[16..18], self.port as u16);
+        LittleEndian::write_u64(&mut buf[18..18+NBYTES_U64], self.services);
+        LittleEndian::write_i64(&mut buf[18+NBYTES_U64..], self.timestamp);
+        buf.to_vec()
+    }
+}
+
+pub fn addrs_to_vec(addrs: &[NetAddr]) -> Vec<u8>
+{
+    let mut array = vec![];
+    let mut count = [0; 2];
+    LittleEndian::write_u16(&mut count, addrs.len() as u16);
+    array.extend_from_slice(&count);
+    for addr in addrs.iter()
+    {
+        array.extend_from_slice(&addr.to_vec());
+    }
+    array
+}
+
+// INVALID ENTRIES ARE SKIPPED; A BAD COUNT OR LENGTH REJECTS THE WHOLE MESSAGE
+pub fn addrs_from_slice(bytes: &[u8]) -> Option<Vec<NetAddr>>
+{
+    if bytes.len() < 2 { return None; }
+    let count = LittleEndian::read_u16(&bytes[..2]) as usize;
+    if count > MAX_ADDRS || bytes.len() != 2 + count * NET_ADDR_LEN { return None; }
+
+    Some((0..count)
+        .filter_map(|i| NetAddr::from_slice(&bytes[2+i*NET_ADDR_LEN..2+(i+1)*NET_ADDR_LEN]))
+        .collect())
+}
+
+#[derive(Clone, Debug)]
+pub struct AddrInfo
+{
+    pub ip:             String,
+    pub port:           i32,
+    pub services:       u64,
+    pub source:         String,
+    pub last_seen:      i64,
+    pub last_success:   i64,
+    pub last_attempt:   i64,
+    pub attempts:       u32,
+    pub tried:          bool
+}
+
+impl AddrInfo
+{
+    pub fn new(ip: String, port: i32, services: u64, source: String, last_seen: i64) -> AddrInfo
+    {
+        AddrInfo {
+            ip: ip,
+            port: port,
+            services: services,
+            source: source,
+            last_seen: last_seen,
+            last_success: 0,
+            last_attempt: 0,
+            attempts: 0,
+            tried: false
+        }
+    }
+
+    // NOT WORTH KEEPING: NOT HEARD OF IN A MONTH, OR NEVER REACHED AFTER SEVERAL TRIES
+    fn is_terrible(&self, now: i64) -> bool
+    {
+        if now - self.last_attempt < 60
+        {
+            return false;
+        }
+        self.last_seen > now + 10 * 60 ||
+        now - self.last_seen > HORIZON ||
+        (self.last_success == 0 && self.attempts >= MAX_FAILURES)
+    }
+
+    fn chance(&self, now: i64) -> f64
+    {
+        let mut chance = 1.0;
+        if now - self.last_attempt < RETRY_DELAY
+        {
+            chance *= 0.01;
+        }
+        chance * 0.66f64.powi(if self.attempts < 8 { self.attempts as i32 } else { 8 })
+    }
+
+    pub fn to_net_addr(&self) -> NetAddr
+    {
+        NetAddr {
+            ip: self.ip.clone(),
+            port: self.port,
+            services: self.services,
+            timestamp: self.last_seen
+        }
+    }
+}
+
+// THE /16 FOR IPV4 AND THE /32 FOR IPV6. ADDRESSES IN ONE GROUP ARE USUALLY
+// UNDER ONE OPERATOR'S CONTROL, SO THEY SHARE A SMALL NUMBER OF BUCKETS.
+pub fn network_group(ip: &str) -> Vec<u8>
+{
+    match ip.parse::<IpAddr>()
+    {
+        Ok(IpAddr::V4(v4)) => { vec![4, v4.octets()[0], v4.octets()[1]] }
+        Ok(IpAddr::V6(v6)) =>
+        {
+            let octets = v6.octets();
+            vec![6, octets[0], octets[1], octets[2], octets[3]]
+        }
+        Err(_) => { vec![0] }
+    }
+}
+
+// KNOWN PEER ADDRESSES. GOSSIPED ADDRESSES GO INTO "NEW" BUCKETS CHOSEN BY THEIR
+// NETWORK GROUP AND THE GROUP OF WHOEVER TOLD US ABOUT THEM, AND MOVE TO "TRIED"
+// BUCKETS ONCE WE'VE CONNECTED. BUCKET POSITIONS ARE KEYED WITH A LOCAL SECRET, SO
+// A SINGLE SOURCE CAN'T FLOOD MORE THAN A FEW BUCKETS OR PREDICT WHICH ONES, AND
+// SELECTION PICKS A BUCKET BEFORE AN ENTRY. CHANGES ARE WRITTEN TO THE peers TABLE
+// BY flush().
+pub struct AddrMan
+{
+    key:            [u8; 32],
+    entries:        HashMap<(String, i32), AddrInfo>,
+    buckets:        HashMap<(String, i32), usize>,
+    // WHO IS IN EACH NEW AND TRIED BUCKET, KEPT IN STEP WITH buckets
+    new_table:      HashMap<usize, Vec<(String, i32)>>,
+    tried_table:    HashMap<usize, Vec<(String, i32)>>,
+    dirty:          HashSet<(String, i32)>,
+    removed:        HashSet<(String, i32)>
+}
+
+impl AddrMan
+{
+    pub fn new() -> AddrMan
+    {
+        let mut key = [0; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        AddrMan {
+            key: key,
+            entries: HashMap::new(),
+            buckets: HashMap::new(),
+            new_table: HashMap::new(),
+            tried_table: HashMap::new(),
+            dirty: HashSet::new(),
+            removed: HashSet::new()
+        }
+    }
+
+    pub fn load(db: &Connection) -> AddrMan
+    {
+        let mut addrman = AddrMan::new();
+        for info in database::addresses(db)
+        {
+            addrman.insert(info);
+        }
+        addrman.dirty.clear();
+        println!("Loaded {} addresses, {} tried", addrman.len(), addrman.tried_len());
+        addrman
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.entries.len()
+    }
+
+    pub fn tried_len(&self) -> usize
+    {
+        self.entries.values().filter(|info| info.tried).count()
+    }
+
+    // RETURNS TRUE IF THE ADDRESS IS NEW TO US OR WE'VE HEARD OF IT MORE RECENTLY
+    pub fn add(&mut self, addr: &NetAddr, source: &str) -> bool
+    {
+        let now = UTC::now().timestamp();
+        let last_seen = if addr.timestamp > now { now } else { addr.timestamp };
+        let key = (addr.ip.clone(), addr.port);
+        if let Some(info) = self.entries.get_mut(&key)
+        {
+            if info.services | addr.services != info.services
+            {
+                info.services |= addr.services;
+                self.dirty.insert(key.clone());
+            }
+            if last_seen > info.last_seen
+            {
+                info.last_seen = last_seen;
+                self.dirty.insert(key);
+                return true;
+            }
+            return false;
+        }
+        self.insert(AddrInfo::new(addr.ip.clone(), addr.port, addr.services, source.to_string(), last_seen));
+        true
+    }
+
+    // WE CONNECTED TO IT, SO IT BELONGS IN THE TRIED TABLE
+    pub fn good(&mut self, ip: &str, port: i32)
+    {
+        let now = UTC::now().timestamp();
+        let key = (ip.to_string(), port);
+        if !self.entries.contains_key(&key)
+        {
+            self.insert(AddrInfo::new(ip.to_string(), port, NODE_NETWORK, ip.to_string(), now));
+        }
+        let was_tried = {
+            let info = self.entries.get_mut(&key).unwrap();
+            info.last_seen = now;
+            info.last_success = now;
+            info.last_attempt = now;
+            info.attempts = 0;
+            let was_tried = info.tried;
+            info.tried = true;
+            was_tried
+        };
+        if !was_tried
+        {
+            self.unbucket(&key, false);
+            let info = self.entries.remove(&key).unwrap();
+            self.insert(info);
+        }
+        self.dirty.insert(key);
+    }
+
+    pub fn attempt(&mut self, ip: &str, port: i32)
+    {
+        let key = (ip.to_string(), port);
+        if let Some(info) = self.entries.get_mut(&key)
+        {
+            info.last_attempt = UTC::now().timestamp();
+            info.attempts += 1;
+            self.dirty.insert(key);
+        }
+    }
+
+    // PICKS A TRIED OR NEW ADDRESS WITH EQUAL ODDS, THEN A RANDOM BUCKET, THEN A
+    // RANDOM ENTRY IN IT. RECENTLY FAILED ADDRESSES ARE USUALLY PASSED OVER.
+    pub fn select<F>(&self, exclude: F) -> Option<AddrInfo>
+        where F: Fn(&AddrInfo) -> bool
+    {
+        let now = UTC::now().timestamp();
+        let mut rng = rand::thread_rng();
+
+        let mut tried: HashMap<usize, Vec<&AddrInfo>> = HashMap::new();
+        let mut new: HashMap<usize, Vec<&AddrInfo>> = HashMap::new();
+        for (key, info) in self.entries.iter()
+        {
+            if exclude(info) { continue; }
+            let bucket = self.buckets[key];
+            if info.tried
+            {
+                tried.entry(bucket).or_insert(vec![]).push(info);
+            }
+            else
+            {
+                new.entry(bucket).or_insert(vec![]).push(info);
+            }
+        }
+
+        let mut factor = 1.0;
+        for _ in 0..SELECT_TRIES
+        {
+            let use_tried = if tried.is_empty() { false } else if new.is_empty() { true } else { rng.gen() };
+            let table = if use_tried { &tried } else { &new };
+            if table.is_empty()
+            {
+                return None;
+            }
+            let buckets: Vec<&usize> = table.keys().collect();
+            let bucket = &table[buckets[rng.gen_range(0, buckets.len())]];
+            let info = bucket[rng.gen_range(0, bucket.len())];
+            if rng.gen::<f64>() < factor * info.chance(now)
+            {
+                return Some(info.clone());
+            }
+            factor *= 1.2;
+        }
+        None
+    }
+
+    // ADDRESSES TO SHARE WITH OTHER NODES
+    pub fn sample(&self, max: usize) -> Vec<NetAddr>
+    {
+        let now = UTC::now().timestamp();
+        let mut addrs: Vec<NetAddr> = self.entries
+            .values()
+            .filter(|info| !info.is_terrible(now))
+            .map(|info| info.to_net_addr())
+            .collect();
+        rand::thread_rng().shuffle(&mut addrs);
+        addrs.truncate(max);
+        addrs
+    }
+
+    pub fn flush(&mut self, db: &Connection)
+    {
+        for key in self.removed.drain()
+        {
+            database::delete_address(&key.0, key.1, db);
+        }
+        for key in self.dirty.drain()
+        {
+            if let Some(info) = self.entries.get(&key)
+            {
+                database::upsert_address(info, db);
+            }
+        }
+    }
+
+    fn insert(&mut self, info: AddrInfo)
+    {
+        let key = (info.ip.clone(), info.port);
+        let tried = info.tried;
+        let bucket = if tried { self.tried_bucket(&info) } else { self.new_bucket(&info) };
+
+        let occupants: Vec<(String, i32)> = self.table(tried).get(&bucket).cloned().unwrap_or(vec![]);
+        if occupants.len() >= BUCKET_SIZE
+        {
+            let now = UTC::now().timestamp();
+            let victim = occupants
+                .iter()
+                .find(|k| self.entries[*k].is_terrible(now))
+                .cloned()
+                .or_else(|| occupants.iter().min_by_key(|k| {
+                    let info = &self.entries[*k];
+                    if tried { info.last_success } else { info.last_seen }
+                }).cloned())
+                .unwrap();
+
+            self.unbucket(&victim, tried);
+            let mut evicted = self.entries.remove(&victim).unwrap();
+            if tried
+            {
+                // A TRIED ADDRESS THAT LOSES ITS SLOT GOES BACK TO THE NEW TABLE
+                evicted.tried = false;
+                self.insert(evicted);
+            }
+            else
+            {
+                self.dirty.remove(&victim);
+                self.removed.insert(victim);
+            }
+        }
+
+        self.removed.remove(&key);
+        self.dirty.insert(key.clone());
+        self.buckets.insert(key.clone(), bucket);
+        self.table(tried).entry(bucket).or_insert(vec![]).push(key.clone());
+        self.entries.insert(key, info);
+    }
+
+    fn table(&mut self, tried: bool) -> &mut HashMap<usize, Vec<(String, i32)>>
+    {
+        if tried { &mut self.tried_table } else { &mut self.new_table }
+    }
+
+    // TAKES key OUT OF ITS BUCKET IN THE NEW OR TRIED TABLE
+    fn unbucket(&mut self, key: &(String, i32), tried: bool)
+    {
+        let bucket = match self.buckets.remove(key)
+        {
+            Some(bucket) => { bucket }
+            None => { return; }
+        };
+        let table = self.table(tried);
+        let empty = match table.get_mut(&bucket)
+        {
+            Some(occupants) =>
+            {
+                occupants.retain(|k| k != key);
+                occupants.is_empty()
+            }
+            None => { false }
+        };
+        if empty
+        {
+            table.remove(&bucket);
+        }
+    }
+
+    fn new_bucket(&self, info: &AddrInfo) -> usize
+    {
+        let source_group = network_group(&info.source);
+        let group = network_group(&info.ip);
+        let slot = self.hash(&[&group, &source_group]) % NEW_BUCKETS_PER_SOURCE_GROUP;
+        let mut slot_bytes = [0; 8];
+        LittleEndian::write_u64(&mut slot_bytes, slot);
+        (self.hash(&[&source_group, &slot_bytes]) % NEW_BUCKET_COUNT as u64) as usize
+    }
+
+    fn tried_bucket(&self, info: &AddrInfo) -> usize
+    {
+        let group = network_group(&info.ip);
+        let addr = format!("{}:{}", info.ip, info.port);
+        let slot = self.hash(&[addr.as_bytes()]) % TRIED_BUCKETS_PER_GROUP;
+        let mut slot_bytes = [0; 8];
+        LittleEndian::write_u64(&mut slot_bytes, slot);
+        (self.hash(&[&group, &slot_bytes]) % TRIED_BUCKET_COUNT as u64) as usize
+    }
+
+    fn hash(&self, parts: &[&[u8]]) -> u64
+    {
+        let mut buf = self.key.to_vec();
+        for part in parts
+        {
+            buf.push(part.len() as u8);
+            buf.extend_from_slice(part);
+        }
+        LittleEndian::read_u64(&digest_sha256(&buf)[..8])
+    }
+}
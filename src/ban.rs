@@ -0,0 +1,86 @@
+use database;
+use database::{Store};
+
+extern crate chrono;
+extern crate postgres;
+
+use self::chrono::*;
+
+use self::postgres::{Connection};
+
+use std::collections::{HashMap};
+
+pub const BAN_THRESHOLD: u32 = 100;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Misbehaviour
+{
+    InvalidBlock,
+    InvalidHeaders,
+    InvalidTransaction,
+    MalformedMessage,
+    IdentityMismatch,
+    ChatFlood
+}
+
+impl Misbehaviour
+{
+    pub fn score(&self) -> u32
+    {
+        match *self
+        {
+            Misbehaviour::InvalidBlock => { 100 }
+            Misbehaviour::InvalidHeaders => { 50 }
+            Misbehaviour::InvalidTransaction => { 10 }
+            Misbehaviour::MalformedMessage => { 20 }
+            Misbehaviour::IdentityMismatch => { 100 }
+            Misbehaviour::ChatFlood => { 10 }
+        }
+    }
+}
+
+// BANNED IPS AND WHEN EACH BAN EXPIRES; WRITTEN THROUGH TO THE bans TABLE
+pub struct BanList
+{
+    bans: HashMap<String, i64>
+}
+
+impl BanList
+{
+    pub fn new() -> BanList
+    {
+        BanList {
+            bans: HashMap::new()
+        }
+    }
+
+    pub fn load(db: &Connection) -> BanList
+    {
+        let now = UTC::now().timestamp();
+        database::delete_expired_bans(now, db);
+        let mut bans = BanList::new();
+        bans.bans = database::bans(db).into_iter().collect();
+        bans
+    }
+
+    pub fn is_banned(&self, ip: &str) -> bool
+    {
+        match self.bans.get(ip)
+        {
+            Some(&expiry) => { expiry > UTC::now().timestamp() }
+            None => { false }
+        }
+    }
+
+    pub fn ban(
+        &mut self,
+        ip: &str,
+        duration: u64,
+        db: &dyn Store)
+    {
+        let expiry = UTC::now().timestamp() + duration as i64;
+        println!("Banning {} for {}s", ip, duration);
+        db.upsert_ban(ip, expiry);
+        self.bans.insert(ip.to_string(), expiry);
+    }
+}
@@ -0,0 +1,142 @@
+extern crate chrono;
+
+use self::chrono::*;
+
+use std::collections::{HashMap};
+use std::sync::{Arc, Mutex};
+
+// BYTES ON THE WIRE, FRAMING INCLUDED, IN EACH DIRECTION AND BY COMMAND
+#[derive(Clone, Debug, PartialEq)]
+pub struct Traffic
+{
+    pub sent:       u64,
+    pub recv:       u64,
+    pub by_command: HashMap<[u8; 12], (u64, u64)>
+}
+
+impl Traffic
+{
+    pub fn new() -> Traffic
+    {
+        Traffic {
+            sent: 0,
+            recv: 0,
+            by_command: HashMap::new()
+        }
+    }
+
+    pub fn record_sent(&mut self, command: &[u8; 12], nbytes: usize)
+    {
+        self.sent += nbytes as u64;
+        self.by_command.entry(*command).or_insert((0, 0)).0 += nbytes as u64;
+    }
+
+    pub fn record_recv(&mut self, command: &[u8; 12], nbytes: usize)
+    {
+        self.recv += nbytes as u64;
+        self.by_command.entry(*command).or_insert((0, 0)).1 += nbytes as u64;
+    }
+
+    // BIGGEST FIRST
+    pub fn commands(&self) -> Vec<([u8; 12], u64, u64)>
+    {
+        let mut commands: Vec<([u8; 12], u64, u64)> = self.by_command
+            .iter()
+            .map(|(command, &(sent, recv))| (*command, sent, recv))
+            .collect();
+        commands.sort_by(|a, b| (b.1 + b.2).cmp(&(a.1 + a.2)).then(a.0.cmp(&b.0)));
+        commands
+    }
+}
+
+#[derive(Debug)]
+struct Meter
+{
+    totals:         Traffic,
+    budget:         u64,
+    window:         i64,
+    window_start:   i64,
+    window_sent:    u64
+}
+
+// THE WHOLE NODE'S TRAFFIC. EVERY PEER HOLDS A CLONE, SO BYTES STILL COUNT
+// AFTER THE PEER THAT SENT THEM IS GONE, AND THE UPLOAD BUDGET SEES THEM ALL.
+#[derive(Clone, Debug)]
+pub struct Bandwidth
+{
+    meter: Arc<Mutex<Meter>>
+}
+
+impl Bandwidth
+{
+    // budget BYTES OF UPLOAD PER window SECONDS; A budget OF 0 IS UNLIMITED
+    pub fn new(budget: u64, window: u64) -> Bandwidth
+    {
+        Bandwidth {
+            meter: Arc::new(Mutex::new(Meter {
+                totals: Traffic::new(),
+                budget: budget,
+                window: window as i64,
+                // THE FIRST WINDOW STARTS WITH THE FIRST BYTE SENT
+                window_start: 0,
+                window_sent: 0
+            }))
+        }
+    }
+
+    pub fn record_sent(&self, command: &[u8; 12], nbytes: usize)
+    {
+        self.record_sent_at(command, nbytes, UTC::now().timestamp());
+    }
+
+    pub fn record_sent_at(&self, command: &[u8; 12], nbytes: usize, now: i64)
+    {
+        let mut meter = self.meter.lock().unwrap();
+        meter.roll(now);
+        meter.totals.record_sent(command, nbytes);
+        meter.window_sent += nbytes as u64;
+    }
+
+    pub fn record_recv(&self, command: &[u8; 12], nbytes: usize)
+    {
+        self.meter.lock().unwrap().totals.record_recv(command, nbytes);
+    }
+
+    pub fn totals(&self) -> Traffic
+    {
+        self.meter.lock().unwrap().totals.clone()
+    }
+
+    // ONCE THE WINDOW'S BUDGET IS SPENT WE STOP SERVING OLD BLOCKS UNTIL THE
+    // NEXT WINDOW; NEW BLOCKS AND EVERYTHING ELSE STILL GO OUT
+    pub fn upload_exhausted(&self) -> bool
+    {
+        self.upload_exhausted_at(UTC::now().timestamp())
+    }
+
+    pub fn upload_exhausted_at(&self, now: i64) -> bool
+    {
+        let mut meter = self.meter.lock().unwrap();
+        meter.roll(now);
+        meter.budget > 0 && meter.window_sent >= meter.budget
+    }
+
+    // BYTES SENT IN THE CURRENT WINDOW, AND THE BUDGET
+    pub fn upload_usage(&self) -> (u64, u64)
+    {
+        let meter = self.meter.lock().unwrap();
+        (meter.window_sent, meter.budget)
+    }
+}
+
+impl Meter
+{
+    fn roll(&mut self, now: i64)
+    {
+        if now - self.window_start >= self.window
+        {
+            self.window_start = now;
+            self.window_sent = 0;
+        }
+    }
+}
@@ -1,14 +1,27 @@
 extern crate ring;
 extern crate untrusted;
 extern crate byteorder;
+extern crate num;
 
 use self::ring::{digest};
 
+use self::num::bigint::{BigUint, ToBigUint};
+
 use self::byteorder::{ByteOrder, LittleEndian};
 
 use transaction::*;
 use util::{NBYTES_U64};
 
+use std::cmp;
+
+pub const HEADER_LEN: usize = 144;
+// HOW MANY TIMES EASIER A BLOCK'S TARGET MAY BE THAN ITS PARENT'S
+pub const MAX_RETARGET: u32 = 4;
+// txs_hash IS THE MERKLE ROOT OF THE TRANSACTIONS. BLOCKS FROM BEFORE THIS
+// TIME MAY STILL CARRY THE OLD FLAT HASH OF ALL THEIR TRANSACTION HASHES, SO
+// THE CHAIN MINED BEFORE THE SWITCH STAYS VALID. 2027-01-01 00:00 UTC.
+pub const MERKLE_TXS_HASH_TIME: i64 = 1798761600;
+
 #[derive(PartialEq, Clone)]
 pub struct Block
 {
@@ -70,6 +83,25 @@ impl Block
         block
     }
 
+    // CHECKS THE LENGTHS FROM_SLICE RELIES ON BEFORE PARSING UNTRUSTED BYTES
+    pub fn try_from_slice(bytes: &[u8]) -> Option<Block>
+    {
+        if bytes.len() < 36 { return None; }
+        let ntxs = LittleEndian::read_u32(&bytes[32..36]) as usize;
+        let mut idx: usize = 36;
+        for _ in 0..ntxs
+        {
+            if bytes.len() < idx + 4 { return None; }
+            let tx_len = LittleEndian::read_u32(&bytes[idx..idx+4]) as usize;
+            idx += 4;
+            if bytes.len() < idx + tx_len { return None; }
+            if Transaction::try_from_slice(&bytes[idx..idx+tx_len]).is_none() { return None; }
+            idx += tx_len;
+        }
+        if bytes.len() != idx + HEADER_LEN - 32 { return None; }
+        Some(Block::from_slice(bytes))
+    }
+
     pub fn from_slice(bytes: &[u8]) -> Block
     {
         let txs_hash_len = 32;
@@ -133,8 +165,8 @@ impl Block
         for tx in self.txs.iter()
         {
             let tx_vec = tx.to_vec();
-            let tx_len = [0; 4];
-            LittleEndian::write_u32(&mut ntxs_buf, tx_vec.len() as u32);
+            let mut tx_len = [0; 4];
+            LittleEndian::write_u32(&mut tx_len, tx_vec.len() as u32);
             array.extend_from_slice(&tx_len);
             array.extend_from_slice(&tx_vec);
         }
@@ -156,6 +188,43 @@ impl Block
         array
     }
 
+    pub fn header_from_slice(bytes: &[u8]) -> Block
+    {
+        Block::new(
+            &bytes[..32],
+            vec![],
+            &bytes[32..64],
+            &bytes[64..96],
+            LittleEndian::read_i64(&bytes[96..104]),
+            LittleEndian::read_i64(&bytes[104..112]),
+            &bytes[112..HEADER_LEN])
+    }
+
+    pub fn header_to_vec(&self) -> Vec<u8>
+    {
+        let mut array = vec![];
+        array.extend_from_slice(&self.txs_hash);
+        array.extend_from_slice(&self.parent_hash);
+        array.extend_from_slice(&self.target);
+
+        let mut ts_buf = [0; NBYTES_U64];
+        LittleEndian::write_i64(&mut ts_buf, self.timestamp);
+        array.extend_from_slice(&ts_buf);
+
+        let mut nonce_buf = [0; NBYTES_U64];
+        LittleEndian::write_i64(&mut nonce_buf, self.nonce);
+        array.extend_from_slice(&nonce_buf);
+
+        array.extend_from_slice(&self.block_hash);
+
+        array
+    }
+
+    pub fn verify_header(&self) -> bool
+    {
+        self.compute_hash() == self.block_hash.to_vec() && self.block_hash < self.target
+    }
+
     pub fn verify(&mut self) -> bool
     {
         let mut tx_verify = true;
@@ -166,13 +235,44 @@ impl Block
         tx_verify && self.compute_hash() == self.block_hash.to_vec()
     }
 
+    // THE HEADER COMMITS TO txs_hash, SO THIS TIES THE TRANSACTIONS TO THE PROOF OF WORK
+    pub fn verify_txs_hash(&self) -> bool
+    {
+        let txs_hash = self.txs_hash.to_vec();
+        self.compute_txs_hash() == txs_hash
+            || (self.timestamp < MERKLE_TXS_HASH_TIME && self.compute_flat_txs_hash() == txs_hash)
+    }
+
     pub fn update_hash(&mut self)
     {
         let hash = self.compute_hash();
         self.block_hash.clone_from_slice(&hash);
     }
 
+    // THE SIBLINGS A LIGHT CLIENT NEEDS TO GET FROM THE idx'TH TRANSACTION TO txs_hash
+    pub fn merkle_branch(&self, idx: usize) -> Vec<[u8; 32]>
+    {
+        let mut level: Vec<[u8; 32]> = self.txs.iter().map(|tx| tx.hash).collect();
+        let mut branch = vec![];
+        let mut idx = idx;
+        while level.len() > 1
+        {
+            branch.push(level[cmp::min(idx ^ 1, level.len() - 1)]);
+            level = merkle_level(&level);
+            idx /= 2;
+        }
+        branch
+    }
+
     fn compute_txs_hash(&self) -> Vec<u8>
+    {
+        let hashes: Vec<[u8; 32]> = self.txs.iter().map(|tx| tx.hash).collect();
+        merkle_root(&hashes).to_vec()
+    }
+
+    // WHAT txs_hash WAS BEFORE MERKLE_TXS_HASH_TIME. A LIGHT CLIENT CAN'T GET
+    // A PROOF AGAINST IT, ONLY THE WHOLE BLOCK.
+    fn compute_flat_txs_hash(&self) -> Vec<u8>
     {
         let txs_hash_bytes: Vec<u8> = self.txs.iter().flat_map(|x| x.hash.to_vec()).collect();
         digest::digest(&digest::SHA256, &txs_hash_bytes).as_ref().to_vec()
@@ -193,3 +293,67 @@ impl Block
         digest::digest(&digest::SHA256, &block_buf).as_ref().to_vec()
     }
 }
+
+// THE EASIEST TARGET A CHILD OF A BLOCK WITH parent_target MAY HAVE. WITHOUT
+// IT A HEADER'S PROOF OF WORK ONLY HAS TO MEET WHATEVER TARGET IT CLAIMS.
+pub fn max_target(parent_target: &[u8; 32]) -> [u8; 32]
+{
+    let target = BigUint::from_bytes_be(parent_target) * MAX_RETARGET.to_biguint().unwrap();
+    let bytes = target.to_bytes_be();
+    if bytes.len() > 32
+    {
+        return [0xff; 32];
+    }
+    let mut max = [0; 32];
+    max[32-bytes.len()..].clone_from_slice(&bytes);
+    max
+}
+
+// EACH LEVEL HASHES ITS PAIRS TOGETHER UNTIL ONE HASH IS LEFT; AN ODD ONE OUT
+// IS PAIRED WITH ITSELF. ONLY NO TRANSACTIONS HASH THE SAME AS UNDER THE OLD
+// FLAT HASH; ANY OTHER BLOCK'S txs_hash CHANGES, SEE MERKLE_TXS_HASH_TIME.
+pub fn merkle_root(hashes: &[[u8; 32]]) -> [u8; 32]
+{
+    let mut root = [0; 32];
+    if hashes.is_empty()
+    {
+        root.clone_from_slice(digest::digest(&digest::SHA256, &[]).as_ref());
+        return root;
+    }
+    let mut level = hashes.to_vec();
+    while level.len() > 1
+    {
+        level = merkle_level(&level);
+    }
+    level[0]
+}
+
+// WHETHER leaf AT POSITION idx HASHES UP THROUGH branch TO root
+pub fn verify_merkle_branch(leaf: &[u8; 32], idx: usize, branch: &[[u8; 32]], root: &[u8; 32]) -> bool
+{
+    let mut hash = *leaf;
+    let mut idx = idx;
+    for sibling in branch.iter()
+    {
+        hash = if idx % 2 == 0 { merkle_parent(&hash, sibling) } else { merkle_parent(sibling, &hash) };
+        idx /= 2;
+    }
+    idx == 0 && hash == *root
+}
+
+fn merkle_level(level: &[[u8; 32]]) -> Vec<[u8; 32]>
+{
+    level
+        .chunks(2)
+        .map(|pair| merkle_parent(&pair[0], pair.last().unwrap()))
+        .collect()
+}
+
+fn merkle_parent(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32]
+{
+    let mut buf = left.to_vec();
+    buf.extend_from_slice(right);
+    let mut hash = [0; 32];
+    hash.clone_from_slice(digest::digest(&digest::SHA256, &buf).as_ref());
+    hash
+}
@@ -0,0 +1,262 @@
+extern crate byteorder;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use block::*;
+use transaction::*;
+use util::{NBYTES_U32, NBYTES_U64};
+
+use std::cmp;
+
+pub const MAX_FILTER_BYTES: usize = 36000;
+pub const MAX_HASH_FUNCS: u32 = 50;
+pub const MAX_FILTER_ELEMENT: usize = 520;
+const MAX_FILTERED_TXS: usize = 100000;
+const HASH_SEED_STEP: u32 = 0xfba4c795;
+
+// A LIGHT CLIENT'S FILTER, LOADED WITH filterload. WE ONLY SEND IT
+// TRANSACTIONS THAT TOUCH SOMETHING IN IT, SO IT NEVER HAS TO TELL US
+// EXACTLY WHICH ADDRESSES ARE ITS OWN.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BloomFilter
+{
+    pub data:       Vec<u8>,
+    pub hash_funcs: u32,
+    pub tweak:      u32
+}
+
+impl BloomFilter
+{
+    // SIZED FOR elements ITEMS AT ROUGHLY fp_rate FALSE POSITIVES
+    pub fn new(elements: usize, fp_rate: f64, tweak: u32) -> BloomFilter
+    {
+        let ln2 = 2f64.ln();
+        let elements = cmp::max(elements, 1) as f64;
+        let bits = -elements * fp_rate.ln() / (ln2 * ln2);
+        let nbytes = cmp::min(cmp::max((bits / 8.0) as usize, 1), MAX_FILTER_BYTES);
+        let hash_funcs = (nbytes as f64 * 8.0 / elements * ln2) as u32;
+        BloomFilter {
+            data: vec![0; nbytes],
+            hash_funcs: cmp::min(cmp::max(hash_funcs, 1), MAX_HASH_FUNCS),
+            tweak: tweak
+        }
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Option<BloomFilter>
+    {
+        if bytes.len() <= 2 * NBYTES_U32 || bytes.len() > 2 * NBYTES_U32 + MAX_FILTER_BYTES { return None; }
+        let hash_funcs = LittleEndian::read_u32(&bytes[..NBYTES_U32]);
+        if hash_funcs == 0 || hash_funcs > MAX_HASH_FUNCS { return None; }
+        Some(BloomFilter {
+            data: bytes[2*NBYTES_U32..].to_vec(),
+            hash_funcs: hash_funcs,
+            tweak: LittleEndian::read_u32(&bytes[NBYTES_U32..2*NBYTES_U32])
+        })
+    }
+
+    pub fn to_vec(&self) -> Vec<u8>
+    {
+        let mut buf = [0; NBYTES_U32];
+        LittleEndian::write_u32(&mut buf, self.hash_funcs);
+        let mut bytes = buf.to_vec();
+        LittleEndian::write_u32(&mut buf, self.tweak);
+        bytes.extend_from_slice(&buf);
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    pub fn insert(&mut self, element: &[u8])
+    {
+        for n in 0..self.hash_funcs
+        {
+            let bit = self.bit(n, element);
+            self.data[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    pub fn contains(&self, element: &[u8]) -> bool
+    {
+        (0..self.hash_funcs).all(|n| {
+            let bit = self.bit(n, element);
+            self.data[bit / 8] & (1 << (bit % 8)) != 0
+        })
+    }
+
+    // WHETHER THE TRANSACTION'S HASH, SENDER, AN OUTPUT ADDRESS OR A SPENT
+    // OUTPOINT IS IN THE FILTER. A MATCHING OUTPUT'S OUTPOINT IS ADDED SO THE
+    // CLIENT ALSO SEES THE TRANSACTION THAT LATER SPENDS IT.
+    pub fn matches(&mut self, tx: &Transaction) -> bool
+    {
+        let mut matched = self.contains(&tx.hash) || self.contains(&tx.public_key);
+        for (idx, output) in tx.outputs.iter().enumerate()
+        {
+            if self.contains(&output.address)
+            {
+                matched = true;
+                self.insert(&outpoint(&tx.hash, idx as i64));
+            }
+        }
+        matched || tx.inputs.iter().any(|input| self.contains(&outpoint(&input.src_hash, input.src_idx)))
+    }
+
+    fn bit(&self, n: u32, element: &[u8]) -> usize
+    {
+        let seed = n.wrapping_mul(HASH_SEED_STEP).wrapping_add(self.tweak);
+        murmur3(seed, element) as usize % (self.data.len() * 8)
+    }
+}
+
+// AN OUTPUT AS AN INPUT REFERS TO IT
+pub fn outpoint(tx_hash: &[u8; 32], idx: i64) -> Vec<u8>
+{
+    let mut bytes = tx_hash.to_vec();
+    let mut buf = [0; NBYTES_U64];
+    LittleEndian::write_i64(&mut buf, idx);
+    bytes.extend_from_slice(&buf);
+    bytes
+}
+
+// 32-BIT MURMUR3, THE SAME HASH BITCOIN'S FILTERS USE
+pub fn murmur3(seed: u32, data: &[u8]) -> u32
+{
+    const C1: u32 = 0xcc9e2d51;
+    const C2: u32 = 0x1b873593;
+
+    let mut h = seed;
+    let mut chunks = data.chunks(4);
+    let tail = if data.len() % 4 == 0 { &[][..] } else { chunks.next_back().unwrap() };
+    for chunk in chunks
+    {
+        let k = LittleEndian::read_u32(chunk).wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h = (h ^ k).rotate_left(13).wrapping_mul(5).wrapping_add(0xe6546b64);
+    }
+    if !tail.is_empty()
+    {
+        let mut k = 0u32;
+        for (i, byte) in tail.iter().enumerate()
+        {
+            k |= (*byte as u32) << (8 * i);
+        }
+        h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85ebca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2ae35);
+    h ^ (h >> 16)
+}
+
+// THE BRANCH FROM ONE TRANSACTION UP TO ITS BLOCK'S txs_hash
+#[derive(Clone, Debug, PartialEq)]
+pub struct MerkleProof
+{
+    pub idx:    u32,
+    pub branch: Vec<[u8; 32]>
+}
+
+// A BLOCK'S HEADER AND ONLY THE TRANSACTIONS THAT MATCHED A FILTER, EACH
+// WITH ITS PROOF, SO A CLIENT HOLDING JUST HEADERS CAN CHECK THEY'RE IN IT
+#[derive(Clone)]
+pub struct FilteredBlock
+{
+    pub header:     Block,
+    pub tx_count:   u32,
+    pub txs:        Vec<(Transaction, MerkleProof)>
+}
+
+impl FilteredBlock
+{
+    pub fn new(block: &Block, filter: &mut BloomFilter) -> FilteredBlock
+    {
+        let mut header = block.clone();
+        header.txs = vec![];
+        let txs = block.txs
+            .iter()
+            .enumerate()
+            .filter(|&(_, tx)| filter.matches(tx))
+            .map(|(idx, tx)| (tx.clone(), MerkleProof { idx: idx as u32, branch: block.merkle_branch(idx) }))
+            .collect();
+        FilteredBlock {
+            header: header,
+            tx_count: block.txs.len() as u32,
+            txs: txs
+        }
+    }
+
+    // EVERY TRANSACTION HASHES UP TO THE HEADER; THE HEADER ITSELF IS
+    // CHECKED AGAINST THE CLIENT'S CHAIN
+    pub fn verify(&self) -> bool
+    {
+        self.txs.iter().all(|&(ref tx, ref proof)| {
+            proof.idx < self.tx_count && verify_merkle_branch(&tx.hash, proof.idx as usize, &proof.branch, &self.header.txs_hash)
+        })
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Option<FilteredBlock>
+    {
+        let txs_start = HEADER_LEN + 2 * NBYTES_U32;
+        if bytes.len() < txs_start { return None; }
+        let tx_count = LittleEndian::read_u32(&bytes[HEADER_LEN..HEADER_LEN+NBYTES_U32]);
+        let matched = LittleEndian::read_u32(&bytes[HEADER_LEN+NBYTES_U32..txs_start]) as usize;
+        if matched > MAX_FILTERED_TXS || matched > tx_count as usize { return None; }
+        let mut idx = txs_start;
+        let mut txs = vec![];
+        for _ in 0..matched
+        {
+            if bytes.len() < idx + 3 * NBYTES_U32 { return None; }
+            let tx_idx = LittleEndian::read_u32(&bytes[idx..idx+NBYTES_U32]);
+            let depth = LittleEndian::read_u32(&bytes[idx+NBYTES_U32..idx+2*NBYTES_U32]) as usize;
+            let tx_len = LittleEndian::read_u32(&bytes[idx+2*NBYTES_U32..idx+3*NBYTES_U32]) as usize;
+            idx += 3 * NBYTES_U32;
+            if depth > 32 || bytes.len() < idx + depth * 32 + tx_len { return None; }
+            let branch = bytes[idx..idx+depth*32]
+                .chunks(32)
+                .map(|chunk| {
+                    let mut hash = [0; 32];
+                    hash.clone_from_slice(chunk);
+                    hash
+                })
+                .collect();
+            idx += depth * 32;
+            match Transaction::try_from_slice(&bytes[idx..idx+tx_len])
+            {
+                Some(tx) => { txs.push((tx, MerkleProof { idx: tx_idx, branch: branch })); }
+                None => { return None; }
+            }
+            idx += tx_len;
+        }
+        if idx != bytes.len() { return None; }
+        Some(FilteredBlock {
+            header: Block::header_from_slice(&bytes[..HEADER_LEN]),
+            tx_count: tx_count,
+            txs: txs
+        })
+    }
+
+    pub fn to_vec(&self) -> Vec<u8>
+    {
+        let mut bytes = self.header.header_to_vec();
+        let mut buf = [0; NBYTES_U32];
+        LittleEndian::write_u32(&mut buf, self.tx_count);
+        bytes.extend_from_slice(&buf);
+        LittleEndian::write_u32(&mut buf, self.txs.len() as u32);
+        bytes.extend_from_slice(&buf);
+        for &(ref tx, ref proof) in self.txs.iter()
+        {
+            let tx_vec = tx.to_vec();
+            for n in [proof.idx, proof.branch.len() as u32, tx_vec.len() as u32].iter()
+            {
+                LittleEndian::write_u32(&mut buf, *n);
+                bytes.extend_from_slice(&buf);
+            }
+            for hash in proof.branch.iter()
+            {
+                bytes.extend_from_slice(hash);
+            }
+            bytes.extend_from_slice(&tx_vec);
+        }
+        bytes
+    }
+}
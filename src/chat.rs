@@ -0,0 +1,182 @@
+extern crate chrono;
+extern crate byteorder;
+extern crate mio;
+
+use self::chrono::*;
+
+use self::mio::{Token};
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use crypto;
+use handshake::*;
+use util::{NBYTES_U32, NBYTES_U64};
+
+use std::collections::{HashMap, VecDeque};
+use std::str;
+
+pub const MAX_CHAT_LEN: usize = 512;
+const CHAT_MAX_AGE: i64 = 10 * 60;
+const CHAT_RATE_WINDOW: i64 = 60;
+// NEW MESSAGES PER CHAT_RATE_WINDOW, FROM ONE SENDER AND ON ONE CONNECTION,
+// WHICH MAY BE RELAYING FOR MANY SENDERS
+pub const MAX_CHATS_PER_SENDER: usize = 10;
+pub const MAX_CHATS_PER_CONNECTION: usize = 30;
+
+// WHICH RATE LIMIT A MESSAGE WENT OVER
+#[derive(Debug, PartialEq)]
+pub enum ChatFlood
+{
+    Sender,
+    Connection
+}
+
+// A LINE OF TEXT SIGNED WITH THE SENDER'S NODE KEY. EVERY NODE RELAYS IT ONCE
+// TO ITS OTHER PEERS, SO IT REACHES THE WHOLE NETWORK.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChatMessage
+{
+    pub public_key: [u8; KEY_LEN],
+    pub timestamp:  i64,
+    pub text:       String,
+    pub signature:  Vec<u8>
+}
+
+impl ChatMessage
+{
+    pub fn new(text: &str, identity: &Identity) -> ChatMessage
+    {
+        let mut chat = ChatMessage {
+            public_key: identity.public_key,
+            timestamp: UTC::now().timestamp(),
+            text: text.to_string(),
+            signature: vec![]
+        };
+        chat.signature = identity.sign(&chat.signed_bytes());
+        chat
+    }
+
+    fn signed_bytes(&self) -> Vec<u8>
+    {
+        let mut bytes = self.public_key.to_vec();
+        let mut ts = [0; NBYTES_U64];
+        LittleEndian::write_i64(&mut ts, self.timestamp);
+        bytes.extend_from_slice(&ts);
+        let mut len = [0; NBYTES_U32];
+        LittleEndian::write_u32(&mut len, self.text.len() as u32);
+        bytes.extend_from_slice(&len);
+        bytes.extend_from_slice(self.text.as_bytes());
+        bytes
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Option<ChatMessage>
+    {
+        let text_start = KEY_LEN + NBYTES_U64 + NBYTES_U32;
+        if bytes.len() < text_start + SIG_LEN { return None; }
+        let text_len = LittleEndian::read_u32(&bytes[KEY_LEN+NBYTES_U64..text_start]) as usize;
+        if text_len > MAX_CHAT_LEN || bytes.len() != text_start + text_len + SIG_LEN { return None; }
+        let text = match str::from_utf8(&bytes[text_start..text_start+text_len])
+        {
+            Ok(text) => { text.to_string() }
+            Err(_) => { return None; }
+        };
+        let mut public_key = [0; KEY_LEN];
+        public_key.clone_from_slice(&bytes[..KEY_LEN]);
+        Some(ChatMessage {
+            public_key: public_key,
+            timestamp: LittleEndian::read_i64(&bytes[KEY_LEN..KEY_LEN+NBYTES_U64]),
+            text: text,
+            signature: bytes[text_start+text_len..].to_vec()
+        })
+    }
+
+    pub fn to_vec(&self) -> Vec<u8>
+    {
+        let mut bytes = self.signed_bytes();
+        bytes.extend_from_slice(&self.signature);
+        bytes
+    }
+
+    pub fn verify(&self) -> bool
+    {
+        crypto::verify_ed25519(&self.signed_bytes(), &self.signature, &self.public_key)
+    }
+
+    pub fn hash(&self) -> [u8; 32]
+    {
+        let mut hash = [0; 32];
+        hash.clone_from_slice(&crypto::digest_sha256(&self.to_vec()));
+        hash
+    }
+}
+
+// MESSAGES ALREADY SHOWN AND RELAYED. ANYTHING OLDER THAN CHAT_MAX_AGE IS
+// DROPPED RATHER THAN REMEMBERED, WHICH KEEPS THIS SET SMALL. ALSO WHEN THE
+// RECENT NEW MESSAGES FROM EACH SENDER AND ON EACH CONNECTION ARRIVED.
+pub struct ChatLog
+{
+    seen:           HashMap<[u8; 32], i64>,
+    by_sender:      HashMap<[u8; KEY_LEN], VecDeque<i64>>,
+    by_connection:  HashMap<Token, VecDeque<i64>>
+}
+
+impl ChatLog
+{
+    pub fn new() -> ChatLog
+    {
+        ChatLog {
+            seen: HashMap::new(),
+            by_sender: HashMap::new(),
+            by_connection: HashMap::new()
+        }
+    }
+
+    // TRUE THE FIRST TIME A RECENT MESSAGE IS SEEN
+    pub fn insert(&mut self, chat: &ChatMessage) -> bool
+    {
+        let now = UTC::now().timestamp();
+        if (now - chat.timestamp).abs() > CHAT_MAX_AGE
+        {
+            return false;
+        }
+        self.seen.insert(chat.hash(), chat.timestamp).is_none()
+    }
+
+    // COUNTS A NEW MESSAGE THAT ARRIVED ON from AT now AGAINST BOTH LIMITS
+    pub fn check_rate(&mut self, chat: &ChatMessage, from: Token, now: i64) -> Result<(), ChatFlood>
+    {
+        if over_limit(self.by_connection.entry(from).or_insert_with(VecDeque::new), MAX_CHATS_PER_CONNECTION, now)
+        {
+            return Err(ChatFlood::Connection);
+        }
+        if over_limit(self.by_sender.entry(chat.public_key).or_insert_with(VecDeque::new), MAX_CHATS_PER_SENDER, now)
+        {
+            return Err(ChatFlood::Sender);
+        }
+        Ok(())
+    }
+
+    pub fn expire(&mut self)
+    {
+        let now = UTC::now().timestamp();
+        self.seen.retain(|_, timestamp| now - *timestamp <= CHAT_MAX_AGE);
+        self.by_sender.retain(|_, times| times.back().map_or(false, |t| now - *t < CHAT_RATE_WINDOW));
+        self.by_connection.retain(|_, times| times.back().map_or(false, |t| now - *t < CHAT_RATE_WINDOW));
+    }
+}
+
+// A SLIDING WINDOW: FORGETS ARRIVALS OLDER THAN CHAT_RATE_WINDOW, THEN
+// RECORDS THIS ONE UNLESS limit ARE ALREADY IN IT
+fn over_limit(times: &mut VecDeque<i64>, limit: usize, now: i64) -> bool
+{
+    while times.front().map_or(false, |t| now - *t >= CHAT_RATE_WINDOW)
+    {
+        times.pop_front();
+    }
+    if times.len() >= limit
+    {
+        return true;
+    }
+    times.push_back(now);
+    false
+}
@@ -0,0 +1,260 @@
+extern crate mio;
+extern crate chrono;
+extern crate byteorder;
+extern crate rand;
+
+use self::mio::{Token};
+
+use self::chrono::*;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use block::*;
+use transaction::*;
+use crypto;
+use util::{NBYTES_U32, NBYTES_U64};
+
+use std::collections::{HashMap};
+
+pub const SHORT_ID_LEN: usize = 6;
+const MAX_COMPACT_TXS: usize = 100000;
+const PARTIAL_BLOCK_TIMEOUT: i64 = 30;
+
+// SALTED PER BLOCK SO A COLLISION CAN'T BE MADE TO HIT EVERY NODE AT ONCE
+pub fn short_id(block_hash: &[u8], nonce: u64, tx_hash: &[u8]) -> [u8; SHORT_ID_LEN]
+{
+    let mut buf = [0; NBYTES_U64];
+    LittleEndian::write_u64(&mut buf, nonce);
+    let mut bytes = buf.to_vec();
+    bytes.extend_from_slice(block_hash);
+    bytes.extend_from_slice(tx_hash);
+    let mut id = [0; SHORT_ID_LEN];
+    id.clone_from_slice(&crypto::digest_sha256(&bytes)[..SHORT_ID_LEN]);
+    id
+}
+
+// A BLOCK ANNOUNCED AS ITS HEADER AND A SHORT ID PER TRANSACTION. PEERS
+// USUALLY HAVE THE TRANSACTIONS ALREADY AND ONLY ASK FOR THE ONES THEY DON'T.
+#[derive(Clone)]
+pub struct CompactBlock
+{
+    pub header:     Block,
+    pub nonce:      u64,
+    pub short_ids:  Vec<[u8; SHORT_ID_LEN]>
+}
+
+impl CompactBlock
+{
+    pub fn new(block: &Block) -> CompactBlock
+    {
+        let nonce = rand::random::<u64>();
+        let mut header = block.clone();
+        header.txs = vec![];
+        CompactBlock {
+            short_ids: block.txs.iter().map(|tx| short_id(&block.block_hash, nonce, &tx.hash)).collect(),
+            header: header,
+            nonce: nonce
+        }
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Option<CompactBlock>
+    {
+        let ids_start = HEADER_LEN + NBYTES_U64 + NBYTES_U32;
+        if bytes.len() < ids_start { return None; }
+        let count = LittleEndian::read_u32(&bytes[HEADER_LEN+NBYTES_U64..ids_start]) as usize;
+        if count > MAX_COMPACT_TXS || bytes.len() != ids_start + count * SHORT_ID_LEN { return None; }
+        Some(CompactBlock {
+            header: Block::header_from_slice(&bytes[..HEADER_LEN]),
+            nonce: LittleEndian::read_u64(&bytes[HEADER_LEN..HEADER_LEN+NBYTES_U64]),
+            short_ids: bytes[ids_start..]
+                .chunks(SHORT_ID_LEN)
+                .map(|chunk| {
+                    let mut id = [0; SHORT_ID_LEN];
+                    id.clone_from_slice(chunk);
+                    id
+                })
+                .collect()
+        })
+    }
+
+    pub fn to_vec(&self) -> Vec<u8>
+    {
+        let mut bytes = self.header.header_to_vec();
+        let mut nonce = [0; NBYTES_U64];
+        LittleEndian::write_u64(&mut nonce, self.nonce);
+        bytes.extend_from_slice(&nonce);
+        let mut count = [0; NBYTES_U32];
+        LittleEndian::write_u32(&mut count, self.short_ids.len() as u32);
+        bytes.extend_from_slice(&count);
+        for id in self.short_ids.iter()
+        {
+            bytes.extend_from_slice(id);
+        }
+        bytes
+    }
+
+    // FILLS WHAT IT CAN FROM THE TRANSACTIONS WE ALREADY HOLD
+    pub fn reconstruct(&self, pool: &[Transaction]) -> PartialBlock
+    {
+        let mut by_id: HashMap<[u8; SHORT_ID_LEN], &Transaction> = HashMap::new();
+        for tx in pool.iter()
+        {
+            by_id.insert(short_id(&self.header.block_hash, self.nonce, &tx.hash), tx);
+        }
+        PartialBlock {
+            header: self.header.clone(),
+            txs: self.short_ids.iter().map(|id| by_id.get(id).map(|tx| (*tx).clone())).collect()
+        }
+    }
+}
+
+pub struct PartialBlock
+{
+    pub header: Block,
+    pub txs:    Vec<Option<Transaction>>
+}
+
+impl PartialBlock
+{
+    pub fn missing(&self) -> Vec<u32>
+    {
+        self.txs
+            .iter()
+            .enumerate()
+            .filter(|&(_, tx)| tx.is_none())
+            .map(|(idx, _)| idx as u32)
+            .collect()
+    }
+
+    // TRANSACTIONS FROM A blocktxn, IN THE ORDER OF THE INDEXES WE ASKED FOR
+    pub fn fill(&mut self, txs: Vec<Transaction>) -> bool
+    {
+        let missing = self.missing();
+        if txs.len() != missing.len() { return false; }
+        for (idx, tx) in missing.into_iter().zip(txs.into_iter())
+        {
+            self.txs[idx as usize] = Some(tx);
+        }
+        true
+    }
+
+    // NONE IF ANYTHING IS STILL MISSING OR A SHORT ID MATCHED THE WRONG
+    // TRANSACTION, IN WHICH CASE THE CALLER FALLS BACK TO THE FULL BLOCK
+    pub fn finish(self) -> Option<Block>
+    {
+        if self.txs.iter().any(|tx| tx.is_none()) { return None; }
+        let mut block = self.header;
+        block.txs = self.txs.into_iter().filter_map(|tx| tx).collect();
+        if block.verify_txs_hash() { Some(block) } else { None }
+    }
+}
+
+pub fn indexes_to_vec(block_hash: &[u8], indexes: &[u32]) -> Vec<u8>
+{
+    let mut bytes = block_hash.to_vec();
+    let mut buf = [0; NBYTES_U32];
+    LittleEndian::write_u32(&mut buf, indexes.len() as u32);
+    bytes.extend_from_slice(&buf);
+    for idx in indexes.iter()
+    {
+        LittleEndian::write_u32(&mut buf, *idx);
+        bytes.extend_from_slice(&buf);
+    }
+    bytes
+}
+
+pub fn indexes_from_slice(bytes: &[u8]) -> Option<([u8; 32], Vec<u32>)>
+{
+    if bytes.len() < 32 + NBYTES_U32 { return None; }
+    let count = LittleEndian::read_u32(&bytes[32..32+NBYTES_U32]) as usize;
+    if count > MAX_COMPACT_TXS || bytes.len() != 32 + NBYTES_U32 + count * NBYTES_U32 { return None; }
+    let mut hash = [0; 32];
+    hash.clone_from_slice(&bytes[..32]);
+    Some((hash, bytes[32+NBYTES_U32..].chunks(NBYTES_U32).map(LittleEndian::read_u32).collect()))
+}
+
+pub fn block_txs_to_vec(block_hash: &[u8], txs: &[Transaction]) -> Vec<u8>
+{
+    let mut bytes = block_hash.to_vec();
+    let mut buf = [0; NBYTES_U32];
+    LittleEndian::write_u32(&mut buf, txs.len() as u32);
+    bytes.extend_from_slice(&buf);
+    for tx in txs.iter()
+    {
+        let tx_vec = tx.to_vec();
+        LittleEndian::write_u32(&mut buf, tx_vec.len() as u32);
+        bytes.extend_from_slice(&buf);
+        bytes.extend_from_slice(&tx_vec);
+    }
+    bytes
+}
+
+pub fn block_txs_from_slice(bytes: &[u8]) -> Option<([u8; 32], Vec<Transaction>)>
+{
+    if bytes.len() < 32 + NBYTES_U32 { return None; }
+    let count = LittleEndian::read_u32(&bytes[32..32+NBYTES_U32]) as usize;
+    if count > MAX_COMPACT_TXS { return None; }
+    let mut idx = 32 + NBYTES_U32;
+    let mut txs = vec![];
+    for _ in 0..count
+    {
+        if bytes.len() < idx + NBYTES_U32 { return None; }
+        let tx_len = LittleEndian::read_u32(&bytes[idx..idx+NBYTES_U32]) as usize;
+        idx += NBYTES_U32;
+        if bytes.len() < idx + tx_len { return None; }
+        match Transaction::try_from_slice(&bytes[idx..idx+tx_len])
+        {
+            Some(tx) => { txs.push(tx); }
+            None => { return None; }
+        }
+        idx += tx_len;
+    }
+    if idx != bytes.len() { return None; }
+    let mut hash = [0; 32];
+    hash.clone_from_slice(&bytes[..32]);
+    Some((hash, txs))
+}
+
+// COMPACT BLOCKS WAITING ON A blocktxn, KEYED BY BLOCK HASH, WITH THE
+// CONNECTION WE ASKED SO ONLY THAT PEER CAN COMPLETE THEM
+pub struct PartialBlocks
+{
+    pending: HashMap<[u8; 32], (Token, i64, PartialBlock)>
+}
+
+impl PartialBlocks
+{
+    pub fn new() -> PartialBlocks
+    {
+        PartialBlocks {
+            pending: HashMap::new()
+        }
+    }
+
+    pub fn contains(&self, hash: &[u8; 32]) -> bool
+    {
+        self.pending.contains_key(hash)
+    }
+
+    pub fn insert(&mut self, token: Token, partial: PartialBlock)
+    {
+        let now = UTC::now().timestamp();
+        self.pending.insert(partial.header.block_hash, (token, now, partial));
+    }
+
+    pub fn take(&mut self, token: Token, hash: &[u8; 32]) -> Option<PartialBlock>
+    {
+        match self.pending.get(hash)
+        {
+            Some(&(from, _, _)) if from == token => {}
+            _ => { return None; }
+        }
+        self.pending.remove(hash).map(|(_, _, partial)| partial)
+    }
+
+    pub fn expire(&mut self)
+    {
+        let now = UTC::now().timestamp();
+        self.pending.retain(|_, &mut (_, received, _)| now - received < PARTIAL_BLOCK_TIMEOUT);
+    }
+}
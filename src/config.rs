@@ -0,0 +1,292 @@
+use util::{canonical_ip, from_hex_string, parse_addr};
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const SERVER_DEFAULT_PORT: u16 = 9001;
+const DEFAULT_LISTEN_IP: &'static str = "127.0.0.1";
+const DEFAULT_PING_INTERVAL: u64 = 60;
+const DEFAULT_PING_TIMEOUT: u64 = 20;
+const DEFAULT_IDLE_TIMEOUT: u64 = 600;
+const DEFAULT_BAN_TIME: u64 = 24 * 60 * 60;
+const DEFAULT_MAX_INBOUND: usize = 64;
+const DEFAULT_MAX_OUTBOUND: usize = 8;
+const DEFAULT_MAX_PER_IP: usize = 4;
+const DEFAULT_UPLOAD_WINDOW: u64 = 24 * 60 * 60;
+const DEFAULT_PROXY_PORT: u16 = 1080;
+const DEFAULT_DISCOVERY_GROUP: &'static str = "239.255.42.1:9010";
+
+// ARGUMENTS THE NODE CAN'T RUN WITH, SO IT REFUSES TO START
+#[derive(Debug, PartialEq)]
+pub enum ConfigError
+{
+    InvalidPort(String),
+    InvalidProxy(String),
+    InvalidValue(String, String)
+}
+
+impl fmt::Display for ConfigError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self
+        {
+            ConfigError::InvalidPort(ref port) => { write!(f, "Invalid port {}", port) }
+            ConfigError::InvalidProxy(ref proxy) => { write!(f, "Invalid proxy address {}", proxy) }
+            ConfigError::InvalidValue(ref name, ref value) => { write!(f, "Invalid value for --{}: {}", name, value) }
+        }
+    }
+}
+
+// NODE SETTINGS. THE FIRST BARE ARGUMENT IS THE LISTEN PORT, AS BEFORE;
+// EVERYTHING ELSE IS --name value. --listen MAY BE GIVEN MORE THAN ONCE,
+// EITHER AS A BARE IP (WHICH TAKES THE LISTEN PORT) OR AS ip:port, WITH IPV6
+// ADDRESSES BRACKETED.
+#[derive(Clone, Debug)]
+pub struct Config
+{
+    pub port:           u16,
+    pub listen:         Vec<String>,
+    pub external:       Option<String>,
+    pub persistent:     Vec<String>,
+    pub ping_interval:  u64,
+    pub ping_timeout:   u64,
+    pub idle_timeout:   u64,
+    pub ban_time:       u64,
+    pub max_inbound:    usize,
+    pub max_outbound:   usize,
+    pub max_per_ip:     usize,
+    pub lan_discovery:  bool,
+    pub discovery_group: String,
+    pub encrypt:        bool,
+    pub pins:           Vec<String>,
+    pub allow:          Vec<String>,
+    // BYTES WE'LL UPLOAD PER upload_window SECONDS BEFORE WE STOP SERVING OLD
+    // BLOCKS; 0 IS UNLIMITED
+    pub upload_budget:  u64,
+    pub upload_window:  u64,
+    // SOCKS5 PROXY EVERY OUTBOUND CONNECTION GOES THROUGH, FROM --proxy ip[:port]
+    // WITH THE USUAL SOCKS PORT IF NONE IS GIVEN
+    pub proxy:          Option<SocketAddr>
+}
+
+impl Config
+{
+    pub fn new() -> Config
+    {
+        Config {
+            port: SERVER_DEFAULT_PORT,
+            listen: vec![],
+            external: None,
+            persistent: vec![],
+            ping_interval: DEFAULT_PING_INTERVAL,
+            ping_timeout: DEFAULT_PING_TIMEOUT,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            ban_time: DEFAULT_BAN_TIME,
+            max_inbound: DEFAULT_MAX_INBOUND,
+            max_outbound: DEFAULT_MAX_OUTBOUND,
+            max_per_ip: DEFAULT_MAX_PER_IP,
+            lan_discovery: false,
+            discovery_group: DEFAULT_DISCOVERY_GROUP.to_string(),
+            encrypt: false,
+            pins: vec![],
+            allow: vec![],
+            upload_budget: 0,
+            upload_window: DEFAULT_UPLOAD_WINDOW,
+            proxy: None
+        }
+    }
+
+    pub fn from_args<I: Iterator<Item=String>>(mut args: I) -> Result<Config, ConfigError>
+    {
+        let mut config = Config::new();
+        while let Some(arg) = args.next()
+        {
+            if arg.starts_with("--")
+            {
+                match args.next()
+                {
+                    Some(value) => { config.set(&arg[2..], &value)?; }
+                    None => { println!("Missing value for {}", arg); }
+                }
+            }
+            else
+            {
+                config.port = match arg.parse::<u16>()
+                {
+                    Ok(port) => { port }
+                    Err(_) => { return Err(ConfigError::InvalidPort(arg)); }
+                };
+            }
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> Result<(), ConfigError>
+    {
+        match name
+        {
+            "listen" => { self.listen.push(value.to_string()); }
+            "external" => { self.external = Some(value.to_string()); }
+            "addnode" => { self.persistent.push(value.to_string()); }
+            "ping-interval" => { parse_into(&mut self.ping_interval, name, value)?; }
+            "ping-timeout" => { parse_into(&mut self.ping_timeout, name, value)?; }
+            "idle-timeout" => { parse_into(&mut self.idle_timeout, name, value)?; }
+            "ban-time" => { parse_into(&mut self.ban_time, name, value)?; }
+            "max-inbound" => { parse_into(&mut self.max_inbound, name, value)?; }
+            "max-outbound" => { parse_into(&mut self.max_outbound, name, value)?; }
+            "max-per-ip" => { parse_into(&mut self.max_per_ip, name, value)?; }
+            "lan-discovery" => { parse_into(&mut self.lan_discovery, name, value)?; }
+            "discovery-group" => { self.discovery_group = value.to_string(); }
+            "encrypt" => { parse_into(&mut self.encrypt, name, value)?; }
+            "pin" => { self.pins.push(value.to_string()); }
+            "allow" => { self.allow.push(value.to_string()); }
+            "upload-budget" => { parse_into(&mut self.upload_budget, name, value)?; }
+            "upload-window" => { parse_into(&mut self.upload_window, name, value)?; }
+            "proxy" =>
+            {
+                // EVERY OUTBOUND CONNECTION DEPENDS ON IT, AND FALLING BACK TO
+                // DIRECT ONES WOULD DEFEAT THE POINT OF ASKING FOR A PROXY
+                match parse_socket_addr(value, DEFAULT_PROXY_PORT)
+                {
+                    Some(proxy) => { self.proxy = Some(proxy); }
+                    None => { return Err(ConfigError::InvalidProxy(value.to_string())); }
+                }
+            }
+            _ => { println!("Unknown option --{}", name); }
+        }
+        Ok(())
+    }
+
+    pub fn listen_addrs(&self) -> Vec<SocketAddr>
+    {
+        let port = self.port;
+        if self.listen.is_empty()
+        {
+            return vec![SocketAddr::new(DEFAULT_LISTEN_IP.parse().unwrap(), port)];
+        }
+        self.listen
+            .iter()
+            .filter_map(|addr| {
+                let parsed = parse_socket_addr(addr, port);
+                if parsed.is_none()
+                {
+                    println!("Invalid listen address {}", addr);
+                }
+                parsed
+            })
+            .collect()
+    }
+
+    // THE ADDRESS WE TELL PEERS TO CONNECT BACK TO. A WILDCARD LISTEN ADDRESS
+    // ISN'T REACHABLE, SO WITHOUT --external WE FALL BACK TO LOOPBACK.
+    pub fn advertised_addr(&self) -> SocketAddr
+    {
+        let port = self.port;
+        if let Some(addr) = self.external.as_ref().and_then(|addr| parse_socket_addr(addr, port))
+        {
+            return addr;
+        }
+        let listen = self.listen_addrs();
+        match listen.iter().find(|addr| !addr.ip().is_unspecified())
+        {
+            Some(addr) => { *addr }
+            None =>
+            {
+                let addr = listen.first().cloned().unwrap_or(SocketAddr::new(DEFAULT_LISTEN_IP.parse().unwrap(), port));
+                let loopback = match addr.ip()
+                {
+                    IpAddr::V4(_) => { IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)) }
+                    IpAddr::V6(_) => { IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)) }
+                };
+                SocketAddr::new(loopback, addr.port())
+            }
+        }
+    }
+
+    // ANY --allow <hex public key> PUTS THE NODE IN PERMISSIONED MODE: EVERY
+    // CONNECTION MUST HANDSHAKE AND ONLY LISTED NODE KEYS ARE KEPT
+    pub fn permissioned(&self) -> bool
+    {
+        !self.allow.is_empty()
+    }
+
+    pub fn require_handshake(&self) -> bool
+    {
+        self.encrypt || self.permissioned()
+    }
+
+    pub fn is_allowed(&self, key: &[u8]) -> bool
+    {
+        !self.permissioned() || self.allow.iter().any(|allowed| from_hex_string(allowed).map_or(false, |allowed| allowed == key))
+    }
+
+    // THE NODE KEY WE EXPECT FROM ip:port, FROM --pin ip:port=<hex public key>
+    pub fn pinned_key(&self, ip: &str, port: i32) -> Option<[u8; 32]>
+    {
+        for pin in self.pins.iter()
+        {
+            let mut parts = pin.splitn(2, '=');
+            let addr = parts.next().and_then(|addr| parse_addr(addr.as_bytes()));
+            let key = parts.next().and_then(from_hex_string);
+            match (addr, key)
+            {
+                (Some((pin_ip, pin_port)), Some(ref key)) if key.len() == 32 =>
+                {
+                    if pin_ip == ip && pin_port == port
+                    {
+                        let mut pinned = [0; 32];
+                        pinned.clone_from_slice(key);
+                        return Some(pinned);
+                    }
+                }
+                _ => { println!("Invalid --pin {}", pin); }
+            }
+        }
+        None
+    }
+
+    // WHETHER ip:port IS THIS NODE, SO WE DON'T CONNECT TO OURSELVES
+    pub fn is_self(&self, ip: &str, port: i32) -> bool
+    {
+        let ip = match ip.parse::<IpAddr>()
+        {
+            Ok(ip) => { canonical_ip(ip) }
+            Err(_) => { return false; }
+        };
+        let advertised = self.advertised_addr();
+        if canonical_ip(advertised.ip()) == ip && advertised.port() as i32 == port
+        {
+            return true;
+        }
+        self.listen_addrs().iter().any(|addr| {
+            addr.port() as i32 == port &&
+            (canonical_ip(addr.ip()) == ip || (addr.ip().is_unspecified() && ip.is_loopback()))
+        })
+    }
+}
+
+fn parse_socket_addr(addr: &str, default_port: u16) -> Option<SocketAddr>
+{
+    match addr.parse::<SocketAddr>()
+    {
+        Ok(addr) => { Some(addr) }
+        Err(_) =>
+        {
+            addr.trim_matches(|c| c == '[' || c == ']')
+                .parse::<IpAddr>()
+                .ok()
+                .map(|ip| SocketAddr::new(ip, default_port))
+        }
+    }
+}
+
+fn parse_into<T: ::std::str::FromStr>(field: &mut T, name: &str, value: &str) -> Result<(), ConfigError>
+{
+    match value.parse::<T>()
+    {
+        Ok(value) => { *field = value; }
+        Err(_) => { return Err(ConfigError::InvalidValue(name.to_string(), value.to_string())); }
+    }
+    Ok(())
+}
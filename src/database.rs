@@ -1,6 +1,8 @@
 use transaction::*;
 use block::*;
-use peer::*;
+use gcs::*;
+use addrman::*;
+use util::{parse_ip};
 
 extern crate postgres;
 use self::postgres::{Connection, TlsMode};
@@ -22,45 +24,193 @@ pub fn conn() -> Connection
     Connection::connect(DB_URL, TlsMode::None).expect("Unable to connect to database")
 }
 
-pub fn peers(db: &Connection) -> Vec<Peer>
+// WHAT MESSAGE HANDLERS READ AND WRITE. THE NODE KEEPS IT ALL IN POSTGRES;
+// THE SIMULATOR AND THE TESTS KEEP IT IN MEMORY.
+pub trait Store
+{
+    fn blockchain(&self) -> Vec<Block>;
+    fn latest_block(&self) -> Option<Block>;
+    fn block(&self, hash: &[u8]) -> Option<Block>;
+    fn full_block(&self, hash: &[u8]) -> Option<Block>;
+    fn insert_block(&self, block: &Block) -> Result<(), DatabaseInsertionError>;
+    fn block_filter(&self, hash: &[u8]) -> Option<GcsFilter>;
+    fn insert_block_filter(&self, hash: &[u8], filter: &GcsFilter);
+    fn transaction(&self, hash: &[u8]) -> Option<Transaction>;
+    fn insert_transaction(&self, tx: &Transaction) -> Result<(), DatabaseInsertionError>;
+    fn mempool_hashes(&self) -> Vec<[u8; 32]>;
+    fn mempool_txs(&self) -> Vec<Transaction>;
+    fn unspent_outputs(&self, public_key: &[u8]) -> Vec<TxOutput>;
+    fn upsert_ban(&self, ip: &str, expiry: i64);
+}
+
+impl Store for Connection
+{
+    fn blockchain(&self) -> Vec<Block>
+    {
+        blockchain(self)
+    }
+
+    fn latest_block(&self) -> Option<Block>
+    {
+        latest_block(self)
+    }
+
+    fn block(&self, hash: &[u8]) -> Option<Block>
+    {
+        block(hash, self)
+    }
+
+    fn full_block(&self, hash: &[u8]) -> Option<Block>
+    {
+        full_block(hash, self)
+    }
+
+    fn insert_block(&self, block: &Block) -> Result<(), DatabaseInsertionError>
+    {
+        insert_block(block, self)
+    }
+
+    fn block_filter(&self, hash: &[u8]) -> Option<GcsFilter>
+    {
+        block_filter(hash, self)
+    }
+
+    fn insert_block_filter(&self, hash: &[u8], filter: &GcsFilter)
+    {
+        insert_block_filter(hash, filter, self)
+    }
+
+    fn transaction(&self, hash: &[u8]) -> Option<Transaction>
+    {
+        transaction(hash, self)
+    }
+
+    fn insert_transaction(&self, tx: &Transaction) -> Result<(), DatabaseInsertionError>
+    {
+        insert_transaction(tx, self)
+    }
+
+    fn mempool_hashes(&self) -> Vec<[u8; 32]>
+    {
+        mempool_hashes(self)
+    }
+
+    fn mempool_txs(&self) -> Vec<Transaction>
+    {
+        mempool_txs(self)
+    }
+
+    fn unspent_outputs(&self, public_key: &[u8]) -> Vec<TxOutput>
+    {
+        unspent_outputs(public_key, self)
+    }
+
+    fn upsert_ban(&self, ip: &str, expiry: i64)
+    {
+        upsert_ban(ip, expiry, self)
+    }
+}
+
+pub fn addresses(db: &Connection) -> Vec<AddrInfo>
 {
     db.query(
-        "SELECT ip, port, timestamp FROM peers ORDER BY timestamp DESC;",
+        "SELECT ip, port, timestamp, source, last_success, last_attempt, attempts, tried, services FROM peers;",
         &[])
         .unwrap()
         .iter()
-        .map(|row| Peer::new(
-            row.get(0),
-            row.get(1),
-            row.get(2),
-            None))
+        .filter_map(|row| {
+            let ip: String = row.get(0);
+            let source: Option<String> = row.get(3);
+            parse_ip(&ip).map(|ip| AddrInfo {
+                source: source.and_then(|source| parse_ip(&source)).unwrap_or(ip.clone()),
+                ip: ip,
+                port: row.get(1),
+                last_seen: row.get(2),
+                last_success: row.get::<_, Option<i64>>(4).unwrap_or(0),
+                last_attempt: row.get::<_, Option<i64>>(5).unwrap_or(0),
+                attempts: row.get::<_, Option<i32>>(6).unwrap_or(0) as u32,
+                tried: row.get::<_, Option<bool>>(7).unwrap_or(false),
+                services: row.get::<_, Option<i64>>(8).unwrap_or(0) as u64
+            })
+        })
         .collect()
 }
 
-pub fn upsert_peer(peer: &Peer, db: &Connection) -> Result<(), DatabaseInsertionError>
+pub fn upsert_address(info: &AddrInfo, db: &Connection)
 {
-    let mut result = Ok(());
+    let attempts = info.attempts as i32;
+    let services = info.services as i64;
     db.execute("BEGIN WORK;", &[]).unwrap();
-    db.execute("LOCK TABLE blocks IN SHARE ROW EXCLUSIVE MODE;", &[]).unwrap();
+    db.execute("LOCK TABLE peers IN SHARE ROW EXCLUSIVE MODE;", &[]).unwrap();
     if db.execute(
         "SELECT 1 FROM peers WHERE ip = $1 AND port = $2",
-        &[&peer.ip, &peer.port])
+        &[&info.ip, &info.port])
         .unwrap() != 1
     {
         db.execute(
-            "INSERT INTO peers (ip, port, timestamp) SELECT $1, $2, $3",
-            &[&peer.ip, &peer.port, &peer.timestamp])
+            "INSERT INTO peers (ip, port, timestamp, source, last_success, last_attempt, attempts, tried, services) SELECT $1, $2, $3, $4, $5, $6, $7, $8, $9",
+            &[&info.ip, &info.port, &info.last_seen, &info.source, &info.last_success, &info.last_attempt, &attempts, &info.tried, &services])
             .unwrap();
     }
     else
     {
         db.execute(
-            "UPDATE peers SET timestamp = $1 WHERE ip = $2 AND port = $3",
-            &[&peer.timestamp, &peer.ip, &peer.port])
+            "UPDATE peers SET timestamp = $1, source = $2, last_success = $3, last_attempt = $4, attempts = $5, tried = $6, services = $7 WHERE ip = $8 AND port = $9",
+            &[&info.last_seen, &info.source, &info.last_success, &info.last_attempt, &attempts, &info.tried, &services, &info.ip, &info.port])
             .unwrap();
-        result = Err(DatabaseInsertionError::ValueExists);
     }
-    result
+    db.execute("COMMIT WORK;", &[]).unwrap();
+}
+
+pub fn delete_address(ip: &str, port: i32, db: &Connection)
+{
+    db.execute(
+        "DELETE FROM peers WHERE ip = $1 AND port = $2",
+        &[&ip, &port])
+        .unwrap();
+}
+
+pub fn bans(db: &Connection) -> Vec<(String, i64)>
+{
+    db.query(
+        "SELECT ip, expiry FROM bans;",
+        &[])
+        .unwrap()
+        .iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect()
+}
+
+pub fn upsert_ban(ip: &str, expiry: i64, db: &Connection)
+{
+    db.execute("BEGIN WORK;", &[]).unwrap();
+    db.execute("LOCK TABLE bans IN SHARE ROW EXCLUSIVE MODE;", &[]).unwrap();
+    if db.execute(
+        "SELECT 1 FROM bans WHERE ip = $1",
+        &[&ip])
+        .unwrap() != 1
+    {
+        db.execute(
+            "INSERT INTO bans (ip, expiry) SELECT $1, $2",
+            &[&ip, &expiry])
+            .unwrap();
+    }
+    else
+    {
+        db.execute(
+            "UPDATE bans SET expiry = $1 WHERE ip = $2",
+            &[&expiry, &ip])
+            .unwrap();
+    }
+    db.execute("COMMIT WORK;", &[]).unwrap();
+}
+
+pub fn delete_expired_bans(now: i64, db: &Connection)
+{
+    db.execute(
+        "DELETE FROM bans WHERE expiry <= $1",
+        &[&now])
+        .unwrap();
 }
 
 pub fn blockchain(db: &Connection) -> Vec<Block>
@@ -83,6 +233,28 @@ pub fn blockchain(db: &Connection) -> Vec<Block>
         .collect()
 }
 
+// THE NEWEST BLOCK WE HAVE, ON WHATEVER BRANCH, WITHOUT LOADING THE REST
+pub fn latest_block(db: &Connection) -> Option<Block>
+{
+    let blocks: Vec<Block> = db.query(
+        "SELECT txs_hash, parent_hash, target, timestamp, nonce, block_hash FROM blocks ORDER BY timestamp DESC LIMIT 1;",
+        &[])
+        .unwrap()
+        .iter()
+        .map(|row|
+            Block::new(
+                &(row.get::<usize, Vec<u8>>(0)),
+                vec![],
+                &(row.get::<usize, Vec<u8>>(1)),
+                &(row.get::<usize, Vec<u8>>(2)),
+                row.get(3),
+                row.get(4),
+                &(row.get::<usize, Vec<u8>>(5))
+            ))
+        .collect();
+    blocks.first().map_or(None, |x| Some(x.clone()))
+}
+
 pub fn pending_txs(db: &Connection) -> Vec<Transaction>
 {
     db.query(
@@ -99,6 +271,44 @@ pub fn pending_txs(db: &Connection) -> Vec<Transaction>
         .collect()
 }
 
+pub fn mempool_hashes(db: &Connection) -> Vec<[u8; 32]>
+{
+    db.query(
+        "SELECT hash FROM transactions WHERE block IS NULL OR block NOT IN (SELECT block_hash FROM blocks);",
+        &[])
+        .unwrap()
+        .iter()
+        .map(|row| {
+            let mut hash = [0; 32];
+            hash.clone_from_slice(&row.get::<usize, Vec<u8>>(0));
+            hash
+        })
+        .collect()
+}
+
+// TRANSACTIONS NOT YET IN ANY BLOCK WE HOLD, WITH THEIR INPUTS AND OUTPUTS
+pub fn mempool_txs(db: &Connection) -> Vec<Transaction>
+{
+    let mut txs: Vec<Transaction> = db.query(
+        "SELECT hash, public_key, timestamp FROM transactions WHERE block IS NULL OR block NOT IN (SELECT block_hash FROM blocks);",
+        &[])
+        .unwrap()
+        .iter()
+        .map(|row|
+            Transaction::new_with_hash(
+                &(row.get::<usize, Vec<u8>>(0)),
+                &(row.get::<usize, Vec<u8>>(1)),
+                row.get(2),
+            ))
+        .collect();
+    for tx in txs.iter_mut()
+    {
+        tx.inputs = tx_inputs(&tx, db);
+        tx.outputs = tx_outputs(&tx, db);
+    }
+    txs
+}
+
 pub fn tx_inputs(tx: &Transaction, db: &Connection) -> Vec<TxInput>
 {
     db.query(
@@ -118,7 +328,7 @@ pub fn tx_inputs(tx: &Transaction, db: &Connection) -> Vec<TxInput>
 pub fn tx_outputs(tx: &Transaction, db: &Connection) -> Vec<TxOutput>
 {
     db.query(
-        "SELECT amount, address FROM tx_outputs WHERE tx = $1;",
+        "SELECT amount, address FROM tx_outputs WHERE tx = $1 ORDER BY idx ASC;",
         &[&tx.hash.as_ref()])
         .unwrap()
         .iter()
@@ -130,6 +340,28 @@ pub fn tx_outputs(tx: &Transaction, db: &Connection) -> Vec<TxOutput>
         .collect()
 }
 
+pub fn transaction(hash: &[u8], db: &Connection) -> Option<Transaction>
+{
+    let txs: Vec<Transaction> = db.query(
+        "SELECT hash, public_key, timestamp FROM transactions WHERE hash = $1;",
+        &[&hash.as_ref()])
+        .unwrap()
+        .iter()
+        .map(|row|
+            Transaction::new_with_hash(
+                &(row.get::<usize, Vec<u8>>(0)),
+                &(row.get::<usize, Vec<u8>>(1)),
+                row.get(2),
+            ))
+        .collect();
+    txs.first().map(|tx| {
+        let mut tx = tx.clone();
+        tx.inputs = tx_inputs(&tx, db);
+        tx.outputs = tx_outputs(&tx, db);
+        tx
+    })
+}
+
 pub fn block(hash: &[u8], db: &Connection) -> Option<Block>
 {
     let blocks: Vec<Block> = db.query(
@@ -151,6 +383,37 @@ pub fn block(hash: &[u8], db: &Connection) -> Option<Block>
     blocks.first().map_or(None, |x| Some(x.clone()))
 }
 
+// IN THE ORDER THEY WERE MINED, WHICH txs_hash DEPENDS ON
+pub fn block_txs(hash: &[u8], db: &Connection) -> Vec<Transaction>
+{
+    let mut txs: Vec<Transaction> = db.query(
+        "SELECT hash, public_key, timestamp FROM transactions WHERE block = $1 ORDER BY position ASC;",
+        &[&hash.as_ref()])
+        .unwrap()
+        .iter()
+        .map(|row|
+            Transaction::new_with_hash(
+                &(row.get::<usize, Vec<u8>>(0)),
+                &(row.get::<usize, Vec<u8>>(1)),
+                row.get(2),
+            ))
+        .collect();
+    for tx in txs.iter_mut()
+    {
+        tx.inputs = tx_inputs(&tx, db);
+        tx.outputs = tx_outputs(&tx, db);
+    }
+    txs
+}
+
+pub fn full_block(hash: &[u8], db: &Connection) -> Option<Block>
+{
+    block(hash, db).map(|mut block| {
+        block.txs = block_txs(hash, db);
+        block
+    })
+}
+
 pub fn insert_block(block: &Block, db: &Connection) -> Result<(), DatabaseInsertionError>
 {
     let mut result = Ok(());
@@ -165,9 +428,10 @@ pub fn insert_block(block: &Block, db: &Connection) -> Result<(), DatabaseInsert
             "INSERT INTO blocks (txs_hash, parent_hash, target, timestamp, nonce, block_hash) SELECT $1, $2, $3, $4, $5, $6",
             &[&block.txs_hash.as_ref(), &block.parent_hash.as_ref(), &block.target.as_ref(), &block.timestamp, &block.nonce, &block.block_hash.as_ref()])
             .unwrap();
+        insert_block_filter(&block.block_hash, &GcsFilter::for_block(block), db);
 
         // db.execute("LOCK TABLE transactions IN SHARE ROW EXCLUSIVE MODE;", &[]).unwrap();
-        for tx in block.txs.iter()
+        for (position, tx) in block.txs.iter().enumerate()
         {
             if db.execute(
                 "SELECT 1 FROM transactions WHERE hash = $1",
@@ -176,8 +440,8 @@ pub fn insert_block(block: &Block, db: &Connection) -> Result<(), DatabaseInsert
             {
                 // TRANSACTION DOESN'T EXIST LOCALLY, MUST HAVE RECEIVED THIS BLOCK FROM A PEER
                 db.execute(
-                    "INSERT INTO transactions (hash, timestamp, block) SELECT $1, $2, $3",
-                    &[&tx.hash.as_ref(), &tx.timestamp, &block.block_hash.as_ref()])
+                    "INSERT INTO transactions (hash, public_key, timestamp, block, position) SELECT $1, $2, $3, $4, $5",
+                    &[&tx.hash.as_ref(), &tx.public_key.as_ref(), &tx.timestamp, &block.block_hash.as_ref(), &(position as i32)])
                     .unwrap();
 
                 for txi in tx.inputs.iter()
@@ -199,8 +463,8 @@ pub fn insert_block(block: &Block, db: &Connection) -> Result<(), DatabaseInsert
             {
                 // TRANSACTION IS ALREADY STORED/PENDING; ADD IT TO THE BLOCK
                 db.execute(
-                    "UPDATE transactions SET block = $1 WHERE hash = $2",
-                    &[&block.block_hash.as_ref(), &tx.hash.as_ref()])
+                    "UPDATE transactions SET block = $1, position = $2 WHERE hash = $3",
+                    &[&block.block_hash.as_ref(), &(position as i32), &tx.hash.as_ref()])
                     .unwrap();
             }
         }
@@ -213,6 +477,26 @@ pub fn insert_block(block: &Block, db: &Connection) -> Result<(), DatabaseInsert
     result
 }
 
+pub fn block_filter(hash: &[u8], db: &Connection) -> Option<GcsFilter>
+{
+    db.query(
+        "SELECT filter FROM block_filters WHERE block_hash = $1;",
+        &[&hash.as_ref()])
+        .unwrap()
+        .iter()
+        .next()
+        .and_then(|row| GcsFilter::from_slice(&row.get::<usize, Vec<u8>>(0)))
+}
+
+// BLOCKS STORED BEFORE block_filters EXISTED GET THEIRS THE FIRST TIME IT'S ASKED FOR
+pub fn insert_block_filter(hash: &[u8], filter: &GcsFilter, db: &Connection)
+{
+    db.execute(
+        "INSERT INTO block_filters (block_hash, filter) SELECT $1, $2 WHERE NOT EXISTS (SELECT 1 FROM block_filters WHERE block_hash = $1)",
+        &[&hash.as_ref(), &filter.to_vec()])
+        .unwrap();
+}
+
 pub fn insert_transaction(tx: &Transaction, db: &Connection) -> Result<(), DatabaseInsertionError>
 {
     let mut result = Ok(());
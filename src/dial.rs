@@ -0,0 +1,251 @@
+extern crate chrono;
+extern crate mio;
+extern crate net2;
+
+use self::chrono::*;
+
+use self::mio::{Token};
+use self::mio::tcp::{TcpStream};
+
+use self::net2::{TcpBuilder};
+
+use handshake::*;
+use message::*;
+use socks;
+
+use std::io;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::mem;
+use std::net;
+use std::net::{SocketAddr};
+
+// HOW LONG A DIAL HAS, FROM connect() TO BECOMING A PEER
+pub const DIAL_TIMEOUT_SECS: i64 = 10;
+
+// WHY WE'RE DIALING, WHICH DECIDES WHAT HAPPENS ONCE THE CONNECTION IS UP
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DialPurpose
+{
+    Outbound,
+    // ONE BEYOND max_outbound, BECAUSE THE TIP HAS GONE STALE
+    Extra,
+    // BACK TO A NODE THAT CONNECTED TO US, FOR THE CLIENT WITH THIS TOKEN
+    LinkBack(Token)
+}
+
+// A DIAL ASKED FOR WHILE HANDLING A MESSAGE. HANDLERS ONLY QUEUE THESE; THE
+// EVENT LOOP STARTS THEM ONCE THE HANDLER HAS RETURNED.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DialRequest
+{
+    pub ip:         String,
+    pub port:       i32,
+    pub purpose:    DialPurpose
+}
+
+enum DialState
+{
+    Connecting,
+    // CONNECTED TO --proxy, WHICH IS CONNECTING US ON TO ip:port
+    Proxy(socks::Negotiation),
+    // OUR hello IS SENT AND WE'RE WAITING FOR THE helloack
+    Handshake(Initiator),
+    Done
+}
+
+// AN OUTBOUND CONNECTION THAT ISN'T A PEER YET. THE SOCKET IS NON-BLOCKING
+// AND REGISTERED WITH POLL, AND ready() IS CALLED ON EACH EVENT FOR IT, SO THE
+// HANDSHAKE RUNS THE SAME WAY rcv_hello AND rcv_hellofin DO ON THE OTHER SIDE.
+// stream IS THE SAME SOCKET, WHICH THE PEER KEEPS ONCE THE DIAL IS DONE.
+pub struct Dial
+{
+    pub ip:         String,
+    pub port:       i32,
+    pub purpose:    DialPurpose,
+    pub started:    i64,
+    // SET ONCE THE HANDSHAKE IS DONE
+    pub session:    Option<Session>,
+    pub remote:     Option<[u8; KEY_LEN]>,
+    socket:         TcpStream,
+    stream:         net::TcpStream,
+    state:          DialState,
+    // THE SOCKET GOES TO --proxy, WHICH CONNECTS US ON TO ip:port
+    proxied:        bool,
+    // WHO TO SHAKE HANDS AS ONCE CONNECTED, AND WHO WE EXPECT; NONE FOR PLAINTEXT
+    handshake:      Option<(Identity, Option<[u8; KEY_LEN]>)>,
+    received:       Vec<u8>
+}
+
+impl Dial
+{
+    // ISSUES A NON-BLOCKING CONNECT TO addr, WHICH IS ip:port OR THE PROXY
+    pub fn start(
+        ip: &str,
+        port: i32,
+        addr: &SocketAddr,
+        purpose: DialPurpose,
+        proxied: bool,
+        handshake: Option<(Identity, Option<[u8; KEY_LEN]>)>) -> io::Result<Dial>
+    {
+        let builder = match *addr
+        {
+            SocketAddr::V4(..) => { TcpBuilder::new_v4()? }
+            SocketAddr::V6(..) => { TcpBuilder::new_v6()? }
+        };
+        let stream = builder.to_tcp_stream()?;
+        let socket = TcpStream::connect_stream(stream.try_clone()?, addr)?;
+        Ok(Dial {
+            ip: ip.to_string(),
+            port: port,
+            purpose: purpose,
+            started: UTC::now().timestamp(),
+            session: None,
+            remote: None,
+            socket: socket,
+            stream: stream,
+            state: DialState::Connecting,
+            proxied: proxied,
+            handshake: handshake,
+            received: vec![]
+        })
+    }
+
+    pub fn socket(&self) -> &TcpStream
+    {
+        &self.socket
+    }
+
+    pub fn expired(&self, now: i64) -> bool
+    {
+        now - self.started > DIAL_TIMEOUT_SECS
+    }
+
+    // TRUE ONCE THE CONNECTION IS UP AND, IF WE ASKED FOR ONE, THE HANDSHAKE
+    // DONE. AN EVENT CAN COME BEFORE THERE'S ANYTHING TO DO, SO NOTHING TO DO
+    // YET JUST MEANS WAIT FOR THE NEXT ONE.
+    pub fn ready(&mut self) -> io::Result<bool>
+    {
+        match mem::replace(&mut self.state, DialState::Done)
+        {
+            DialState::Connecting =>
+            {
+                if !self.connected()?
+                {
+                    self.state = DialState::Connecting;
+                    return Ok(false);
+                }
+                if self.proxied
+                {
+                    let negotiation = socks::Negotiation::start(&mut self.socket, &self.ip, self.port as u16)?;
+                    self.state = DialState::Proxy(negotiation);
+                    return self.ready();
+                }
+                self.start_handshake()
+            }
+            DialState::Proxy(mut negotiation) =>
+            {
+                if !negotiation.ready(&mut self.socket)?
+                {
+                    self.state = DialState::Proxy(negotiation);
+                    return Ok(false);
+                }
+                self.start_handshake()
+            }
+            DialState::Handshake(initiator) =>
+            {
+                let ack = match self.receive_ack()?
+                {
+                    Some(ack) => { ack }
+                    None =>
+                    {
+                        self.state = DialState::Handshake(initiator);
+                        return Ok(false);
+                    }
+                };
+                let pin = self.handshake.as_ref().and_then(|&(_, pin)| pin);
+                let (session, fin, remote) = initiator.finish(&ack, pin.as_ref()).map_err(invalid)?;
+                self.socket.write_all(&NetworkMessage::HelloFin(fin).to_msg().to_vec())?;
+                self.session = Some(session);
+                self.remote = Some(remote);
+                Ok(true)
+            }
+            DialState::Done => { Ok(true) }
+        }
+    }
+
+    fn start_handshake(&mut self) -> io::Result<bool>
+    {
+        match self.handshake.take()
+        {
+            Some((identity, pin)) =>
+            {
+                let (initiator, hello) = Initiator::new(&identity).map_err(invalid)?;
+                self.handshake = Some((identity, pin));
+                // A FEW BYTES ON A FRESH CONNECTION ALWAYS FIT THE SEND BUFFER
+                self.socket.write_all(&NetworkMessage::Hello(hello).to_msg().to_vec())?;
+                self.state = DialState::Handshake(initiator);
+                self.ready()
+            }
+            None => { Ok(true) }
+        }
+    }
+
+    fn connected(&mut self) -> io::Result<bool>
+    {
+        if let Some(e) = self.socket.take_error()?
+        {
+            return Err(e);
+        }
+        match self.socket.peer_addr()
+        {
+            Ok(_) => { Ok(true) }
+            Err(ref e) if e.kind() == ErrorKind::NotConnected => { Ok(false) }
+            Err(e) => { Err(e) }
+        }
+    }
+
+    // READS WHAT HAS ARRIVED, AND RETURNS THE helloack ONCE IT'S ALL HERE
+    fn receive_ack(&mut self) -> io::Result<Option<Vec<u8>>>
+    {
+        let mut buf = [0; 256];
+        loop
+        {
+            match self.socket.read(&mut buf)
+            {
+                Ok(0) => { return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed during handshake")); }
+                Ok(n) => { self.received.extend_from_slice(&buf[..n]); }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => { break; }
+                Err(e) => { return Err(e); }
+            }
+            if self.received.len() > MSG_HEADER_LEN + HELLO_ACK_LEN
+            {
+                return Err(invalid(HandshakeError::Malformed));
+            }
+        }
+
+        let msg = match Msg::from_buffer(&mut self.received, HELLO_ACK_LEN as u32)
+        {
+            Ok(Some(msg)) => { msg }
+            Ok(None) => { return Ok(None); }
+            Err(_) => { return Err(invalid(HandshakeError::Malformed)); }
+        };
+        match NetworkMessage::from_msg(&msg)
+        {
+            Ok(NetworkMessage::HelloAck(ack)) => { Ok(Some(ack)) }
+            _ => { Err(invalid(HandshakeError::Malformed)) }
+        }
+    }
+
+    // THE CONNECTED SOCKET, BLOCKING AGAIN LIKE EVERY OTHER PEER'S. THE CALLER
+    // DEREGISTERS socket() FIRST.
+    pub fn finish(self) -> io::Result<net::TcpStream>
+    {
+        self.stream.set_nonblocking(false)?;
+        Ok(self.stream)
+    }
+}
+
+fn invalid(e: HandshakeError) -> Error
+{
+    Error::new(ErrorKind::InvalidData, format!("handshake failed: {:?}", e))
+}
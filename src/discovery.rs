@@ -0,0 +1,142 @@
+extern crate mio;
+extern crate net2;
+extern crate byteorder;
+extern crate rand;
+
+use self::mio::udp::{UdpSocket};
+
+use self::net2::{UdpBuilder};
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use util::{NBYTES_U32, NBYTES_U64, canonical_ip};
+
+use std::io;
+use std::io::{ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+pub const BEACON_MAGIC: u32 = 0x314e4843;
+pub const BEACON_LEN: usize = NBYTES_U32 + 2 + NBYTES_U64;
+
+// SENT TO THE MULTICAST GROUP: WHICH NETWORK WE'RE ON, THE PORT WE ACCEPT
+// CONNECTIONS ON AND A RANDOM ID SO WE CAN IGNORE OUR OWN BEACONS
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Beacon
+{
+    pub magic:      u32,
+    pub port:       u16,
+    pub node_id:    u64
+}
+
+impl Beacon
+{
+    pub fn from_slice(bytes: &[u8]) -> Option<Beacon>
+    {
+        if bytes.len() != BEACON_LEN { return None; }
+        let beacon = Beacon {
+            magic: LittleEndian::read_u32(&bytes[..NBYTES_U32]),
+            port: LittleEndian::read_u16(&bytes[NBYTES_U32..NBYTES_U32+2]),
+            node_id: LittleEndian::read_u64(&bytes[NBYTES_U32+2..])
+        };
+        if beacon.magic != BEACON_MAGIC || beacon.port == 0 { return None; }
+        Some(beacon)
+    }
+
+    pub fn to_vec(&self) -> Vec<u8>
+    {
+        let mut buf = [0; BEACON_LEN];
+        LittleEndian::write_u32(&mut buf[..NBYTES_U32], self.magic);
+        LittleEndian::write_u16(&mut buf[NBYTES_U32..NBYTES_U32+2], self.port);
+        LittleEndian::write_u64(&mut buf[NBYTES_U32+2..], self.node_id);
+        buf.to_vec()
+    }
+}
+
+// OPT-IN (--lan-discovery true) BEACONS ON A MULTICAST GROUP SO NODES ON THE
+// SAME NETWORK FIND EACH OTHER WITHOUT SEEDING THE peers TABLE
+pub struct LanDiscovery
+{
+    pub socket: UdpSocket,
+    group:      SocketAddr,
+    beacon:     Beacon
+}
+
+impl LanDiscovery
+{
+    pub fn new(group: SocketAddr, port: u16) -> io::Result<LanDiscovery>
+    {
+        let socket = match group.ip()
+        {
+            IpAddr::V4(ip) =>
+            {
+                let builder = UdpBuilder::new_v4()?;
+                builder.reuse_address(true)?;
+                let socket = UdpSocket::from_socket(builder.bind((Ipv4Addr::new(0, 0, 0, 0), group.port()))?)?;
+                socket.join_multicast_v4(&ip, &Ipv4Addr::new(0, 0, 0, 0))?;
+                socket.set_multicast_loop_v4(true)?;
+                socket.set_multicast_ttl_v4(1)?;
+                socket
+            }
+            IpAddr::V6(ip) =>
+            {
+                let builder = UdpBuilder::new_v6()?;
+                builder.reuse_address(true)?;
+                builder.only_v6(true)?;
+                let socket = UdpSocket::from_socket(builder.bind((Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), group.port()))?)?;
+                socket.join_multicast_v6(&ip, 0)?;
+                socket.set_multicast_loop_v6(true)?;
+                socket
+            }
+        };
+        Ok(LanDiscovery {
+            socket: socket,
+            group: group,
+            beacon: Beacon {
+                magic: BEACON_MAGIC,
+                port: port,
+                node_id: rand::random::<u64>()
+            }
+        })
+    }
+
+    pub fn announce(&self)
+    {
+        match self.socket.send_to(&self.beacon.to_vec(), &self.group)
+        {
+            Ok(_) => {}
+            Err(e) => { println!("Error sending discovery beacon: {}", e); }
+        }
+    }
+
+    // DRAINS PENDING BEACONS, RETURNING THE ADDRESS OF EACH OTHER NODE HEARD
+    pub fn receive(&self) -> Vec<(String, i32)>
+    {
+        let mut found = vec![];
+        let mut buf = [0; 64];
+        loop
+        {
+            match self.socket.recv_from(&mut buf)
+            {
+                Ok(Some((len, from))) =>
+                {
+                    match Beacon::from_slice(&buf[..len])
+                    {
+                        Some(beacon) if beacon.node_id != self.beacon.node_id =>
+                        {
+                            found.push((canonical_ip(from.ip()).to_string(), beacon.port as i32));
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(None) => { break; }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => { break; }
+                Err(e) =>
+                {
+                    println!("Error receiving discovery beacon: {}", e);
+                    break;
+                }
+            }
+        }
+        found
+    }
+}
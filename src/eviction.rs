@@ -0,0 +1,58 @@
+extern crate mio;
+use self::mio::{Token};
+
+use std::cmp;
+use std::collections::{HashMap};
+
+const PROTECT_BY_LATENCY: usize = 4;
+const PROTECT_BY_BLOCK_RELAY: usize = 4;
+
+#[derive(Clone, Debug)]
+pub struct EvictionCandidate
+{
+    pub token:      Token,
+    pub ip:         String,
+    pub connected:  i64,
+    pub latency_ms: Option<u64>,
+    pub last_block: i64
+}
+
+// PICKS AN INBOUND CONNECTION TO DROP TO MAKE ROOM FOR A NEW ONE. THE FASTEST
+// PEERS AND THE ONES THAT MOST RECENTLY GAVE US NEW BLOCKS ARE NEVER CHOSEN,
+// SINCE AN ATTACKER CAN'T CHEAPLY FAKE EITHER. OF WHAT'S LEFT, THE NEWEST
+// CONNECTION FROM WHICHEVER IP HOLDS THE MOST SLOTS GOES.
+pub fn select_eviction(mut candidates: Vec<EvictionCandidate>) -> Option<Token>
+{
+    candidates.sort_by(|a, b| {
+        let a_latency = a.latency_ms.unwrap_or(u64::max_value());
+        let b_latency = b.latency_ms.unwrap_or(u64::max_value());
+        a_latency.cmp(&b_latency)
+    });
+    let protected = candidates.iter().filter(|c| c.latency_ms.is_some()).count();
+    candidates.drain(..cmp::min(protected, PROTECT_BY_LATENCY));
+
+    candidates.sort_by(|a, b| b.last_block.cmp(&a.last_block));
+    let protected = candidates.iter().filter(|c| c.last_block > 0).count();
+    candidates.drain(..cmp::min(protected, PROTECT_BY_BLOCK_RELAY));
+
+    if candidates.is_empty()
+    {
+        return None;
+    }
+
+    let mut by_ip: HashMap<String, Vec<EvictionCandidate>> = HashMap::new();
+    for candidate in candidates
+    {
+        by_ip.entry(candidate.ip.clone()).or_insert(vec![]).push(candidate);
+    }
+
+    let most_connected = by_ip
+        .values()
+        .max_by_key(|group| (group.len(), group.iter().map(|c| c.connected).max()))
+        .unwrap();
+
+    most_connected
+        .iter()
+        .max_by_key(|c| c.connected)
+        .map(|c| c.token)
+}
@@ -0,0 +1,216 @@
+extern crate byteorder;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use block::*;
+use bloom::{outpoint};
+use crypto;
+use util::{NBYTES_U32};
+
+// GOLOMB-RICE PARAMETERS: EACH ITEM HASHES INTO [0, N * M) AND THE LOW P BITS
+// OF EACH GAP ARE WRITTEN AS IS, WHICH GIVES ABOUT ONE FALSE POSITIVE IN M
+const P: u8 = 19;
+const M: u64 = 784931;
+pub const MAX_FILTER_ITEMS: u32 = 1 << 20;
+pub const MAX_CFILTERS: usize = 1000;
+
+// A COMPACT, PROBABILISTIC SET OF EVERYTHING A BLOCK TOUCHES. UNLIKE A BLOOM
+// FILTER THE CLIENT DOWNLOADS IT AND TESTS IT ITSELF, SO WE NEVER LEARN WHAT
+// IT WAS LOOKING FOR.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GcsFilter
+{
+    pub n:      u32,
+    pub data:   Vec<u8>
+}
+
+impl GcsFilter
+{
+    // key SALTS THE HASHES; FOR A BLOCK'S FILTER IT'S THE BLOCK HASH
+    pub fn new(key: &[u8], items: &[Vec<u8>]) -> GcsFilter
+    {
+        let n = items.len() as u32;
+        let mut values: Vec<u64> = items.iter().map(|item| hash_to_range(key, item, n)).collect();
+        values.sort();
+
+        let mut writer = BitWriter::new();
+        let mut last = 0;
+        for value in values
+        {
+            let delta = value - last;
+            for _ in 0..(delta >> P)
+            {
+                writer.write(1, 1);
+            }
+            writer.write(0, 1);
+            writer.write(delta & ((1 << P) - 1), P);
+            last = value;
+        }
+        GcsFilter {
+            n: n,
+            data: writer.finish()
+        }
+    }
+
+    // OUTPUT ADDRESSES AND THE OUTPOINTS THE BLOCK SPENDS, EACH ONCE
+    pub fn for_block(block: &Block) -> GcsFilter
+    {
+        let mut items = vec![];
+        for tx in block.txs.iter()
+        {
+            for output in tx.outputs.iter()
+            {
+                items.push(output.address.to_vec());
+            }
+            for input in tx.inputs.iter()
+            {
+                items.push(outpoint(&input.src_hash, input.src_idx));
+            }
+        }
+        items.sort();
+        items.dedup();
+        GcsFilter::new(&block.block_hash, &items)
+    }
+
+    pub fn contains(&self, key: &[u8], item: &[u8]) -> bool
+    {
+        self.match_any(key, &[item.to_vec()])
+    }
+
+    // WALKS THE SORTED SET AND THE SORTED QUERY TOGETHER, SO ONE PASS
+    // ANSWERS FOR EVERY ITEM
+    pub fn match_any(&self, key: &[u8], items: &[Vec<u8>]) -> bool
+    {
+        if self.n == 0 || items.is_empty() { return false; }
+        let mut targets: Vec<u64> = items.iter().map(|item| hash_to_range(key, item, self.n)).collect();
+        targets.sort();
+
+        let mut reader = BitReader::new(&self.data);
+        let mut value = 0;
+        let mut t = 0;
+        for _ in 0..self.n
+        {
+            let mut q = 0;
+            loop
+            {
+                match reader.read(1)
+                {
+                    Some(1) => { q += 1; }
+                    Some(_) => { break; }
+                    None => { return false; }
+                }
+            }
+            match reader.read(P)
+            {
+                Some(r) => { value += (q << P) | r; }
+                None => { return false; }
+            }
+            while targets[t] < value
+            {
+                t += 1;
+                if t == targets.len() { return false; }
+            }
+            if targets[t] == value { return true; }
+        }
+        false
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Option<GcsFilter>
+    {
+        if bytes.len() < NBYTES_U32 { return None; }
+        let n = LittleEndian::read_u32(&bytes[..NBYTES_U32]);
+        if n > MAX_FILTER_ITEMS { return None; }
+        Some(GcsFilter {
+            n: n,
+            data: bytes[NBYTES_U32..].to_vec()
+        })
+    }
+
+    pub fn to_vec(&self) -> Vec<u8>
+    {
+        let mut buf = [0; NBYTES_U32];
+        LittleEndian::write_u32(&mut buf, self.n);
+        let mut bytes = buf.to_vec();
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+}
+
+// MAPS THE ITEM'S HASH UNIFORMLY ONTO [0, n * M) WITHOUT A DIVISION
+fn hash_to_range(key: &[u8], item: &[u8], n: u32) -> u64
+{
+    let mut bytes = key.to_vec();
+    bytes.extend_from_slice(item);
+    let hash = LittleEndian::read_u64(&crypto::digest_sha256(&bytes)[..8]);
+    ((hash as u128 * (n as u64 * M) as u128) >> 64) as u64
+}
+
+// MOST SIGNIFICANT BIT FIRST
+struct BitWriter
+{
+    bytes:  Vec<u8>,
+    nbits:  usize
+}
+
+impl BitWriter
+{
+    fn new() -> BitWriter
+    {
+        BitWriter {
+            bytes: vec![],
+            nbits: 0
+        }
+    }
+
+    fn write(&mut self, value: u64, nbits: u8)
+    {
+        for i in (0..nbits).rev()
+        {
+            if self.nbits % 8 == 0
+            {
+                self.bytes.push(0);
+            }
+            if (value >> i) & 1 == 1
+            {
+                let last = self.bytes.len() - 1;
+                self.bytes[last] |= 0x80 >> (self.nbits % 8);
+            }
+            self.nbits += 1;
+        }
+    }
+
+    fn finish(self) -> Vec<u8>
+    {
+        self.bytes
+    }
+}
+
+struct BitReader<'a>
+{
+    bytes:  &'a [u8],
+    pos:    usize
+}
+
+impl<'a> BitReader<'a>
+{
+    fn new(bytes: &'a [u8]) -> BitReader<'a>
+    {
+        BitReader {
+            bytes: bytes,
+            pos: 0
+        }
+    }
+
+    fn read(&mut self, nbits: u8) -> Option<u64>
+    {
+        let mut value = 0;
+        for _ in 0..nbits
+        {
+            if self.pos >= self.bytes.len() * 8 { return None; }
+            let bit = (self.bytes[self.pos / 8] >> (7 - self.pos % 8)) & 1;
+            value = (value << 1) | bit as u64;
+            self.pos += 1;
+        }
+        Some(value)
+    }
+}
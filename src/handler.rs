@@ -0,0 +1,113 @@
+extern crate mio;
+
+use self::mio::{Token};
+use self::mio::channel::{Sender};
+
+use addrman::*;
+use ban::*;
+use bandwidth::*;
+use block::*;
+use chat::*;
+use compact::*;
+use config::*;
+use database::{Store};
+use dial::*;
+use handshake::*;
+use inventory::*;
+use message::*;
+use peer::*;
+use query::*;
+use sync::*;
+use timedata::*;
+use transaction::*;
+
+use std::collections::{HashMap};
+
+// EVERYTHING A HANDLER MAY TOUCH WHILE IT DEALS WITH ONE MESSAGE. token IS
+// THE CONNECTION THE MESSAGE ARRIVED ON.
+pub struct NodeContext<'a>
+{
+    pub token:                      Token,
+    pub clients:                    &'a mut HashMap<Token, Client>,
+    pub peers:                      &'a mut Vec<Peer>,
+    pub addrman:                    &'a mut AddrMan,
+    pub bans:                       &'a mut BanList,
+    pub sync:                       &'a mut HeaderSync,
+    pub inv_requests:               &'a mut InvRequests,
+    pub partial_blocks:             &'a mut PartialBlocks,
+    pub chat_log:                   &'a mut ChatLog,
+    pub pending_requests:           &'a mut PendingRequests,
+    pub config:                     &'a Config,
+    pub identity:                   &'a Identity,
+    pub db:                         &'a dyn Store,
+    pub transaction_snd_to_mine:    &'a Sender<Transaction>,
+    pub block_snd_to_mine:          &'a Sender<Block>,
+    pub bandwidth:                  &'a Bandwidth,
+    pub network_time:               &'a NetworkTime,
+    // DIALS TO START ONCE THE HANDLER RETURNS; HANDLERS NEVER DIAL THEMSELVES
+    pub dial_requests:              &'a mut Vec<DialRequest>
+}
+
+impl<'a> NodeContext<'a>
+{
+    // THE PEER WE WRITE TO FOR THIS CONNECTION, ONCE IT HAS SENT ITS addp
+    pub fn peer(&mut self) -> Option<&mut Peer>
+    {
+        let token = self.token;
+        self.peers.iter_mut().find(|p| p.token == Some(token))
+    }
+
+    pub fn reply(&mut self, msg: &Msg) -> bool
+    {
+        match self.peer()
+        {
+            Some(peer) => { peer.send(msg) }
+            None => { false }
+        }
+    }
+}
+
+// HANDLES EVERY MESSAGE RECEIVED UNDER THE COMMANDS IT IS REGISTERED FOR. AN
+// Err COUNTS AGAINST THE SENDER THE SAME WAY A BAD BUILT-IN MESSAGE DOES.
+pub trait MessageHandler
+{
+    fn handle(&mut self, msg: &Msg, ctx: &mut NodeContext) -> Result<(), Misbehaviour>;
+}
+
+impl<F> MessageHandler for F
+    where F: FnMut(&Msg, &mut NodeContext) -> Result<(), Misbehaviour>
+{
+    fn handle(&mut self, msg: &Msg, ctx: &mut NodeContext) -> Result<(), Misbehaviour>
+    {
+        self(msg, ctx)
+    }
+}
+
+pub struct HandlerRegistry
+{
+    handlers: HashMap<[u8; 12], Box<dyn MessageHandler + Send>>
+}
+
+impl HandlerRegistry
+{
+    pub fn new() -> HandlerRegistry
+    {
+        HandlerRegistry {
+            handlers: HashMap::new()
+        }
+    }
+
+    // REPLACES WHATEVER WAS REGISTERED FOR THE COMMAND BEFORE, BUILT-INS
+    // INCLUDED, AND SAYS WHETHER THERE WAS ONE
+    pub fn register<H>(&mut self, command: &[u8; 12], handler: H) -> bool
+        where H: MessageHandler + Send + 'static
+    {
+        self.handlers.insert(*command, Box::new(handler)).is_some()
+    }
+
+    // NONE WHEN NOTHING IS REGISTERED FOR THE COMMAND
+    pub fn dispatch(&mut self, msg: &Msg, ctx: &mut NodeContext) -> Option<Result<(), Misbehaviour>>
+    {
+        self.handlers.get_mut(&msg.command).map(|handler| handler.handle(msg, ctx))
+    }
+}
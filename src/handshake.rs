@@ -0,0 +1,345 @@
+extern crate ring;
+extern crate untrusted;
+extern crate byteorder;
+
+use self::ring::{aead, agreement, digest, hkdf, hmac, rand};
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use crypto;
+use message::*;
+use util::{NBYTES_U32, NBYTES_U64};
+
+use std::fmt;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt};
+
+static NODE_PATH: &'static str = ".node";
+static NODE_PUBLIC_KEY_PATH: &'static str = ".node/id_ed25519.pub";
+static NODE_PRIVATE_KEY_PATH: &'static str = ".node/id_ed25519";
+
+const PROTOCOL_NAME: &'static [u8] = b"rust-chain-handshake-v1";
+pub const HANDSHAKE_TIMEOUT_SECS: u64 = 5;
+
+pub const KEY_LEN: usize = 32;
+pub const SIG_LEN: usize = 64;
+pub const TAG_LEN: usize = 16;
+pub const HELLO_LEN: usize = 2 * KEY_LEN;
+pub const HELLO_ACK_LEN: usize = 2 * KEY_LEN + SIG_LEN;
+pub const HELLO_FIN_LEN: usize = SIG_LEN;
+// ONLY THE NODE'S OWN USER MAY READ ITS PRIVATE KEY
+const PRIVATE_KEY_MODE: u32 = 0o600;
+
+#[derive(Debug, PartialEq)]
+pub enum HandshakeError
+{
+    Malformed,
+    BadSignature,
+    PinMismatch,
+    Crypto
+}
+
+// THE NODE'S LONG-TERM ED25519 KEY, KEPT IN .node THE SAME WAY THE WALLET
+// KEEPS ITS KEY IN .wallet. PEERS PIN THE PUBLIC HALF WITH --pin.
+#[derive(Clone)]
+pub struct Identity
+{
+    pub public_key: [u8; KEY_LEN],
+    private_key:    [u8; KEY_LEN]
+}
+
+impl Identity
+{
+    pub fn generate() -> Identity
+    {
+        let mut identity = Identity {
+            public_key: [0; KEY_LEN],
+            private_key: [0; KEY_LEN]
+        };
+        crypto::gen_ed25519keypair(&mut identity.public_key, &mut identity.private_key);
+        identity
+    }
+
+    pub fn load_or_create() -> Identity
+    {
+        match fs::metadata(NODE_PATH)
+        {
+            Ok(_) => {
+                let mut identity = Identity {
+                    public_key: [0; KEY_LEN],
+                    private_key: [0; KEY_LEN]
+                };
+                let mut public_key_file = File::open(NODE_PUBLIC_KEY_PATH).expect("Failed to open node public key file");
+                let mut private_key_file = File::open(NODE_PRIVATE_KEY_PATH).expect("Failed to open node private key file");
+                public_key_file.read_exact(&mut identity.public_key).expect("Failed reading node public key from file");
+                private_key_file.read_exact(&mut identity.private_key).expect("Failed reading node private key from file");
+                identity
+            }
+            Err(_) => {
+                fs::create_dir(NODE_PATH).expect("Failed creating node directory");
+                let mut public_key_file = File::create(NODE_PUBLIC_KEY_PATH).expect("Failed creating node public key file");
+                let mut private_key_file = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(PRIVATE_KEY_MODE)
+                    .open(NODE_PRIVATE_KEY_PATH)
+                    .expect("Failed creating node private key file");
+                let identity = Identity::generate();
+                public_key_file.write_all(&identity.public_key).expect("Failed writing node public key to file");
+                private_key_file.write_all(&identity.private_key).expect("Failed writing node private key to file");
+                identity
+            }
+        }
+    }
+
+    pub fn sign(&self, bytes: &[u8]) -> Vec<u8>
+    {
+        crypto::sign_ed25519(bytes, &self.public_key, &self.private_key)
+    }
+}
+
+// ONE DIRECTION OF AN ENCRYPTED CONNECTION. THE INITIATOR ONLY WRITES ON ITS
+// OUTBOUND SOCKET AND THE RESPONDER ONLY READS FROM THE ACCEPTED ONE, SO EACH
+// HANDSHAKE YIELDS A SINGLE KEY. FRAMES ARE [len u32][ciphertext + tag] WITH
+// THE LENGTH AUTHENTICATED AS ASSOCIATED DATA AND A COUNTER FOR THE NONCE.
+pub struct Session
+{
+    key:    [u8; KEY_LEN],
+    nonce:  u64
+}
+
+impl Session
+{
+    fn new(key: [u8; KEY_LEN]) -> Session
+    {
+        Session {
+            key: key,
+            nonce: 0
+        }
+    }
+
+    fn next_nonce(&mut self) -> [u8; 12]
+    {
+        let mut nonce = [0; 12];
+        LittleEndian::write_u64(&mut nonce[12-NBYTES_U64..], self.nonce);
+        self.nonce += 1;
+        nonce
+    }
+
+    pub fn seal(&mut self, plaintext: &[u8]) -> Vec<u8>
+    {
+        let key = aead::SealingKey::new(&aead::CHACHA20_POLY1305, &self.key).expect("Invalid session key");
+        let nonce = self.next_nonce();
+        let mut len = [0; NBYTES_U32];
+        LittleEndian::write_u32(&mut len, (plaintext.len() + TAG_LEN) as u32);
+
+        let mut in_out = plaintext.to_vec();
+        in_out.extend_from_slice(&[0; TAG_LEN]);
+        let sealed_len = aead::seal_in_place(&key, &nonce, &mut in_out, TAG_LEN, &len).expect("Failed sealing frame");
+        in_out.truncate(sealed_len);
+
+        let mut frame = len.to_vec();
+        frame.extend_from_slice(&in_out);
+        frame
+    }
+
+    // TAKES THE FIRST FRAME OFF buf AND DECRYPTS IT, ONCE ALL OF IT HAS ARRIVED.
+    // THE NONCE ONLY MOVES ON FOR A WHOLE FRAME, SO A FRAME SPLIT ACROSS READS
+    // ON A NON-BLOCKING SOCKET LEAVES THE SESSION WHERE IT WAS.
+    pub fn open(&mut self, buf: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>>
+    {
+        if buf.len() < NBYTES_U32
+        {
+            return Ok(None);
+        }
+        let framelen = LittleEndian::read_u32(&buf[..NBYTES_U32]);
+        if framelen < TAG_LEN as u32 || framelen > MAX_PAYLOAD_LEN + 64
+        {
+            return Err(Error::new(ErrorKind::InvalidData, "bad frame length"));
+        }
+        let end = NBYTES_U32 + framelen as usize;
+        if buf.len() < end
+        {
+            return Ok(None);
+        }
+        let frame: Vec<u8> = buf.drain(..end).collect();
+        let mut in_out = frame[NBYTES_U32..].to_vec();
+
+        let key = aead::OpeningKey::new(&aead::CHACHA20_POLY1305, &self.key).expect("Invalid session key");
+        let nonce = self.next_nonce();
+        match aead::open_in_place(&key, &nonce, 0, &mut in_out, &frame[..NBYTES_U32])
+        {
+            Ok(plainlen) =>
+            {
+                in_out.truncate(plainlen);
+                Ok(Some(in_out))
+            }
+            Err(_) => { Err(Error::new(ErrorKind::InvalidData, "frame failed authentication")) }
+        }
+    }
+
+    // A FRAME HOLDS EXACTLY ONE MESSAGE, SO ONE THAT DOESN'T DECODE IS MALFORMED
+    pub fn read_msg(&mut self, buf: &mut Vec<u8>) -> io::Result<Option<Msg>>
+    {
+        match self.open(buf)?
+        {
+            Some(plaintext) =>
+            {
+                Msg::from_stream(&mut &plaintext[..])
+                    .map(Some)
+                    .map_err(|_| Error::new(ErrorKind::InvalidData, "malformed message in frame"))
+            }
+            None => { Ok(None) }
+        }
+    }
+}
+
+impl fmt::Debug for Session
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "Session {{ nonce: {} }}", self.nonce)
+    }
+}
+
+// HASH OF BOTH SIDES' EPHEMERAL AND STATIC KEYS. EACH SIDE SIGNS IT WITH ITS
+// STATIC KEY, WHICH TIES THE EPHEMERAL EXCHANGE TO THE IDENTITIES.
+fn transcript_hash(hello: &[u8], responder_ephemeral: &[u8], responder_static: &[u8]) -> Vec<u8>
+{
+    let mut transcript = PROTOCOL_NAME.to_vec();
+    transcript.extend_from_slice(hello);
+    transcript.extend_from_slice(responder_ephemeral);
+    transcript.extend_from_slice(responder_static);
+    crypto::digest_sha256(&transcript)
+}
+
+fn signed_bytes(role: &[u8], transcript: &[u8]) -> Vec<u8>
+{
+    let mut bytes = role.to_vec();
+    bytes.extend_from_slice(transcript);
+    bytes
+}
+
+fn session_key(shared: &[u8], transcript: &[u8]) -> [u8; KEY_LEN]
+{
+    let salt = hmac::SigningKey::new(&digest::SHA256, transcript);
+    let mut key = [0; KEY_LEN];
+    hkdf::extract_and_expand(&salt, shared, b"initiator to responder", &mut key);
+    key
+}
+
+fn ephemeral_keypair() -> Result<(agreement::EphemeralPrivateKey, [u8; KEY_LEN]), HandshakeError>
+{
+    let rng = rand::SystemRandom::new();
+    let private_key = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng).map_err(|_| HandshakeError::Crypto)?;
+    let mut public_key = [0; KEY_LEN];
+    private_key.compute_public_key(&mut public_key).map_err(|_| HandshakeError::Crypto)?;
+    Ok((private_key, public_key))
+}
+
+fn agree(private_key: agreement::EphemeralPrivateKey, public_key: &[u8]) -> Result<Vec<u8>, HandshakeError>
+{
+    agreement::agree_ephemeral(
+        private_key,
+        &agreement::X25519,
+        untrusted::Input::from(public_key),
+        HandshakeError::Crypto,
+        |shared| Ok(shared.to_vec()))
+}
+
+fn to_key(bytes: &[u8]) -> [u8; KEY_LEN]
+{
+    let mut key = [0; KEY_LEN];
+    key.clone_from_slice(bytes);
+    key
+}
+
+// hello:    I -> R  ephemeral | static
+// helloack: R -> I  ephemeral | static | sig_R("responder" | transcript)
+// hellofin: I -> R  sig_I("initiator" | transcript)
+pub struct Initiator
+{
+    identity:   Identity,
+    ephemeral:  agreement::EphemeralPrivateKey,
+    hello:      Vec<u8>
+}
+
+impl Initiator
+{
+    pub fn new(identity: &Identity) -> Result<(Initiator, Vec<u8>), HandshakeError>
+    {
+        let (ephemeral, ephemeral_public) = ephemeral_keypair()?;
+        let mut hello = ephemeral_public.to_vec();
+        hello.extend_from_slice(&identity.public_key);
+        Ok((Initiator { identity: identity.clone(), ephemeral: ephemeral, hello: hello.clone() }, hello))
+    }
+
+    // RETURNS THE SENDING SESSION, THE hellofin PAYLOAD AND THE RESPONDER'S KEY
+    pub fn finish(
+        self,
+        ack: &[u8],
+        pin: Option<&[u8; KEY_LEN]>) -> Result<(Session, Vec<u8>, [u8; KEY_LEN]), HandshakeError>
+    {
+        if ack.len() != HELLO_ACK_LEN { return Err(HandshakeError::Malformed); }
+        let responder_ephemeral = &ack[..KEY_LEN];
+        let responder_static = to_key(&ack[KEY_LEN..2*KEY_LEN]);
+        let sig = &ack[2*KEY_LEN..];
+
+        let transcript = transcript_hash(&self.hello, responder_ephemeral, &responder_static);
+        if !crypto::verify_ed25519(&signed_bytes(b"responder", &transcript), sig, &responder_static)
+        {
+            return Err(HandshakeError::BadSignature);
+        }
+        if pin.map_or(false, |pin| *pin != responder_static)
+        {
+            return Err(HandshakeError::PinMismatch);
+        }
+
+        let shared = agree(self.ephemeral, responder_ephemeral)?;
+        let fin = self.identity.sign(&signed_bytes(b"initiator", &transcript));
+        Ok((Session::new(session_key(&shared, &transcript)), fin, responder_static))
+    }
+}
+
+pub struct Responder
+{
+    key:        [u8; KEY_LEN],
+    transcript: Vec<u8>,
+    initiator:  [u8; KEY_LEN]
+}
+
+impl Responder
+{
+    pub fn new(identity: &Identity, hello: &[u8]) -> Result<(Responder, Vec<u8>), HandshakeError>
+    {
+        if hello.len() != HELLO_LEN { return Err(HandshakeError::Malformed); }
+        let (ephemeral, ephemeral_public) = ephemeral_keypair()?;
+        let transcript = transcript_hash(hello, &ephemeral_public, &identity.public_key);
+        let shared = agree(ephemeral, &hello[..KEY_LEN])?;
+
+        let mut ack = ephemeral_public.to_vec();
+        ack.extend_from_slice(&identity.public_key);
+        ack.extend_from_slice(&identity.sign(&signed_bytes(b"responder", &transcript)));
+
+        let responder = Responder {
+            key: session_key(&shared, &transcript),
+            transcript: transcript,
+            initiator: to_key(&hello[KEY_LEN..])
+        };
+        Ok((responder, ack))
+    }
+
+    // RETURNS THE RECEIVING SESSION AND THE INITIATOR'S KEY
+    pub fn finish(self, fin: &[u8]) -> Result<(Session, [u8; KEY_LEN]), HandshakeError>
+    {
+        if fin.len() != HELLO_FIN_LEN { return Err(HandshakeError::Malformed); }
+        if !crypto::verify_ed25519(&signed_bytes(b"initiator", &self.transcript), fin, &self.initiator)
+        {
+            return Err(HandshakeError::BadSignature);
+        }
+        Ok((Session::new(self.key), self.initiator))
+    }
+}
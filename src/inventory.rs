@@ -0,0 +1,152 @@
+extern crate byteorder;
+extern crate chrono;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use self::chrono::*;
+
+use util::{NBYTES_U32};
+
+use std::collections::{HashMap};
+
+pub const INV_ITEM_LEN: usize = 36;
+pub const MAX_INV_ITEMS: usize = 50000;
+const GETDATA_TIMEOUT: i64 = 30;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum InvKind
+{
+    Transaction,
+    Block,
+    // A BLOCK AS A merkleblock, FILTERED BY THE REQUESTER'S BLOOM FILTER
+    FilteredBlock
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct InvItem
+{
+    pub kind:   InvKind,
+    pub hash:   [u8; 32]
+}
+
+impl InvItem
+{
+    pub fn new(kind: InvKind, hash: &[u8]) -> InvItem
+    {
+        let mut item = InvItem {
+            kind: kind,
+            hash: [0; 32]
+        };
+        item.hash.clone_from_slice(hash);
+        item
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Option<InvItem>
+    {
+        let kind = match LittleEndian::read_u32(&bytes[..NBYTES_U32])
+        {
+            1 => { InvKind::Transaction }
+            2 => { InvKind::Block }
+            3 => { InvKind::FilteredBlock }
+            _ => { return None; }
+        };
+        Some(InvItem::new(kind, &bytes[NBYTES_U32..INV_ITEM_LEN]))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8>
+    {
+        let mut kind = [0; NBYTES_U32];
+        LittleEndian::write_u32(&mut kind, match self.kind
+        {
+            InvKind::Transaction => { 1 }
+            InvKind::Block => { 2 }
+            InvKind::FilteredBlock => { 3 }
+        });
+        let mut array = vec![];
+        array.extend_from_slice(&kind);
+        array.extend_from_slice(&self.hash);
+        array
+    }
+}
+
+pub fn items_to_vec(items: &[InvItem]) -> Vec<u8>
+{
+    let mut array = vec![];
+    let mut count = [0; NBYTES_U32];
+    LittleEndian::write_u32(&mut count, items.len() as u32);
+    array.extend_from_slice(&count);
+    for item in items.iter()
+    {
+        array.extend_from_slice(&item.to_vec());
+    }
+    array
+}
+
+pub fn items_from_slice(bytes: &[u8]) -> Option<Vec<InvItem>>
+{
+    if bytes.len() < NBYTES_U32 { return None; }
+    let count = LittleEndian::read_u32(&bytes[..NBYTES_U32]) as usize;
+    if count > MAX_INV_ITEMS || bytes.len() != NBYTES_U32 + count * INV_ITEM_LEN { return None; }
+
+    let mut items = vec![];
+    for i in 0..count
+    {
+        let start = NBYTES_U32 + i * INV_ITEM_LEN;
+        match InvItem::from_slice(&bytes[start..start+INV_ITEM_LEN])
+        {
+            Some(item) => { items.push(item); }
+            None => { return None; }
+        }
+    }
+    Some(items)
+}
+
+// ITEMS WE'VE ASKED SOMEONE FOR, SO THAT ANNOUNCEMENTS FROM OTHER PEERS
+// DON'T TRIGGER DUPLICATE DOWNLOADS WHILE THE FIRST REQUEST IS OUTSTANDING.
+// ONLY THE PEER WE ASKED CAN CLEAR A REQUEST, OR ANYONE COULD CANCEL OUR
+// DOWNLOADS WITH notfound.
+pub struct InvRequests
+{
+    requested: HashMap<[u8; 32], (String, i32, i64)>
+}
+
+impl InvRequests
+{
+    pub fn new() -> InvRequests
+    {
+        InvRequests {
+            requested: HashMap::new()
+        }
+    }
+
+    pub fn should_request(&mut self, hash: &[u8; 32], ip: &str, port: i32) -> bool
+    {
+        let now = UTC::now().timestamp();
+        match self.requested.get(hash)
+        {
+            Some(&(_, _, requested)) if now - requested < GETDATA_TIMEOUT => { return false; }
+            _ => {}
+        }
+        self.requested.insert(*hash, (ip.to_string(), port, now));
+        true
+    }
+
+    pub fn received(&mut self, hash: &[u8; 32], ip: &str, port: i32)
+    {
+        let from_requested = match self.requested.get(hash)
+        {
+            Some(&(ref requested_ip, requested_port, _)) => { requested_ip == ip && requested_port == port }
+            None => { false }
+        };
+        if from_requested
+        {
+            self.requested.remove(hash);
+        }
+    }
+
+    pub fn expire(&mut self)
+    {
+        let now = UTC::now().timestamp();
+        self.requested.retain(|_, &mut (_, _, requested)| now - requested < GETDATA_TIMEOUT);
+    }
+}
@@ -3,22 +3,49 @@ pub mod wallet;
 pub mod transaction;
 pub mod message;
 pub mod peer;
+pub mod bloom;
+pub mod gcs;
 mod util;
 mod network;
+mod sync;
+mod inventory;
 mod mining;
 mod database;
 mod crypto;
+mod config;
+mod ban;
+mod bandwidth;
+mod eviction;
+mod addrman;
+mod persistent;
+mod discovery;
+mod handshake;
+mod compact;
+mod chat;
+mod query;
+mod handler;
+mod dial;
+mod socks;
+mod timedata;
+mod stale;
+pub mod transport;
+#[cfg(test)]
+mod sim;
 mod tests;
 
 use transaction::*;
 use block::*;
 use network::*;
 use mining::*;
+use config::*;
+use query::*;
+use timedata::*;
+use util::{from_hex_string, parse_host_addr};
 
 use std::env;
 
 extern crate mio;
-use self::mio::channel::{channel};
+use self::mio::channel::{channel, Sender};
 
 extern crate rustyline;
 use self::rustyline::error::ReadlineError;
@@ -28,24 +55,41 @@ use std::thread;
 
 pub fn main()
 {
+    let config = match Config::from_args(env::args().skip(1))
+    {
+        Ok(config) => { config }
+        Err(e) =>
+        {
+            println!("{}", e);
+            return;
+        }
+    };
+
     let (transaction_snd_to_mine, transaction_rcv_from_network) = channel::<Transaction>();
     let (block_snd_to_mine, block_rcv_from_network) = channel::<Block>();
     let (block_snd_to_network, block_rcv_from_mine) = channel::<Block>();
+    let network_time = NetworkTime::new();
+    let mining_time = network_time.clone();
     let mining_child = thread::spawn(move || {
         start_mining(
             transaction_rcv_from_network,
             block_rcv_from_network,
-            block_snd_to_network);
+            block_snd_to_network,
+            mining_time);
     });
 
     let (quit_snd, quit_rcv) = channel::<()>();
+    let (command_snd, command_rcv) = channel::<NetworkCommand>();
     let network_child = thread::spawn(move || {
         start_server(
-            env::args().nth(1),
+            config,
+            default_handlers(),
             quit_rcv,
+            command_rcv,
             transaction_snd_to_mine,
             block_snd_to_mine,
-            block_rcv_from_mine);
+            block_rcv_from_mine,
+            network_time);
     });
 
     let mut rl = Editor::<()>::new();
@@ -57,7 +101,60 @@ pub fn main()
         match readline {
             Ok(line) => {
                 rl.add_history_entry(&line);
-                println!("Line: {}", line);
+                let words: Vec<&str> = line.split_whitespace().collect();
+                match words.as_slice()
+                {
+                    &["addnode", addr] =>
+                    {
+                        match parse_host_addr(addr)
+                        {
+                            Some((ip, port)) => { let _ = command_snd.send(NetworkCommand::AddNode(ip, port)); }
+                            None => { println!("Usage: addnode <host:port>"); }
+                        }
+                    }
+                    &["height"] => { ask(&command_snd, Query::Height); }
+                    &["latest"] => { ask(&command_snd, Query::Latest); }
+                    &["blocks", start, count] =>
+                    {
+                        match (start.parse::<u64>(), count.parse::<u32>())
+                        {
+                            (Ok(start), Ok(count)) => { ask(&command_snd, Query::ListBlocks(start, count)); }
+                            _ => { println!("Usage: blocks <start height> <count>"); }
+                        }
+                    }
+                    &["balance"] =>
+                    {
+                        let mut key = [0; 32];
+                        key.clone_from_slice(&wallet::get_public_key());
+                        ask(&command_snd, Query::Balance(key));
+                    }
+                    &["balance", public_key] =>
+                    {
+                        match from_hex_string(public_key)
+                        {
+                            Some(ref bytes) if bytes.len() == 32 =>
+                            {
+                                let mut key = [0; 32];
+                                key.clone_from_slice(bytes);
+                                ask(&command_snd, Query::Balance(key));
+                            }
+                            _ => { println!("Usage: balance <hex public key>"); }
+                        }
+                    }
+                    &["validate", tx] =>
+                    {
+                        match from_hex_string(tx).and_then(|bytes| Transaction::try_from_slice(&bytes))
+                        {
+                            Some(tx) => { ask(&command_snd, Query::Validate(tx)); }
+                            None => { println!("Usage: validate <hex transaction>"); }
+                        }
+                    }
+                    &["echo", ..] => { ask(&command_snd, Query::Echo(line.trim()[4..].trim().as_bytes().to_vec())); }
+                    &["netstats"] => { let _ = command_snd.send(NetworkCommand::NetStats); }
+                    &["status"] => { let _ = command_snd.send(NetworkCommand::Status); }
+                    &["chat", ..] => { let _ = command_snd.send(NetworkCommand::Chat(line.trim()[4..].trim().to_string())); }
+                    _ => { println!("Line: {}", line); }
+                }
             },
             Err(ReadlineError::Interrupted) => {
                 println!("CTRL-C");
@@ -81,3 +178,19 @@ pub fn main()
     }
     rl.save_history("history.txt").unwrap();
 }
+
+// WAITS FOR THE ANSWER SO IT PRINTS BEFORE THE NEXT PROMPT
+fn ask(command_snd: &Sender<NetworkCommand>, query: Query)
+{
+    let (callback, answer) = callback_channel();
+    if command_snd.send(NetworkCommand::Query(query, callback)).is_err()
+    {
+        return;
+    }
+    match answer.recv()
+    {
+        Ok(Ok(response)) => { println!("{}", response); }
+        Ok(Err(error)) => { println!("Query failed: {:?}", error); }
+        Err(_) => {}
+    }
+}
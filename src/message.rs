@@ -1,15 +1,27 @@
-extern crate mio;
 extern crate byteorder;
-extern crate rand;
 
 use self::byteorder::{ByteOrder, LittleEndian};
 
 use util::{NBYTES_U32, NBYTES_U64};
 
-use self::mio::tcp::{TcpStream};
-use std::io::{Error, Read};
+use std::io::{Error, ErrorKind, Read};
+use std::net::{SocketAddr};
+use std::str;
 
-use peer::*;
+use block::*;
+use bloom::*;
+use gcs::*;
+use inventory::*;
+use addrman::*;
+use compact::*;
+use chat::*;
+use query::*;
+use sync::{MAX_HEADERS};
+use transaction::*;
+
+pub const MAX_PAYLOAD_LEN: u32 = 32 * 1024 * 1024;
+// MAGIC, COMMAND, LENGTH AND CHECKSUM
+pub const MSG_HEADER_LEN: usize = 24;
 
 #[derive(Clone, Debug)]
 pub struct Msg
@@ -23,7 +35,20 @@ pub struct Msg
 
 impl Msg
 {
-    pub fn from_stream(stream: &mut TcpStream) -> Result<Msg, Error>
+    pub fn new(command: &[u8], payload: Vec<u8>) -> Msg
+    {
+        let mut msg = Msg {
+            magic:      0u32,
+            command:    [0; 12],
+            length:     payload.len() as u32,
+            checksum:   [0; 4],
+            payload:    payload
+        };
+        msg.command.clone_from_slice(command);
+        msg
+    }
+
+    pub fn from_stream<R: Read>(stream: &mut R) -> Result<Msg, Error>
     {
         let mut mgc = [0; 4];
         let mut cmd = [0; 12];
@@ -47,9 +72,12 @@ impl Msg
                                     Ok(_) =>
                                     {
                                         let paylen = LittleEndian::read_u32(&len);
-                                        let mut pay = vec![];
-                                        let mut take = stream.take(paylen as u64);
-                                        let _ = take.read_to_end(&mut pay);
+                                        if paylen > MAX_PAYLOAD_LEN
+                                        {
+                                            return Err(Error::new(ErrorKind::InvalidData, "payload too large"));
+                                        }
+                                        let mut pay = vec![0; paylen as usize];
+                                        stream.read_exact(&mut pay)?;
                                         let mut msg = Msg {
                                             magic: LittleEndian::read_u32(&mgc),
                                             command: [0; 12],
@@ -85,6 +113,28 @@ impl Msg
         }
     }
 
+    // TAKES THE FIRST MESSAGE OFF buf ONCE ALL OF IT HAS ARRIVED; NONE UNTIL THEN
+    pub fn from_buffer(buf: &mut Vec<u8>, max_len: u32) -> Result<Option<Msg>, Error>
+    {
+        if buf.len() < MSG_HEADER_LEN
+        {
+            return Ok(None);
+        }
+        let paylen = LittleEndian::read_u32(&buf[16..20]);
+        if paylen > max_len
+        {
+            return Err(Error::new(ErrorKind::InvalidData, "payload too large"));
+        }
+        let end = MSG_HEADER_LEN + paylen as usize;
+        if buf.len() < end
+        {
+            return Ok(None);
+        }
+        let msg = Msg::from_stream(&mut &buf[..end])?;
+        buf.drain(..end);
+        Ok(Some(msg))
+    }
+
     pub fn to_vec(&self) -> Vec<u8>
     {
         let mut mgc = [0; NBYTES_U32];
@@ -100,142 +150,441 @@ impl Msg
         msg
     }
 
-    pub fn new_add_peer(addr: &str, port: &str) -> Msg
+}
+
+// EVERYTHING WE SEND OR RECEIVE, ONE VARIANT PER COMMAND. to_msg AND from_msg
+// ARE THE ONLY PLACES THAT KNOW HOW A PAYLOAD IS LAID OUT ON THE WIRE.
+#[derive(Clone)]
+pub enum NetworkMessage
+{
+    Hello(Vec<u8>),
+    HelloAck(Vec<u8>),
+    HelloFin(Vec<u8>),
+    Ping(u64),
+    Pong(u64),
+    // OUR ADDRESS AND OUR CLOCK, SO THE PEER CAN MEASURE ITS OFFSET FROM US
+    AddPeer(SocketAddr, i64),
+    RemovePeer(SocketAddr),
+    GetAddr,
+    Addr(Vec<NetAddr>),
+    AddTransaction(Transaction),
+    AddBlock(Block),
+    GetBlock([u8; 32], SocketAddr),
+    GetHeaders(Vec<[u8; 32]>, [u8; 32]),
+    Headers(Vec<Block>),
+    Inv(Vec<InvItem>),
+    GetData(Vec<InvItem>),
+    NotFound(Vec<InvItem>),
+    Mempool,
+    CompactBlock(CompactBlock),
+    GetBlockTxn([u8; 32], Vec<u32>),
+    BlockTxn([u8; 32], Vec<Transaction>),
+    Chat(ChatMessage),
+    Query(RequestId, Query),
+    Response(RequestId, Response),
+    FilterLoad(BloomFilter),
+    FilterAdd(Vec<u8>),
+    FilterClear,
+    MerkleBlock(FilteredBlock),
+    GetCFilters(u64, [u8; 32]),
+    CFilters(Vec<([u8; 32], GcsFilter)>)
+}
+
+// EVERY COMMAND NetworkMessage KNOWS HOW TO DECODE
+pub const COMMANDS: &'static [&'static [u8; 12]] = &[
+    b"hello       ", b"helloack    ", b"hellofin    ", b"ping        ", b"pong        ",
+    b"addp        ", b"remp        ", b"getaddr     ", b"addr        ", b"addt        ",
+    b"addb        ", b"getb        ", b"getheaders  ", b"headers     ", b"inv         ",
+    b"getdata     ", b"notfound    ", b"mempool     ", b"cmpctblock  ", b"getblocktxn ",
+    b"blocktxn    ", b"chat        ", b"blnc        ", b"vldt        ", b"geth        ",
+    b"getl        ", b"lisb        ", b"echo        ", b"resp        ", b"filterload  ",
+    b"filteradd   ", b"filterclear ", b"merkleblock ", b"getcfilters ", b"cfilters    "
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DecodeError
+{
+    UnknownCommand,
+    Malformed
+}
+
+impl NetworkMessage
+{
+    pub fn command(&self) -> &'static [u8; 12]
     {
-        let pay = addr.to_string() + ":" + port;
-        let mut msg = Msg {
-            magic:      0u32,
-            command:    [0; 12],
-            length:     pay.len() as u32,
-            checksum:   [0; 4],
-            payload:    pay.as_bytes().to_vec()
-        };
-        msg.command.clone_from_slice(b"addp        ");
-        msg
+        match *self
+        {
+            NetworkMessage::Hello(_) => { b"hello       " }
+            NetworkMessage::HelloAck(_) => { b"helloack    " }
+            NetworkMessage::HelloFin(_) => { b"hellofin    " }
+            NetworkMessage::Ping(_) => { b"ping        " }
+            NetworkMessage::Pong(_) => { b"pong        " }
+            NetworkMessage::AddPeer(..) => { b"addp        " }
+            NetworkMessage::RemovePeer(_) => { b"remp        " }
+            NetworkMessage::GetAddr => { b"getaddr     " }
+            NetworkMessage::Addr(_) => { b"addr        " }
+            NetworkMessage::AddTransaction(_) => { b"addt        " }
+            NetworkMessage::AddBlock(_) => { b"addb        " }
+            NetworkMessage::GetBlock(_, _) => { b"getb        " }
+            NetworkMessage::GetHeaders(_, _) => { b"getheaders  " }
+            NetworkMessage::Headers(_) => { b"headers     " }
+            NetworkMessage::Inv(_) => { b"inv         " }
+            NetworkMessage::GetData(_) => { b"getdata     " }
+            NetworkMessage::NotFound(_) => { b"notfound    " }
+            NetworkMessage::Mempool => { b"mempool     " }
+            NetworkMessage::CompactBlock(_) => { b"cmpctblock  " }
+            NetworkMessage::GetBlockTxn(_, _) => { b"getblocktxn " }
+            NetworkMessage::BlockTxn(_, _) => { b"blocktxn    " }
+            NetworkMessage::Chat(_) => { b"chat        " }
+            NetworkMessage::Query(_, ref query) => { query_kind(query) }
+            NetworkMessage::Response(_, _) => { b"resp        " }
+            NetworkMessage::FilterLoad(_) => { b"filterload  " }
+            NetworkMessage::FilterAdd(_) => { b"filteradd   " }
+            NetworkMessage::FilterClear => { b"filterclear " }
+            NetworkMessage::MerkleBlock(_) => { b"merkleblock " }
+            NetworkMessage::GetCFilters(_, _) => { b"getcfilters " }
+            NetworkMessage::CFilters(_) => { b"cfilters    " }
+        }
     }
 
-    pub fn new_remove_peer(addr: &str, port: &str) -> Msg
+    pub fn to_msg(&self) -> Msg
     {
-        let pay = addr.to_string() + ":" + port;
-        let mut msg = Msg {
-            magic:      0u32,
-            command:    [0; 12],
-            length:     pay.len() as u32,
-            checksum:   [0; 4],
-            payload:    pay.as_bytes().to_vec()
+        let pay = match *self
+        {
+            NetworkMessage::Hello(ref bytes) |
+            NetworkMessage::HelloAck(ref bytes) |
+            NetworkMessage::HelloFin(ref bytes) => { bytes.clone() }
+            NetworkMessage::Ping(nonce) |
+            NetworkMessage::Pong(nonce) => { u64_to_vec(nonce) }
+            NetworkMessage::AddPeer(ref addr, timestamp) =>
+            {
+                let mut pay = u64_to_vec(timestamp as u64);
+                pay.extend_from_slice(addr.to_string().as_bytes());
+                pay
+            }
+            NetworkMessage::RemovePeer(ref addr) => { addr.to_string().into_bytes() }
+            NetworkMessage::GetAddr |
+            NetworkMessage::Mempool |
+            NetworkMessage::FilterClear => { vec![] }
+            NetworkMessage::Addr(ref addrs) => { addrs_to_vec(addrs) }
+            NetworkMessage::AddTransaction(ref tx) => { tx.to_vec() }
+            NetworkMessage::AddBlock(ref block) => { block.to_vec() }
+            NetworkMessage::GetBlock(ref hash, ref addr) =>
+            {
+                let mut pay = hash.to_vec();
+                pay.push(',' as u8);
+                pay.extend_from_slice(addr.to_string().as_bytes());
+                pay
+            }
+            NetworkMessage::GetHeaders(ref locator, ref stop_hash) =>
+            {
+                let mut pay = u32_to_vec(locator.len() as u32);
+                for hash in locator.iter()
+                {
+                    pay.extend_from_slice(hash);
+                }
+                pay.extend_from_slice(stop_hash);
+                pay
+            }
+            NetworkMessage::Headers(ref headers) =>
+            {
+                let mut pay = u32_to_vec(headers.len() as u32);
+                for header in headers.iter()
+                {
+                    pay.extend_from_slice(&header.header_to_vec());
+                }
+                pay
+            }
+            NetworkMessage::Inv(ref items) |
+            NetworkMessage::GetData(ref items) |
+            NetworkMessage::NotFound(ref items) => { items_to_vec(items) }
+            NetworkMessage::CompactBlock(ref compact) => { compact.to_vec() }
+            NetworkMessage::GetBlockTxn(ref hash, ref indexes) => { indexes_to_vec(hash, indexes) }
+            NetworkMessage::BlockTxn(ref hash, ref txs) => { block_txs_to_vec(hash, txs) }
+            NetworkMessage::Chat(ref chat) => { chat.to_vec() }
+            NetworkMessage::Query(id, ref query) => { query_to_vec(id, query) }
+            NetworkMessage::Response(id, ref response) => { response_to_vec(id, response) }
+            NetworkMessage::FilterLoad(ref filter) => { filter.to_vec() }
+            NetworkMessage::FilterAdd(ref element) => { element.clone() }
+            NetworkMessage::MerkleBlock(ref filtered) => { filtered.to_vec() }
+            NetworkMessage::GetCFilters(start, ref stop_hash) =>
+            {
+                let mut pay = u64_to_vec(start);
+                pay.extend_from_slice(stop_hash);
+                pay
+            }
+            NetworkMessage::CFilters(ref filters) => { cfilters_to_vec(filters) }
         };
-        msg.command.clone_from_slice(b"remp        ");
-        msg
+        Msg::new(self.command(), pay)
     }
 
-    pub fn new_list_peers(addr: &str, port: &str) -> Msg
+    pub fn from_msg(msg: &Msg) -> Result<NetworkMessage, DecodeError>
     {
-        let pay = addr.to_string() + ":" + port;
-        let mut msg = Msg {
-            magic:      0u32,
-            command:    [0; 12],
-            length:     pay.len() as u32,
-            checksum:   [0; 4],
-            payload:    pay.as_bytes().to_vec()
+        if msg.length as usize != msg.payload.len() { return Err(DecodeError::Malformed); }
+        let pay = &msg.payload[..];
+        let message = match &msg.command
+        {
+            b"hello       " => { Some(NetworkMessage::Hello(pay.to_vec())) }
+            b"helloack    " => { Some(NetworkMessage::HelloAck(pay.to_vec())) }
+            b"hellofin    " => { Some(NetworkMessage::HelloFin(pay.to_vec())) }
+            b"ping        " => { u64_from_slice(pay).map(NetworkMessage::Ping) }
+            b"pong        " => { u64_from_slice(pay).map(NetworkMessage::Pong) }
+            b"addp        " if pay.len() > NBYTES_U64 =>
+            {
+                socket_addr_from_slice(&pay[NBYTES_U64..]).map(|addr| NetworkMessage::AddPeer(addr, LittleEndian::read_i64(&pay[..NBYTES_U64])))
+            }
+            b"remp        " => { socket_addr_from_slice(pay).map(NetworkMessage::RemovePeer) }
+            b"getaddr     " if pay.is_empty() => { Some(NetworkMessage::GetAddr) }
+            b"addr        " => { addrs_from_slice(pay).map(NetworkMessage::Addr) }
+            b"addt        " => { Transaction::try_from_slice(pay).map(NetworkMessage::AddTransaction) }
+            b"addb        " => { Block::try_from_slice(pay).map(NetworkMessage::AddBlock) }
+            // THE HASH IS RAW BYTES AND MAY ITSELF CONTAIN ','
+            b"getb        " if pay.len() > 33 && pay[32] == ',' as u8 =>
+            {
+                socket_addr_from_slice(&pay[33..]).map(|addr| NetworkMessage::GetBlock(hash_from_slice(&pay[..32]), addr))
+            }
+            b"getheaders  " => { get_headers_from_slice(pay) }
+            b"headers     " => { headers_from_slice(pay).map(NetworkMessage::Headers) }
+            b"inv         " => { items_from_slice(pay).map(NetworkMessage::Inv) }
+            b"getdata     " => { items_from_slice(pay).map(NetworkMessage::GetData) }
+            b"notfound    " => { items_from_slice(pay).map(NetworkMessage::NotFound) }
+            b"mempool     " if pay.is_empty() => { Some(NetworkMessage::Mempool) }
+            b"cmpctblock  " => { CompactBlock::from_slice(pay).map(NetworkMessage::CompactBlock) }
+            b"getblocktxn " => { indexes_from_slice(pay).map(|(hash, indexes)| NetworkMessage::GetBlockTxn(hash, indexes)) }
+            b"blocktxn    " => { block_txs_from_slice(pay).map(|(hash, txs)| NetworkMessage::BlockTxn(hash, txs)) }
+            b"chat        " => { ChatMessage::from_slice(pay).map(NetworkMessage::Chat) }
+            b"blnc        " |
+            b"vldt        " |
+            b"geth        " |
+            b"getl        " |
+            b"lisb        " |
+            b"echo        " => { query_from_slice(&msg.command, pay) }
+            b"resp        " => { response_from_slice(pay) }
+            b"filterload  " => { BloomFilter::from_slice(pay).map(NetworkMessage::FilterLoad) }
+            b"filteradd   " if !pay.is_empty() && pay.len() <= MAX_FILTER_ELEMENT => { Some(NetworkMessage::FilterAdd(pay.to_vec())) }
+            b"filterclear " if pay.is_empty() => { Some(NetworkMessage::FilterClear) }
+            b"merkleblock " => { FilteredBlock::from_slice(pay).map(NetworkMessage::MerkleBlock) }
+            b"getcfilters " if pay.len() == NBYTES_U64 + 32 =>
+            {
+                Some(NetworkMessage::GetCFilters(LittleEndian::read_u64(&pay[..NBYTES_U64]), hash_from_slice(&pay[NBYTES_U64..])))
+            }
+            b"cfilters    " => { cfilters_from_slice(pay).map(NetworkMessage::CFilters) }
+            b"getaddr     " |
+            b"mempool     " |
+            b"filteradd   " |
+            b"filterclear " |
+            b"getcfilters " |
+            b"getb        " => { None }
+            _ => { return Err(DecodeError::UnknownCommand); }
         };
-        msg.command.clone_from_slice(b"lisp        ");
-        msg
+        message.ok_or(DecodeError::Malformed)
     }
+}
 
-    pub fn new_list_peers_response(peers: &Vec<Peer>) -> Msg
+// A QUERY GOES OUT UNDER ITS OWN COMMAND AND COMES BACK AS A resp OF THE SAME KIND
+fn query_kind(query: &Query) -> &'static [u8; 12]
+{
+    match *query
     {
-        let mut pay = "lisp        ".to_owned();
-        for peer in peers.iter()
-        {
-            pay.push_str(&peer.ip.clone());
-            pay.push_str(":");
-            pay.push_str(&peer.port.clone().to_string());
-            pay.push_str(",");
-        }
-        let mut msg = Msg {
-            magic:      0u32,
-            command:    [0; 12],
-            length:     pay.len() as u32,
-            checksum:   [0; 4],
-            payload:    pay.as_bytes().to_vec()
-        };
-        msg.command.clone_from_slice(b"resp        ");
-        msg
+        Query::Balance(_) => { b"blnc        " }
+        Query::Validate(_) => { b"vldt        " }
+        Query::Height => { b"geth        " }
+        Query::Latest => { b"getl        " }
+        Query::ListBlocks(_, _) => { b"lisb        " }
+        Query::Echo(_) => { b"echo        " }
     }
+}
 
-    pub fn new_balance_response(balance: i64) -> Msg
+fn response_kind(response: &Response) -> &'static [u8; 12]
+{
+    match *response
     {
-        let mut pay = "blnc        ".to_owned().as_bytes().to_vec();
-        let mut buf = [0; NBYTES_U64];
-        LittleEndian::write_i64(&mut buf, balance);
-        pay.extend_from_slice(&buf);
-        let mut msg = Msg {
-            magic:      0u32,
-            command:    [0; 12],
-            length:     NBYTES_U64 as u32,
-            checksum:   [0; 4],
-            payload:    pay
-        };
-        msg.command.clone_from_slice(b"resp        ");
-        msg
+        Response::Balance(_) => { b"blnc        " }
+        Response::Valid(_) => { b"vldt        " }
+        Response::Height(_) => { b"geth        " }
+        Response::Latest(_) => { b"getl        " }
+        Response::BlockHashes(_, _) => { b"lisb        " }
+        Response::Echo(_) => { b"echo        " }
     }
+}
 
-    pub fn new_validate_response(valid: bool) -> Msg
+// QUERIES CARRY THE REQUEST ID FIRST; A resp CARRIES ITS KIND AND THEN THE ID
+fn query_to_vec(id: RequestId, query: &Query) -> Vec<u8>
+{
+    let mut pay = u64_to_vec(id);
+    match *query
     {
-        let mut pay = "vldt        ".to_owned().as_bytes().to_vec();
-        let mut buf = [0; NBYTES_U32];
-        LittleEndian::write_i32(&mut buf, if valid { 1 } else { 0 });
-        pay.extend_from_slice(&buf);
-        let mut msg = Msg {
-            magic:      0u32,
-            command:    [0; 12],
-            length:     NBYTES_U32 as u32,
-            checksum:   [0; 4],
-            payload:    pay
-        };
-        msg.command.clone_from_slice(b"resp        ");
-        msg
+        Query::Balance(ref public_key) => { pay.extend_from_slice(public_key); }
+        Query::Validate(ref tx) => { pay.extend_from_slice(&tx.to_vec()); }
+        Query::Height |
+        Query::Latest => {}
+        Query::ListBlocks(start, count) =>
+        {
+            pay.extend_from_slice(&u64_to_vec(start));
+            pay.extend_from_slice(&u32_to_vec(count));
+        }
+        Query::Echo(ref bytes) => { pay.extend_from_slice(bytes); }
     }
+    pay
+}
 
-    pub fn new_add_transaction(bytes: Vec<u8>) -> Msg
+fn query_from_slice(kind: &[u8], bytes: &[u8]) -> Option<NetworkMessage>
+{
+    if bytes.len() < NBYTES_U64 { return None; }
+    let id = LittleEndian::read_u64(&bytes[..NBYTES_U64]);
+    let data = &bytes[NBYTES_U64..];
+    let query = match kind
     {
-        let pay = bytes;
-        let mut msg = Msg {
-            magic:      0u32,
-            command:    [0; 12],
-            length:     pay.len() as u32,
-            checksum:   [0; 4],
-            payload:    pay
-        };
-        msg.command.clone_from_slice(b"addt        ");
-        msg
+        b"blnc        " if data.len() == 32 => { Query::Balance(hash_from_slice(data)) }
+        b"vldt        " =>
+        {
+            match Transaction::try_from_slice(data)
+            {
+                Some(tx) => { Query::Validate(tx) }
+                None => { return None; }
+            }
+        }
+        b"geth        " if data.is_empty() => { Query::Height }
+        b"getl        " if data.is_empty() => { Query::Latest }
+        b"lisb        " if data.len() == NBYTES_U64 + NBYTES_U32 =>
+        {
+            Query::ListBlocks(LittleEndian::read_u64(&data[..NBYTES_U64]), LittleEndian::read_u32(&data[NBYTES_U64..]))
+        }
+        b"echo        " => { Query::Echo(data.to_vec()) }
+        _ => { return None; }
+    };
+    Some(NetworkMessage::Query(id, query))
+}
+
+fn response_to_vec(id: RequestId, response: &Response) -> Vec<u8>
+{
+    let mut pay = response_kind(response).to_vec();
+    pay.extend_from_slice(&u64_to_vec(id));
+    match *response
+    {
+        Response::Balance(balance) => { pay.extend_from_slice(&u64_to_vec(balance as u64)); }
+        Response::Valid(valid) => { pay.extend_from_slice(&u32_to_vec(if valid { 1 } else { 0 })); }
+        Response::Height(height) => { pay.extend_from_slice(&u64_to_vec(height as u64)); }
+        Response::Latest(None) => {}
+        Response::Latest(Some(ref header)) => { pay.extend_from_slice(&header.header_to_vec()); }
+        Response::BlockHashes(start, ref hashes) =>
+        {
+            pay.extend_from_slice(&u64_to_vec(start));
+            for hash in hashes.iter()
+            {
+                pay.extend_from_slice(hash);
+            }
+        }
+        Response::Echo(ref bytes) => { pay.extend_from_slice(bytes); }
     }
+    pay
+}
 
-    pub fn new_add_block(bytes: Vec<u8>) -> Msg
+fn response_from_slice(bytes: &[u8]) -> Option<NetworkMessage>
+{
+    if bytes.len() < 12 + NBYTES_U64 { return None; }
+    let id = LittleEndian::read_u64(&bytes[12..12+NBYTES_U64]);
+    let data = &bytes[12+NBYTES_U64..];
+    let response = match &bytes[..12]
     {
-        let pay = bytes;
-        let mut msg = Msg {
-            magic:      0u32,
-            command:    [0; 12],
-            length:     pay.len() as u32,
-            checksum:   [0; 4],
-            payload:    pay
-        };
-        msg.command.clone_from_slice(b"addb        ");
-        msg
+        b"blnc        " if data.len() == NBYTES_U64 => { Response::Balance(LittleEndian::read_i64(data)) }
+        b"vldt        " if data.len() == NBYTES_U32 => { Response::Valid(LittleEndian::read_i32(data) != 0) }
+        b"geth        " if data.len() == NBYTES_U64 => { Response::Height(LittleEndian::read_i64(data)) }
+        b"getl        " if data.is_empty() => { Response::Latest(None) }
+        b"getl        " if data.len() == HEADER_LEN => { Response::Latest(Some(Block::header_from_slice(data))) }
+        b"lisb        " if data.len() >= NBYTES_U64 && (data.len() - NBYTES_U64) % 32 == 0 =>
+        {
+            Response::BlockHashes(LittleEndian::read_u64(&data[..NBYTES_U64]), data[NBYTES_U64..].chunks(32).map(hash_from_slice).collect())
+        }
+        b"echo        " => { Response::Echo(data.to_vec()) }
+        _ => { return None; }
+    };
+    Some(NetworkMessage::Response(id, response))
+}
+
+// EACH FILTER FOLLOWS ITS BLOCK HASH AND LENGTH
+fn cfilters_to_vec(filters: &[([u8; 32], GcsFilter)]) -> Vec<u8>
+{
+    let mut pay = u32_to_vec(filters.len() as u32);
+    for &(ref hash, ref filter) in filters.iter()
+    {
+        let filter_vec = filter.to_vec();
+        pay.extend_from_slice(hash);
+        pay.extend_from_slice(&u32_to_vec(filter_vec.len() as u32));
+        pay.extend_from_slice(&filter_vec);
     }
+    pay
+}
 
-    pub fn new_get_block(bytes: Vec<u8>) -> Msg
+fn cfilters_from_slice(bytes: &[u8]) -> Option<Vec<([u8; 32], GcsFilter)>>
+{
+    if bytes.len() < NBYTES_U32 { return None; }
+    let count = LittleEndian::read_u32(&bytes[..NBYTES_U32]) as usize;
+    if count > MAX_CFILTERS { return None; }
+    let mut idx = NBYTES_U32;
+    let mut filters = vec![];
+    for _ in 0..count
     {
-        let pay = bytes;
-        let mut msg = Msg {
-            magic:      0u32,
-            command:    [0; 12],
-            length:     pay.len() as u32,
-            checksum:   [0; 4],
-            payload:    pay
-        };
-        msg.command.clone_from_slice(b"getb        ");
-        msg
+        if bytes.len() < idx + 32 + NBYTES_U32 { return None; }
+        let hash = hash_from_slice(&bytes[idx..idx+32]);
+        let len = LittleEndian::read_u32(&bytes[idx+32..idx+32+NBYTES_U32]) as usize;
+        idx += 32 + NBYTES_U32;
+        if bytes.len() < idx + len { return None; }
+        match GcsFilter::from_slice(&bytes[idx..idx+len])
+        {
+            Some(filter) => { filters.push((hash, filter)); }
+            None => { return None; }
+        }
+        idx += len;
     }
+    if idx != bytes.len() { return None; }
+    Some(filters)
+}
+
+fn get_headers_from_slice(bytes: &[u8]) -> Option<NetworkMessage>
+{
+    if bytes.len() < NBYTES_U32 { return None; }
+    let count = LittleEndian::read_u32(&bytes[..NBYTES_U32]) as usize;
+    if count > MAX_HEADERS || bytes.len() != NBYTES_U32 + (count + 1) * 32 { return None; }
+    let hashes: Vec<[u8; 32]> = bytes[NBYTES_U32..].chunks(32).map(hash_from_slice).collect();
+    Some(NetworkMessage::GetHeaders(hashes[..count].to_vec(), hashes[count]))
+}
+
+fn headers_from_slice(bytes: &[u8]) -> Option<Vec<Block>>
+{
+    if bytes.len() < NBYTES_U32 { return None; }
+    let count = LittleEndian::read_u32(&bytes[..NBYTES_U32]) as usize;
+    if count > MAX_HEADERS || bytes.len() != NBYTES_U32 + count * HEADER_LEN { return None; }
+    Some(bytes[NBYTES_U32..].chunks(HEADER_LEN).map(Block::header_from_slice).collect())
+}
+
+fn socket_addr_from_slice(bytes: &[u8]) -> Option<SocketAddr>
+{
+    str::from_utf8(bytes).ok().and_then(|addr| addr.parse::<SocketAddr>().ok())
+}
+
+fn hash_from_slice(bytes: &[u8]) -> [u8; 32]
+{
+    let mut hash = [0; 32];
+    hash.clone_from_slice(bytes);
+    hash
+}
+
+fn u64_from_slice(bytes: &[u8]) -> Option<u64>
+{
+    if bytes.len() == NBYTES_U64 { Some(LittleEndian::read_u64(bytes)) } else { None }
+}
+
+fn u64_to_vec(n: u64) -> Vec<u8>
+{
+    let mut buf = [0; NBYTES_U64];
+    LittleEndian::write_u64(&mut buf, n);
+    buf.to_vec()
+}
+
+fn u32_to_vec(n: u32) -> Vec<u8>
+{
+    let mut buf = [0; NBYTES_U32];
+    LittleEndian::write_u32(&mut buf, n);
+    buf.to_vec()
 }
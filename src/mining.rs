@@ -7,20 +7,20 @@ use self::rand::{Rng};
 extern crate num;
 use self::num::bigint::{BigUint, ToBigUint};
 
-extern crate chrono;
-use self::chrono::*;
-
 use transaction::*;
 use block::*;
 use util::*;
 use database;
+use timedata::*;
 
-const TARGET_FREQ: i64 = 10;
+// SECONDS BETWEEN BLOCKS THAT DIFFICULTY RETARGETS TOWARDS
+pub const TARGET_FREQ: i64 = 10;
 
 pub fn start_mining(
     transaction_rcv_from_network: Receiver<Transaction>,
     block_rcv_from_network: Receiver<Block>,
-    block_snd_to_network: Sender<Block>)
+    block_snd_to_network: Sender<Block>,
+    network_time: NetworkTime)
 {
     let db = database::conn();
 
@@ -77,8 +77,16 @@ pub fn start_mining(
 
             println!("newtarget: {}", to_hex_string(&target));
             println!("newtarget: {}", BigUint::from_bytes_be(&target));
-
-            parent_hash.clone_from_slice(&blockchain.last().unwrap().block_hash);
+        }
+        // ONLY THE FIRST BLOCK STARTS A CHAIN; PEERS TAKE NO OTHER GENESIS
+        if let Some(parent) = blockchain.last()
+        {
+            parent_hash.clone_from_slice(&parent.block_hash);
+            let max = max_target(&parent.target);
+            if target > max
+            {
+                target = max;
+            }
         }
 
         let mut rng = rand::thread_rng();
@@ -90,7 +98,7 @@ pub fn start_mining(
             &target,
             nonce);
 
-        'inner: while !mine(&mut next_block)
+        'inner: while !mine(&mut next_block, &network_time)
         {
             match transaction_rcv_from_network.try_recv()
             {
@@ -119,9 +127,9 @@ pub fn start_mining(
     }
 }
 
-pub fn mine(block: &mut Block) -> bool
+pub fn mine(block: &mut Block, network_time: &NetworkTime) -> bool
 {
-    block.timestamp = UTC::now().timestamp();
+    block.timestamp = network_time.now();
     if block.nonce == i64::max_value() { block.nonce = 0; } else { block.nonce += 1; }
     block.update_hash();
     block.block_hash < block.target
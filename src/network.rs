@@ -1,49 +1,112 @@
 use database;
+use database::{Store};
 use transaction::*;
 use peer::*;
 use message::*;
 use block::*;
-use wallet;
+use bloom::*;
+use gcs::*;
+use sync::*;
+use inventory::*;
+use config::*;
+use ban::*;
+use bandwidth::*;
+use eviction::*;
+use addrman::*;
+use persistent::*;
+use discovery::*;
+use handshake::*;
+use compact::*;
+use chat::*;
+use query::*;
+use handler::*;
+use dial::*;
+use timedata::*;
+use stale::*;
+use mining::{TARGET_FREQ};
 
 extern crate mio;
 extern crate chrono;
-extern crate postgres;
 extern crate rand;
 
-extern crate byteorder;
-use self::byteorder::{ByteOrder, LittleEndian};
+extern crate net2;
 
-// use util::{NBYTES_U64, NBYTES_U32};
+use util::{canonical_ip, format_addr, parse_host_addr, to_hex_string, NBYTES_U32};
 
 use self::mio::*;
 use self::mio::channel::{Sender, Receiver};
-use self::mio::tcp::{TcpListener, TcpStream};
+use self::mio::tcp::{TcpListener};
+use self::mio::timer::{Timer};
+
+use self::net2::{TcpBuilder};
 
 use self::chrono::*;
 
-use self::postgres::{Connection};
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::time;
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
 
-use std::collections::{HashMap};
-// use std::time;
-use std::io::{Write};
-use std::net;
+use self::rand::{Rng};
 
 // use std::thread;
-// use self::rand::{thread_rng, Rng};
 
 const QUIT_TOKEN: Token = Token(0);
 const MINED_BLOCK_TOKEN: Token = Token(1);
-const SERVER_TOKEN: Token = Token(2);
-const CLIENT_TOKEN_COUNTER: usize = 3;
-const LOCALHOST: &'static str = "127.0.0.1";
-const SERVER_DEFAULT_PORT: &'static str = "9001";
+const TIMER_TOKEN: Token = Token(2);
+const COMMAND_TOKEN: Token = Token(3);
+const DISCOVERY_TOKEN: Token = Token(4);
+const FIRST_LISTENER_TOKEN: usize = 5;
+const MAX_LISTENERS: usize = 8;
+const FIRST_CLIENT_TOKEN: usize = FIRST_LISTENER_TOKEN + MAX_LISTENERS;
+// FAR ABOVE ANY CLIENT TOKEN, WHICH max_inbound KEEPS LOW
+const FIRST_DIAL_TOKEN: usize = 1 << 24;
+const SYNC_TICK_MS: u64 = 2000;
+const KEEPALIVE_TICK_MS: u64 = 5000;
+const MAX_LISB_HASHES: u32 = 2000;
+const MAX_ECHO_LEN: usize = 1024;
+const MAX_CONNECT_ATTEMPTS: usize = 16;
+const ADVERTISE_TICK_MS: u64 = 30 * 60 * 1000;
+const CONNECT_TICK_MS: u64 = 10000;
+const DISCOVERY_TICK_MS: u64 = 30000;
+const STALE_TIP_TICK_MS: u64 = 10000;
+const MAX_RELAY_ADDRS: usize = 10;
+const ADDR_RELAY_AGE: i64 = 10 * 60;
+const ADDR_RELAY_PEERS: usize = 2;
+// HOW MANY BLOCK INTERVALS BACK FROM NOW A BLOCK STILL COUNTS AS NEAR THE TIP
+const RECENT_BLOCKS: i64 = 144;
+
+enum TimerEvent
+{
+    SyncTick,
+    KeepaliveTick,
+    AdvertiseTick,
+    ConnectTick,
+    DiscoveryTick,
+    StaleTipTick
+}
+
+// REQUESTS FROM THE COMMAND LINE
+pub enum NetworkCommand
+{
+    AddNode(String, i32),
+    Query(Query, Callback),
+    Chat(String),
+    NetStats,
+    Status
+}
 
 pub fn start_server(
-    port: Option<String>,
+    config: Config,
+    mut handlers: HandlerRegistry,
     quit_rcv: Receiver<()>,
+    command_rcv: Receiver<NetworkCommand>,
     transaction_snd_to_mine: Sender<Transaction>,
     block_snd_to_mine: Sender<Block>,
-    block_rcv_from_mine: Receiver<Block>)
+    block_rcv_from_mine: Receiver<Block>,
+    network_time: NetworkTime)
 {
     let db = database::conn();
 
@@ -61,39 +124,92 @@ pub fn start_server(
         Ready::readable(),
         PollOpt::level()).expect("Failed to register block receiver channel");
 
-    let port = match port
+    poll.register(
+        &command_rcv,
+        COMMAND_TOKEN,
+        Ready::readable(),
+        PollOpt::level()).expect("Failed to register command receiver channel");
+
+    let mut servers = vec![];
+    for addr in config.listen_addrs().into_iter().take(MAX_LISTENERS)
     {
-        Some(port) => {
-            port
-        }
-        None => {
-            SERVER_DEFAULT_PORT.to_string()
+        match bind_listener(&addr)
+        {
+            Ok(server) =>
+            {
+                poll.register(
+                    &server,
+                    Token(FIRST_LISTENER_TOKEN + servers.len()),
+                    Ready::readable(),
+                    PollOpt::level()).expect("Failed to register server socket");
+                println!("Listening on {}", addr);
+                servers.push(server);
+            }
+            Err(e) => { println!("Failed to listen on {}: {}", addr, e); }
         }
-    };
-    let addr = (LOCALHOST.to_string() + ":" + &port).parse().expect("Failed to parse server addr");
-    let server = TcpListener::bind(&addr).unwrap();
+    }
+    if servers.is_empty()
+    {
+        panic!("Failed to bind any listen address");
+    }
+    let advertised = config.advertised_addr();
+    println!("Advertising {}", advertised);
+
+    let discovery = if config.lan_discovery { start_discovery(&config, &poll) } else { None };
+
+    let mut timer: Timer<TimerEvent> = Timer::default();
 
     poll.register(
-        &server,
-        SERVER_TOKEN,
+        &timer,
+        TIMER_TOKEN,
         Ready::readable(),
-        PollOpt::level()).expect("Failed to register server socket");
+        PollOpt::edge()).expect("Failed to register timer");
+
+    let _ = timer.set_timeout(time::Duration::from_millis(SYNC_TICK_MS), TimerEvent::SyncTick);
+    let _ = timer.set_timeout(time::Duration::from_millis(KEEPALIVE_TICK_MS), TimerEvent::KeepaliveTick);
+    let _ = timer.set_timeout(time::Duration::from_millis(ADVERTISE_TICK_MS), TimerEvent::AdvertiseTick);
+    let _ = timer.set_timeout(time::Duration::from_millis(CONNECT_TICK_MS), TimerEvent::ConnectTick);
+    let _ = timer.set_timeout(time::Duration::from_millis(STALE_TIP_TICK_MS), TimerEvent::StaleTipTick);
+    if let Some(ref discovery) = discovery
+    {
+        discovery.announce();
+        let _ = timer.set_timeout(time::Duration::from_millis(DISCOVERY_TICK_MS), TimerEvent::DiscoveryTick);
+    }
 
-    let mut token_counter: usize = CLIENT_TOKEN_COUNTER;
-    let mut clients: HashMap<Token, TcpStream> = HashMap::new();
+    let mut clients: HashMap<Token, Client> = HashMap::new();
     let mut events = Events::with_capacity(1024);
 
-    println!("Listening on {}", addr);
-
-    let peer_history = database::peers(&db);
+    let mut addrman = AddrMan::load(&db);
+    let mut bans = BanList::load(&db);
 
-    let mut peers = bootstrap(LOCALHOST, &port, peer_history);
-
-    for peer in &peers
+    let mut persistent = PersistentPeers::new();
+    for addr in config.persistent.iter()
     {
-        let _ = database::upsert_peer(&peer, &db);
+        match parse_host_addr(addr)
+        {
+            Some((ip, port)) => { persistent.add(&ip, port); }
+            None => { println!("Invalid --addnode address {}", addr); }
+        }
     }
 
+    let identity = Identity::load_or_create();
+    println!("Node identity {}", to_hex_string(&identity.public_key));
+
+    let bandwidth = Bandwidth::new(config.upload_budget, config.upload_window);
+
+    let mut peers = vec![];
+    let mut dials: HashMap<Token, Dial> = HashMap::new();
+    let mut dial_requests: Vec<DialRequest> = vec![];
+    bootstrap(&poll, &mut dials, &peers, &mut persistent, &mut addrman, &bans, &config, &identity);
+    addrman.flush(&db);
+
+    let mut sync = HeaderSync::new();
+    let mut inv_requests = InvRequests::new();
+    let mut partial_blocks = PartialBlocks::new();
+    let mut chat_log = ChatLog::new();
+    let mut pending_requests = PendingRequests::new();
+    let mut stale_tip = StaleTipMonitor::new(TARGET_FREQ);
+
     'event_loop: loop
     {
         poll.poll(&mut events, None).unwrap();
@@ -106,611 +222,2183 @@ pub fn start_server(
                 QUIT_TOKEN => {
                     println!("handle quit");
                     handle_quit(
-                        LOCALHOST,
-                        &port,
+                        &advertised,
                         peers);
                     break 'event_loop;
                 }
 
+                COMMAND_TOKEN => {
+                    while let Ok(command) = command_rcv.try_recv()
+                    {
+                        match command
+                        {
+                            NetworkCommand::AddNode(ip, port) => {
+                                persistent.add(&ip, port);
+                                connect_persistent(&poll, &mut dials, &peers, &mut persistent, &mut addrman, &bans, &config, &identity);
+                            }
+                            NetworkCommand::Query(query, callback) => {
+                                send_query(query, callback, &mut peers, &mut pending_requests);
+                            }
+                            NetworkCommand::Chat(ref text) if text.len() > MAX_CHAT_LEN => {
+                                println!("Chat messages are limited to {} bytes", MAX_CHAT_LEN);
+                            }
+                            NetworkCommand::Chat(text) => {
+                                let chat = ChatMessage::new(&text, &identity);
+                                chat_log.insert(&chat);
+                                relay_chat(&chat, None, &mut peers);
+                            }
+                            NetworkCommand::NetStats => {
+                                print_netstats(&bandwidth, &network_time, &peers, &clients);
+                            }
+                            NetworkCommand::Status => {
+                                print_status(&stale_tip, &sync, &network_time, &peers, &db);
+                            }
+                        }
+                    }
+                }
+
+                DISCOVERY_TOKEN => {
+                    if let Some(ref discovery) = discovery
+                    {
+                        for (ip, port) in discovery.receive()
+                        {
+                            discovered(ip, port, &poll, &mut dials, &peers, &mut addrman, &bans, &config, &identity);
+                        }
+                    }
+                }
+
                 MINED_BLOCK_TOKEN => {
                     println!("block received from mine");
                     let block = block_rcv_from_mine.try_recv().unwrap();
-                    publish_block(block, &peers);
+                    publish_block(block, &mut peers, &mut clients);
+                }
+
+                TIMER_TOKEN => {
+                    while let Some(timer_event) = timer.poll()
+                    {
+                        match timer_event
+                        {
+                            TimerEvent::SyncTick => {
+                                sync.tick(&mut peers, &db);
+                                inv_requests.expire();
+                                partial_blocks.expire();
+                                chat_log.expire();
+                                pending_requests.expire(&peers);
+                                addrman.flush(&db);
+                                let _ = timer.set_timeout(time::Duration::from_millis(SYNC_TICK_MS), TimerEvent::SyncTick);
+                            }
+                            TimerEvent::KeepaliveTick => {
+                                keepalive(&config, &mut peers, &mut clients);
+                                let _ = timer.set_timeout(time::Duration::from_millis(KEEPALIVE_TICK_MS), TimerEvent::KeepaliveTick);
+                            }
+                            TimerEvent::AdvertiseTick => {
+                                advertise(&config, &mut peers);
+                                let _ = timer.set_timeout(time::Duration::from_millis(ADVERTISE_TICK_MS), TimerEvent::AdvertiseTick);
+                            }
+                            TimerEvent::ConnectTick => {
+                                expire_dials(&poll, &mut dials, &mut persistent);
+                                connect_persistent(&poll, &mut dials, &peers, &mut persistent, &mut addrman, &bans, &config, &identity);
+                                fill_outbound(&poll, &mut dials, &peers, &mut addrman, &bans, &config, &identity);
+                                let _ = timer.set_timeout(time::Duration::from_millis(CONNECT_TICK_MS), TimerEvent::ConnectTick);
+                            }
+                            TimerEvent::DiscoveryTick => {
+                                if let Some(ref discovery) = discovery
+                                {
+                                    discovery.announce();
+                                }
+                                let _ = timer.set_timeout(time::Duration::from_millis(DISCOVERY_TICK_MS), TimerEvent::DiscoveryTick);
+                            }
+                            TimerEvent::StaleTipTick => {
+                                // THE NEWEST BLOCK, NOT THE MAIN CHAIN'S TIP: ANY NEW BLOCK
+                                // SHOWS WE CAN STILL HEAR THE NETWORK, AND IT'S ONE ROW
+                                // RATHER THAN THE WHOLE CHAIN EVERY TICK
+                                let tip_timestamp = db.latest_block().map_or(0, |block| block.timestamp);
+                                if stale_tip.check(tip_timestamp, sync.is_syncing(), network_time.now())
+                                {
+                                    extra_outbound(&poll, &mut dials, &peers, &mut addrman, &bans, &config, &identity);
+                                }
+                                let _ = timer.set_timeout(time::Duration::from_millis(STALE_TIP_TICK_MS), TimerEvent::StaleTipTick);
+                            }
+                        }
+                    }
                 }
 
-                SERVER_TOKEN => {
+                token if token.0 >= FIRST_LISTENER_TOKEN && token.0 < FIRST_LISTENER_TOKEN + servers.len() => {
                     println!("handle connection");
                     handle_connection(
-                        &server,
-                        &mut token_counter,
+                        &servers[token.0 - FIRST_LISTENER_TOKEN],
                         &poll,
-                        &mut clients);
+                        &mut clients,
+                        &mut peers,
+                        &bans,
+                        &config);
                 }
 
-                token => {
-                    println!("handle message");
-                    handle_message(
-                        &server,
+                token if token.0 >= FIRST_DIAL_TOKEN => {
+                    dial_ready(
                         token,
-                        &mut clients,
+                        &poll,
+                        &mut dials,
                         &mut peers,
-                        &db,
-                        &transaction_snd_to_mine,
-                        &block_snd_to_mine,
-                    );
+                        &mut clients,
+                        &mut persistent,
+                        &mut addrman,
+                        &config,
+                        &bandwidth,
+                        &mut sync,
+                        &db);
                 }
-            }
-        }
-    }
-}
-
-pub fn bootstrap(
-    server_addr: &str,
-    server_port: &str,
-    peer_history: Vec<Peer>) -> Vec<Peer>
-{
-    let mut peers = vec![];
-    for peer in peer_history
-    {
-        if peer.port.to_string() != server_port
-        {
-            let addr = peer.ip.clone() + ":" + &peer.port.to_string();
-            println!("Connecting to peer at {}", addr);
 
-            match net::TcpStream::connect((peer.ip.as_str(), peer.port as u16))
-            {
-                Ok(mut stream) => {
-                    println!("Connected to {:?}", stream.peer_addr().unwrap());
-
-                    let mut addp = Msg::new_add_peer(server_addr, server_port).to_vec();
-                    let mut lisp = Msg::new_list_peers(server_addr, server_port).to_vec();
-
-                    let tx0 = Transaction::new(
-                        vec![
-                            TxInput {
-                                src_hash:   [1; 32],
-                                src_idx:    2,
-                                signature:  [3; 64]
-                            }
-                        ],
-                        vec![
-                            TxOutput {
-                                amount: 4,
-                                address: [5; 32]
-                            }
-                        ],
-                        6
-                    );
-                    let mut addt = Msg::new_add_transaction(tx0.to_vec()).to_vec();
-
-                    addp.append(&mut lisp);
-                    addp.append(&mut addt);
-
-                    match stream.write(&addp)
+                token => {
+                    println!("handle message");
+                    let mut ctx = NodeContext {
+                        token: token,
+                        clients: &mut clients,
+                        peers: &mut peers,
+                        addrman: &mut addrman,
+                        bans: &mut bans,
+                        sync: &mut sync,
+                        inv_requests: &mut inv_requests,
+                        partial_blocks: &mut partial_blocks,
+                        chat_log: &mut chat_log,
+                        pending_requests: &mut pending_requests,
+                        config: &config,
+                        identity: &identity,
+                        db: &db,
+                        transaction_snd_to_mine: &transaction_snd_to_mine,
+                        block_snd_to_mine: &block_snd_to_mine,
+                        bandwidth: &bandwidth,
+                        network_time: &network_time,
+                        dial_requests: &mut dial_requests
+                    };
+                    handle_message(&mut ctx, &mut handlers);
+
+                    for request in dial_requests.drain(..)
                     {
-                        Ok(nbytes) => {
-                            println!("Bytes written: {}", nbytes);
-                        }
-                        Err(e) => {
-                            println!("Error writing to stream: {}", e);
-                        }
+                        start_dial(request, &poll, &mut dials, &peers, &mut addrman, &bans, &config, &identity);
                     }
-                    let _ = stream.flush();
-
-                    peers.push(
-                        Peer::new(
-                            peer.ip,
-                            peer.port,
-                            UTC::now().timestamp(),
-                            Some(stream)));
-                }
-                Err(e) => {
-                    println!("Error connecting to host: {}", e);
                 }
             }
         }
     }
-    peers
 }
 
-fn handle_quit(
-    server_ip: &str,
-    server_port: &str,
-    peers: Vec<Peer>)
+// V6 SOCKETS ARE MADE V6-ONLY SO THAT 0.0.0.0 AND [::] CAN SHARE A PORT
+fn bind_listener(addr: &SocketAddr) -> io::Result<TcpListener>
 {
-    for peer in peers
+    let builder = match *addr
     {
-        let remp = Msg::new_remove_peer(server_ip, server_port).to_vec();
-
-        match peer.socket.unwrap().write(&remp)
+        SocketAddr::V4(..) => { TcpBuilder::new_v4()? }
+        SocketAddr::V6(..) =>
         {
-            Ok(nbytes) => {
-                println!("Bytes written: {}", nbytes);
-            }
-            Err(e) => {
-                println!("Error writing to stream: {}", e);
-            }
+            let builder = TcpBuilder::new_v6()?;
+            builder.only_v6(true)?;
+            builder
         }
-    }
+    };
+    builder.reuse_address(true)?;
+    builder.bind(addr)?;
+    let listener = builder.listen(1024)?;
+    TcpListener::from_listener(listener, addr)
 }
 
-fn handle_connection(
-    server: &TcpListener,
-    token_counter: &mut usize,
-    poll: &Poll,
-    clients: &mut HashMap<Token, TcpStream>)
+fn start_discovery(
+    config: &Config,
+    poll: &Poll) -> Option<LanDiscovery>
 {
-    let socket = match server.accept()
+    let group = match config.discovery_group.parse::<SocketAddr>()
     {
-        Err(e) => {
-            println!("Error accepting connection: {}", e);
-            return;
+        Ok(group) => { group }
+        Err(_) =>
+        {
+            println!("Invalid discovery group {}", config.discovery_group);
+            return None;
         }
-        Ok((socket, _)) => { socket }
     };
-
-    println!("Accepted connection from {:?}", socket.peer_addr().unwrap());
-
-    *token_counter += 1;
-    let token = Token(*token_counter);
-
-    poll.register(
-        &socket,
-        token,
-        Ready::readable(),
-        PollOpt::edge()).expect("Failed to register client socket");
-
-    clients.insert(token, socket);
-}
-
-fn handle_message(
-    server: &TcpListener,
-    token: Token,
-    clients: &mut HashMap<Token, TcpStream>,
-    peers: &mut Vec<Peer>,
-    db: &Connection,
-    transaction_snd_to_mine: &Sender<Transaction>,
-    block_snd_to_mine: &Sender<Block>)
-{
-    // let mut rng = rand::thread_rng();
-    // let stutter = time::Duration::from_millis(rng.gen_range::<u64>(0, 5000));
-    // thread::sleep(stutter);
-
-    match clients[&token].try_clone()
+    match LanDiscovery::new(group, config.advertised_addr().port())
     {
-        Ok(mut stream) =>
+        Ok(discovery) =>
         {
-            loop
-            {
-                match Msg::from_stream(&mut stream)
-                {
-                    Ok(msg) =>
-                    {
-                        match &msg.command
-                        {
-                            b"addp        " => {
-                                rcv_addp(
-                                    &msg.payload,
-                                    server,
-                                    peers,
-                                    db);
-                            }
-                            b"remp        " => {
-                                rcv_remp(
-                                    &msg.payload,
-                                    peers);
-                            }
-                            b"lisp        " => {
-                                rcv_lisp(
-                                    &msg.payload,
-                                    peers);
-                            }
-                            b"blnc        " => {
-                                print!(" balance\n");
-                                rcv_blnc(
-                                    &msg.payload,
-                                    peers);
-                            }
-                            b"addt        " => {
-                                rcv_addt(
-                                    &msg.payload,
-                                    db,
-                                    transaction_snd_to_mine);
-                            }
-                            b"vdlt        " => {
-                                print!(" validate transaction\n");
-                                rcv_vldt(
-                                    &msg.payload,
-                                    peers);
-                            }
-                            b"pent        " => {
-                                print!(" pending transactions\n");
-                            }
-                            b"lisb        " => {
-                                print!(" get blocks\n");
-                            }
-                            b"getb        " => {
-                                rcv_getb(
-                                    &msg.payload,
-                                    server,
-                                    peers,
-                                    db);
-                            }
-                            b"addb        " => {
-                                rcv_addb(
-                                    &msg.payload,
-                                    db,
-                                    block_snd_to_mine)
-                            }
-                            b"geth        " => {
-                                print!(" get block height\n");
-                            }
-                            b"getl        " => {
-                                print!(" get latest block\n");
-                            }
-                            b"chat        " => {
-                                print!(" chat\n");
-                            }
-                            b"echo        " => {
-                                print!(" echo\n");
-                            }
-                            b"resp        " => {
-                                match &msg.payload[..12]
-                                {
-                                    b"lisp        " =>
-                                    {
-                                        rcv_resp_lisp(
-                                            &msg.payload[12..],
-                                            server,
-                                            peers,
-                                            db);
-                                    }
-                                    b"blnc        " =>
-                                    {
-                                        rcv_resp_blnc(
-                                            &msg.payload[12..]);
-                                    }
-                                    b"vldt        " =>
-                                    {
-                                        rcv_resp_vldt(
-                                            &msg.payload[12..]);
-                                    }
-                                    _ => {}
-                                }
-                            }
-                            _ => {
-                                print!("Unknown cmd: {}\n", String::from_utf8(msg.command.to_vec()).unwrap());
-                            }
-                        }
-                    }
-                    Err(e) =>
-                    {
-                        println!("Error reading stream: {}", e);
-                        break;
-                    }
-                }
-            }
+            poll.register(
+                &discovery.socket,
+                DISCOVERY_TOKEN,
+                Ready::readable(),
+                PollOpt::level()).expect("Failed to register discovery socket");
+            println!("LAN discovery on {}", group);
+            Some(discovery)
+        }
+        Err(e) =>
+        {
+            println!("Failed to start LAN discovery on {}: {}", group, e);
+            None
         }
-        Err(_) => { }
     }
 }
 
-fn rcv_addp(
-    payload: &[u8],
-    server: &TcpListener,
-    peers: &mut Vec<Peer>,
-    db: &Connection)
+// A NODE ON THE LOCAL NETWORK ANNOUNCED ITSELF
+fn discovered(
+    ip: String,
+    port: i32,
+    poll: &Poll,
+    dials: &mut HashMap<Token, Dial>,
+    peers: &[Peer],
+    addrman: &mut AddrMan,
+    bans: &BanList,
+    config: &Config,
+    identity: &Identity)
 {
-    println!("rcv_addp {}", String::from_utf8(payload.to_vec()).unwrap());
-
-    let cmpts: Vec<&[u8]> = payload.split({|x| *x == ':' as u8}).collect();
-    if cmpts.len() == 2
+    if config.is_self(&ip, port) || peers.iter().any(|p| p.ip == ip && p.port == port)
     {
-        let ip = String::from_utf8(cmpts[0].to_vec()).unwrap();
-        let port = String::from_utf8(cmpts[1].to_vec()).unwrap().parse::<i32>().unwrap();
-
-        add_peer(ip, port, server, peers, db);
+        return;
+    }
+    println!("Discovered {} on the local network", format_addr(&ip, port));
+    let addr = NetAddr {
+        ip: ip.clone(),
+        port: port,
+        services: NODE_NETWORK,
+        timestamp: UTC::now().timestamp()
+    };
+    addrman.add(&addr, &ip);
+    if outbound_count(peers, dials) < config.max_outbound
+    {
+        let request = DialRequest { ip: ip, port: port, purpose: DialPurpose::Outbound };
+        start_dial(request, poll, dials, peers, addrman, bans, config, identity);
     }
 }
 
-fn add_peer(
-    ip: String,
-    port: i32,
-    server: &TcpListener,
-    peers: &mut Vec<Peer>,
-    db: &Connection)
+// THE FIRST OUTBOUND CONNECTIONS. THESE ARE ONLY DIALS; THEY BECOME PEERS IN
+// finish_dial, FROM THE EVENT LOOP.
+pub fn bootstrap(
+    poll: &Poll,
+    dials: &mut HashMap<Token, Dial>,
+    peers: &[Peer],
+    persistent: &mut PersistentPeers,
+    addrman: &mut AddrMan,
+    bans: &BanList,
+    config: &Config,
+    identity: &Identity)
 {
-    let timestamp = UTC::now().timestamp();
+    connect_persistent(poll, dials, peers, persistent, addrman, bans, config, identity);
+    fill_outbound(poll, dials, peers, addrman, bans, config, identity);
+}
 
-    if  ip != server.local_addr().unwrap().ip().to_string() ||
-        port != server.local_addr().unwrap().port() as i32
+fn connect_persistent(
+    poll: &Poll,
+    dials: &mut HashMap<Token, Dial>,
+    peers: &[Peer],
+    persistent: &mut PersistentPeers,
+    addrman: &mut AddrMan,
+    bans: &BanList,
+    config: &Config,
+    identity: &Identity)
+{
+    let now = UTC::now().timestamp();
+    for (ip, port) in persistent.connections()
     {
-        match net::TcpStream::connect((ip.as_str(), port as u16))
+        if !peers.iter().any(|p| p.ip == ip && p.port == port && p.is_connected())
         {
-            Ok(stream) =>
-            {
-                let peer = Peer::new(ip, port, timestamp, Some(stream));
-                if database::upsert_peer(&peer, db).is_ok()
-                {
-                    peers.push(peer);
-                }
-            }
-            Err(_) => {}
+            persistent.disconnected(&ip, port, now);
         }
     }
-}
 
-fn rcv_remp(
-    payload: &[u8],
-    peers: &mut Vec<Peer>)
-{
-    println!("rcv_remp {}", String::from_utf8(payload.to_vec()).unwrap());
-
-    let cmpts: Vec<&[u8]> = payload.split({|x| *x == ':' as u8}).collect();
-    if cmpts.len() == 2
+    for (ip, port) in persistent.due(now)
     {
-        let ip = String::from_utf8(cmpts[0].to_vec()).unwrap();
-        let port = String::from_utf8(cmpts[1].to_vec()).unwrap().parse::<i32>().unwrap();
-
-        match peers.iter_mut().position(|p| p.ip == ip && p.port == port)
+        if peers.iter().any(|p| p.ip == ip && p.port == port && p.is_connected()) || is_dialing(dials, &ip, port)
         {
-            Some(peer_idx) =>
-            {
-                peers.remove(peer_idx);
-            }
-            None => {}
+            continue;
+        }
+        let request = DialRequest { ip: ip.clone(), port: port, purpose: DialPurpose::Outbound };
+        if !start_dial(request, poll, dials, peers, addrman, bans, config, identity)
+        {
+            persistent.failed(&ip, port);
         }
     }
 }
 
-fn rcv_lisp(
-    payload: &[u8],
-    peers: &mut Vec<Peer>)
+// DIALS ADDRESSES PICKED BY THE ADDRESS MANAGER UNTIL max_outbound ARE OPEN
+// OR ON THEIR WAY, TRYING AT MOST MAX_CONNECT_ATTEMPTS EACH CALL
+fn fill_outbound(
+    poll: &Poll,
+    dials: &mut HashMap<Token, Dial>,
+    peers: &[Peer],
+    addrman: &mut AddrMan,
+    bans: &BanList,
+    config: &Config,
+    identity: &Identity)
 {
-    println!("rcv_lisp {}", String::from_utf8(payload.to_vec()).unwrap());
-
-    let cmpts: Vec<&[u8]> = payload.split({|x| *x == ':' as u8}).collect();
-    if cmpts.len() == 2
+    let mut attempted: HashSet<(String, i32)> = HashSet::new();
+    while outbound_count(peers, dials) < config.max_outbound &&
+          attempted.len() < MAX_CONNECT_ATTEMPTS
     {
-        let ip = String::from_utf8(cmpts[0].to_vec()).unwrap();
-        let port = String::from_utf8(cmpts[1].to_vec()).unwrap().parse::<i32>().unwrap();
-
-        match peers.iter_mut().position(|p| p.ip == ip && p.port == port)
+        let candidate = addrman.select(|info| {
+            attempted.contains(&(info.ip.clone(), info.port)) ||
+            config.is_self(&info.ip, info.port) ||
+            bans.is_banned(&info.ip) ||
+            peers.iter().any(|p| p.ip == info.ip && p.port == info.port) ||
+            is_dialing(dials, &info.ip, info.port)
+        });
+        match candidate
         {
-            Some(peer_idx) =>
+            Some(info) =>
             {
-                let mut stream = peers[peer_idx].clone().socket.unwrap();
-
-                let msg = Msg::new_list_peers_response(peers).to_vec();
-                match stream.write(&msg)
-                {
-                    Ok(nbytes) => {
-                        println!("resp_lisp bytes written: {} at {}", nbytes, UTC::now().time());
-                    }
-                    Err(e) => {
-                        println!("Error writing to stream: {}", e);
-                    }
-                }
+                attempted.insert((info.ip.clone(), info.port));
+                let request = DialRequest { ip: info.ip, port: info.port, purpose: DialPurpose::Outbound };
+                start_dial(request, poll, dials, peers, addrman, bans, config, identity);
             }
-            None => {}
+            None => { break; }
         }
     }
 }
 
-fn rcv_resp_lisp(
-    payload: &[u8],
-    server: &TcpListener,
-    peers: &mut Vec<Peer>,
-    db: &Connection)
+// ONE OUTBOUND PEER BEYOND max_outbound, FOR WHEN THE TIP HAS GONE STALE. ONCE
+// IT'S UP, finish_dial ASKS IT FOR HEADERS AND MAKES ROOM FOR IT.
+fn extra_outbound(
+    poll: &Poll,
+    dials: &mut HashMap<Token, Dial>,
+    peers: &[Peer],
+    addrman: &mut AddrMan,
+    bans: &BanList,
+    config: &Config,
+    identity: &Identity)
 {
-    println!("rcv_resp_lisp {}", String::from_utf8(payload.to_vec()).unwrap());
-
-    let cmpts: Vec<&[u8]> = payload.split({|x| *x == ',' as u8}).collect();
-    for addr in cmpts
+    if dials.values().any(|d| d.purpose == DialPurpose::Extra)
+    {
+        return;
+    }
+    let candidate = addrman.select(|info| {
+        config.is_self(&info.ip, info.port) ||
+        bans.is_banned(&info.ip) ||
+        peers.iter().any(|p| p.ip == info.ip && p.port == info.port) ||
+        is_dialing(dials, &info.ip, info.port)
+    });
+    match candidate
     {
-        rcv_addp(
-            addr,
-            server,
-            peers,
-            db);
+        Some(info) =>
+        {
+            println!("Tip is stale, trying an extra outbound connection to {}", format_addr(&info.ip, info.port));
+            let request = DialRequest { ip: info.ip, port: info.port, purpose: DialPurpose::Extra };
+            start_dial(request, poll, dials, peers, addrman, bans, config, identity);
+        }
+        None => { println!("Tip is stale but there are no new addresses to try"); }
     }
 }
 
-pub fn rcv_blnc(
-    payload: &[u8],
-    peers: &mut Vec<Peer>)
+// IF WE ALREADY HAD THE EXTRA OUTBOUND PEER, THE OUTBOUND PEER THAT HAS GONE
+// LONGEST WITHOUT GIVING US A BLOCK IS DROPPED FOR THE NEW ONE
+fn make_room_for_extra(
+    ip: &str,
+    port: i32,
+    peers: &mut Vec<Peer>,
+    clients: &mut HashMap<Token, Client>,
+    persistent: &PersistentPeers,
+    config: &Config)
 {
-    let cmpts: Vec<&[u8]> = payload.split({|x| *x == ',' as u8}).collect();
-    if cmpts.len() == 2
+    if peers.iter().filter(|p| !p.inbound).count() > config.max_outbound + 1
     {
-        let balance = wallet::balance(cmpts[0]);
-        let cmpts: Vec<&[u8]> = cmpts[1].split({|x| *x == ':' as u8}).collect();
-
-        if cmpts.len() == 2
+        let worst = peers
+            .iter()
+            .enumerate()
+            .filter(|&(_, p)| !p.inbound && !persistent.contains(&p.ip, p.port) && (p.ip != ip || p.port != port))
+            .min_by_key(|&(_, p)| p.last_block)
+            .map(|(idx, _)| idx);
+        if let Some(idx) = worst
         {
-            let ip = String::from_utf8(cmpts[0].to_vec()).unwrap();
-            let port = String::from_utf8(cmpts[1].to_vec()).unwrap().parse::<i32>().unwrap();
-
-            match peers.iter_mut().position(|p| p.ip == ip && p.port == port)
-            {
-                Some(peer_idx) =>
-                {
-                    let mut stream = peers[peer_idx].clone().socket.unwrap();
-
-                    let msg = Msg::new_balance_response(balance).to_vec();
-                    match stream.write(&msg)
-                    {
-                        Ok(nbytes) => {
-                            println!("resp_blnc bytes written: {} at {}", nbytes, UTC::now().time());
-                        }
-                        Err(e) => {
-                            println!("Error writing to stream: {}", e);
-                        }
-                    }
-                }
-                None => {}
-            }
+            disconnect_peer(idx, peers, clients);
         }
     }
 }
 
-pub fn rcv_resp_blnc(
-    payload: &[u8])
+// OUTBOUND PEERS AND THE DIALS THAT WILL BECOME ONE, AGAINST max_outbound
+fn outbound_count(
+    peers: &[Peer],
+    dials: &HashMap<Token, Dial>) -> usize
 {
-    let balance = LittleEndian::read_i64(&payload);
-    println!("Balance received: {}", balance);
+    peers.iter().filter(|p| !p.inbound).count() +
+        dials.values().filter(|d| d.purpose == DialPurpose::Outbound).count()
 }
 
-pub fn rcv_addt(
-    payload: &[u8],
-    db: &Connection,
-    transaction_snd_to_mine: &Sender<Transaction>)
+fn is_dialing(
+    dials: &HashMap<Token, Dial>,
+    ip: &str,
+    port: i32) -> bool
 {
-    println!("rcv_addt");
+    dials.values().any(|d| d.ip == ip && d.port == port)
+}
 
-    let mut tx = Transaction::from_slice(payload);
-    if tx.verify()
+// STARTS A NON-BLOCKING CONNECT, DIRECT OR TO --proxy. NOTHING WAITS ON IT:
+// POLL TELLS US WHEN THE SOCKET IS READY AND finish_dial MAKES THE PEER.
+fn start_dial(
+    request: DialRequest,
+    poll: &Poll,
+    dials: &mut HashMap<Token, Dial>,
+    peers: &[Peer],
+    addrman: &mut AddrMan,
+    bans: &BanList,
+    config: &Config,
+    identity: &Identity) -> bool
+{
+    let (ip, port) = (request.ip, request.port);
+    if config.is_self(&ip, port) || bans.is_banned(&ip) ||
+       peers.iter().any(|p| p.ip == ip && p.port == port) || is_dialing(dials, &ip, port)
+    {
+        return false;
+    }
+
+    let addr = match dial_addr(&ip, port, config)
     {
-        if database::insert_transaction(&tx, db).is_ok()
+        Some(addr) => { addr }
+        None =>
         {
-            let _ = transaction_snd_to_mine.send(tx);
+            println!("Failed to resolve {}", format_addr(&ip, port));
+            return false;
         }
-    }
-    else
+    };
+
+    println!("Connecting to peer at {}", format_addr(&ip, port));
+    addrman.attempt(&ip, port);
+    // ENCRYPTED WHEN THE NETWORK IS PERMISSIONED OR WE PINNED THIS NODE'S KEY
+    let pin = config.pinned_key(&ip, port);
+    let handshake = if config.require_handshake() || pin.is_some() { Some((identity.clone(), pin)) } else { None };
+    match Dial::start(&ip, port, &addr, request.purpose, config.proxy.is_some(), handshake)
     {
-        println!("Invalid transaction");
+        Ok(dial) =>
+        {
+            let token = (FIRST_DIAL_TOKEN..)
+                .map(Token)
+                .find(|token| !dials.contains_key(token))
+                .unwrap();
+            poll.register(
+                dial.socket(),
+                token,
+                Ready::readable() | Ready::writable(),
+                PollOpt::edge()).expect("Failed to register dial socket");
+            dials.insert(token, dial);
+            true
+        }
+        Err(e) =>
+        {
+            println!("Error connecting to host: {}", e);
+            false
+        }
     }
 }
 
-pub fn rcv_vldt(
-    payload: &[u8],
-    peers: &mut Vec<Peer>)
+// WHERE A DIAL'S SOCKET CONNECTS: THE PROXY, OR ip:port ITSELF. WITHOUT A
+// PROXY, A HOSTNAME FROM --addnode IS LOOKED UP HERE.
+fn dial_addr(
+    ip: &str,
+    port: i32,
+    config: &Config) -> Option<SocketAddr>
 {
-    println!("rcv_vldt");
-
-    let cmpts: Vec<&[u8]> = payload.split({|x| *x == ',' as u8}).collect();
-    if cmpts.len() == 2
+    match config.proxy
     {
-        let mut tx = Transaction::from_slice(cmpts[0]);
-        let valid = tx.verify();
+        Some(proxy) => { Some(proxy) }
+        None => { (ip, port as u16).to_socket_addrs().ok().and_then(|mut addrs| addrs.next()) }
+    }
+}
 
-        let cmpts: Vec<&[u8]> = cmpts[1].split({|x| *x == ':' as u8}).collect();
-        if cmpts.len() == 2
+// AN EVENT ON A DIAL'S SOCKET
+fn dial_ready(
+    token: Token,
+    poll: &Poll,
+    dials: &mut HashMap<Token, Dial>,
+    peers: &mut Vec<Peer>,
+    clients: &mut HashMap<Token, Client>,
+    persistent: &mut PersistentPeers,
+    addrman: &mut AddrMan,
+    config: &Config,
+    bandwidth: &Bandwidth,
+    sync: &mut HeaderSync,
+    db: &dyn Store)
+{
+    let ready = match dials.get_mut(&token)
+    {
+        Some(dial) => { dial.ready() }
+        None => { return; }
+    };
+    let connected = match ready
+    {
+        Ok(false) => { return; }
+        Ok(true) => { true }
+        Err(e) =>
+        {
+            if let Some(dial) = dials.get(&token)
+            {
+                println!("Error connecting to {}: {}", format_addr(&dial.ip, dial.port), e);
+            }
+            false
+        }
+    };
+
+    let dial = dials.remove(&token).unwrap();
+    let _ = poll.deregister(dial.socket());
+    let (ip, port) = (dial.ip.clone(), dial.port);
+    if !connected || !finish_dial(dial, peers, clients, persistent, addrman, config, bandwidth, sync, db)
+    {
+        persistent.failed(&ip, port);
+    }
+}
+
+// A DIAL THAT IS TAKING TOO LONG COUNTS AS FAILED
+fn expire_dials(
+    poll: &Poll,
+    dials: &mut HashMap<Token, Dial>,
+    persistent: &mut PersistentPeers)
+{
+    let now = UTC::now().timestamp();
+    let expired: Vec<Token> = dials
+        .iter()
+        .filter(|&(_, dial)| dial.expired(now))
+        .map(|(token, _)| *token)
+        .collect();
+    for token in expired
+    {
+        if let Some(dial) = dials.remove(&token)
+        {
+            println!("Connecting to {} timed out", format_addr(&dial.ip, dial.port));
+            let _ = poll.deregister(dial.socket());
+            persistent.failed(&dial.ip, dial.port);
+        }
+    }
+}
+
+// THE DIAL'S CONNECTION IS UP; RETURNS FALSE IF IT DIDN'T BECOME A PEER
+fn finish_dial(
+    mut dial: Dial,
+    peers: &mut Vec<Peer>,
+    clients: &mut HashMap<Token, Client>,
+    persistent: &mut PersistentPeers,
+    addrman: &mut AddrMan,
+    config: &Config,
+    bandwidth: &Bandwidth,
+    sync: &mut HeaderSync,
+    db: &dyn Store) -> bool
+{
+    let timestamp = UTC::now().timestamp();
+    let (ip, port, purpose) = (dial.ip.clone(), dial.port, dial.purpose);
+
+    let (session, remote) = (dial.session.take(), dial.remote);
+    if let Some(ref key) = remote
+    {
+        if !config.is_allowed(key)
+        {
+            println!("Refusing {}: node {} is not on the allowlist", format_addr(&ip, port), to_hex_string(key));
+            return false;
+        }
+        println!("Encrypted connection to {} as {}", format_addr(&ip, port), to_hex_string(key));
+    }
+    let stream = match dial.finish()
+    {
+        Ok(stream) => { stream }
+        Err(e) =>
+        {
+            println!("Error connecting to host: {}", e);
+            return false;
+        }
+    };
+
+    // THE CONNECTION WE'RE LINKING BACK FOR MAY HAVE GONE, OR BEEN LINKED TO
+    // A PEER WE DIALED OURSELVES, WHILE WE WERE CONNECTING
+    if let DialPurpose::LinkBack(link) = purpose
+    {
+        let linked = peers.iter().any(|p| p.token == Some(link));
+        match clients.get(&link)
+        {
+            Some(_) if linked => { return true; }
+            Some(client) =>
+            {
+                if client.identity.is_some() && remote.is_some() && client.identity != remote
+                {
+                    println!("Connection {:?} is not the node at {}", link, format_addr(&ip, port));
+                    return false;
+                }
+            }
+            None =>
+            {
+                println!("Connection {:?} closed before we linked back to {}", link, format_addr(&ip, port));
+                return true;
+            }
+        }
+    }
+    if peers.iter().any(|p| p.ip == ip && p.port == port)
+    {
+        return true;
+    }
+
+    addrman.good(&ip, port);
+    persistent.connected(&ip, port, timestamp);
+    let mut peer = Peer::new(ip.clone(), port, timestamp, Some(Box::new(stream)));
+    peer.bandwidth = Some(bandwidth.clone());
+    peer.session = session;
+    peer.identity = remote;
+    peer.send(&NetworkMessage::AddPeer(config.advertised_addr(), timestamp).to_msg());
+    peer.send(&NetworkMessage::Mempool.to_msg());
+    match purpose
+    {
+        DialPurpose::LinkBack(link) =>
+        {
+            peer.token = Some(link);
+            peer.inbound = true;
+        }
+        DialPurpose::Outbound =>
+        {
+            // HEADER SYNC STARTS FROM OUR FIRST OUTBOUND PEER
+            peer.send(&NetworkMessage::GetAddr.to_msg());
+            if !sync.is_syncing() && !peers.iter().any(|p| !p.inbound)
+            {
+                sync.request_headers(&mut peer, db);
+            }
+        }
+        DialPurpose::Extra =>
+        {
+            // SO A BETTER CHAIN ON ITS SIDE OF A PARTITION REACHES US
+            sync.request_headers(&mut peer, db);
+        }
+    }
+    peers.push(peer);
+
+    if purpose == DialPurpose::Extra
+    {
+        make_room_for_extra(&ip, port, peers, clients, persistent, config);
+    }
+    true
+}
+
+// HOW THIS NODE SEES ITSELF: CHAIN, TIP FRESHNESS, CONNECTIONS AND CLOCK
+fn print_status(
+    stale_tip: &StaleTipMonitor,
+    sync: &HeaderSync,
+    network_time: &NetworkTime,
+    peers: &[Peer],
+    db: &dyn Store)
+{
+    let chain = main_chain(db);
+    match chain.last()
+    {
+        Some(tip) =>
+        {
+            println!("height {} tip {}", chain.len() - 1, to_hex_string(&tip.block_hash));
+            println!("tip age {}s, stale after {}s", network_time.now() - tip.timestamp, stale_tip.threshold());
+        }
+        None => { println!("no blocks yet"); }
+    }
+    if stale_tip.is_stale()
+    {
+        println!("WARNING: tip is stale; this node may be partitioned from the network");
+    }
+    if sync.is_syncing()
+    {
+        println!("syncing");
+    }
+    let outbound = peers.iter().filter(|p| !p.inbound).count();
+    println!("{} outbound, {} inbound peers", outbound, peers.len() - outbound);
+    println!("clock offset {}s", network_time.offset());
+}
+
+// TOTALS SINCE STARTUP BY COMMAND, THEN EACH PEER. WE SEND ON THE PEER AND
+// RECEIVE ON ITS CLIENT, SO A PEER'S recv COMES FROM THE CLIENT WITH ITS TOKEN.
+fn print_netstats(
+    bandwidth: &Bandwidth,
+    network_time: &NetworkTime,
+    peers: &[Peer],
+    clients: &HashMap<Token, Client>)
+{
+    println!("clock offset {}s from {} peers", network_time.offset(), network_time.samples());
+    let totals = bandwidth.totals();
+    println!("sent {} bytes, received {} bytes", totals.sent, totals.recv);
+    let (window_sent, budget) = bandwidth.upload_usage();
+    if budget > 0
+    {
+        println!("upload budget {}/{} bytes this window", window_sent, budget);
+    }
+    for (command, sent, recv) in totals.commands()
+    {
+        println!("  {:12} sent {:>12} recv {:>12}", String::from_utf8_lossy(&command).trim(), sent, recv);
+    }
+    for peer in peers.iter()
+    {
+        let recv = peer.token.and_then(|token| clients.get(&token)).map_or(0, |client| client.traffic.recv);
+        println!("  {:<24} sent {:>12} recv {:>12}", format_addr(&peer.ip, peer.port), peer.traffic.sent, recv);
+    }
+}
+
+fn handle_quit(
+    advertised: &SocketAddr,
+    peers: Vec<Peer>)
+{
+    let remp = NetworkMessage::RemovePeer(*advertised).to_msg();
+    for mut peer in peers
+    {
+        peer.send(&remp);
+    }
+}
+
+fn handle_connection(
+    server: &TcpListener,
+    poll: &Poll,
+    clients: &mut HashMap<Token, Client>,
+    peers: &mut Vec<Peer>,
+    bans: &BanList,
+    config: &Config)
+{
+    let (socket, addr) = match server.accept()
+    {
+        Err(e) => {
+            println!("Error accepting connection: {}", e);
+            return;
+        }
+        Ok((socket, addr)) => { (socket, addr) }
+    };
+
+    // BANS ARE KEPT BY CANONICAL IP, SO AN IPV4 PEER CAN'T DODGE ONE BY
+    // COMING BACK ON ITS IPV4-MAPPED IPV6 ADDRESS
+    let ip = canonical_ip(addr.ip()).to_string();
+    if bans.is_banned(&ip)
+    {
+        println!("Refusing connection from banned {}", addr);
+        return;
+    }
+
+    if clients.values().filter(|c| c.ip == ip).count() >= config.max_per_ip
+    {
+        println!("Refusing connection from {}: too many connections from that address", addr);
+        return;
+    }
+
+    if clients.len() >= config.max_inbound
+    {
+        // WHEN PERMISSIONED, A CONNECTION THAT HASN'T PROVED ITS KEY YET CAN'T
+        // PUSH OUT ONE FROM AN ALLOWED NODE
+        let candidates = clients
+            .iter()
+            .filter(|&(_, client)| !config.permissioned() || client.identity.is_none())
+            .map(|(token, client)| {
+                let peer = peers.iter().find(|p| p.token == Some(*token));
+                EvictionCandidate {
+                    token: *token,
+                    ip: client.ip.clone(),
+                    connected: client.connected,
+                    latency_ms: peer.and_then(|p| p.latency_ms),
+                    last_block: peer.map_or(0, |p| p.last_block)
+                }
+            })
+            .collect();
+        match select_eviction(candidates)
+        {
+            Some(token) =>
+            {
+                println!("Evicting connection {:?} to make room for {}", token, addr);
+                disconnect_client(token, peers, clients);
+            }
+            None =>
+            {
+                println!("Refusing connection from {}: no inbound slots", addr);
+                return;
+            }
+        }
+    }
+
+    println!("Accepted connection from {:?}", addr);
+
+    // TOKENS OF CLOSED CONNECTIONS ARE REUSED
+    let token = (FIRST_CLIENT_TOKEN..)
+        .map(Token)
+        .find(|token| !clients.contains_key(token))
+        .unwrap();
+
+    poll.register(
+        &socket,
+        token,
+        Ready::readable(),
+        PollOpt::edge()).expect("Failed to register client socket");
+
+    clients.insert(token, Client::new(Box::new(socket), ip));
+}
+
+// PING EVERY PEER THAT'S DUE ONE, AND DROP PEERS THAT DIDN'T ANSWER THE LAST
+// PING IN TIME OR HAVE SENT NOTHING AT ALL FOR idle_timeout SECONDS
+fn keepalive(
+    config: &Config,
+    peers: &mut Vec<Peer>,
+    clients: &mut HashMap<Token, Client>)
+{
+    let now = UTC::now().timestamp();
+
+    let mut dead = vec![];
+    for (idx, peer) in peers.iter_mut().enumerate()
+    {
+        let ping_elapsed = peer.ping_sent.map(|sent| sent.elapsed().as_secs());
+        if !peer.is_connected()
+        {
+            println!("Lost connection to {}:{}", peer.ip, peer.port);
+            dead.push(idx);
+        }
+        else if peer.ping_nonce.is_some() && ping_elapsed.map_or(false, |e| e > config.ping_timeout)
+        {
+            println!("Ping to {}:{} timed out", peer.ip, peer.port);
+            dead.push(idx);
+        }
+        else if now - peer.last_recv > config.idle_timeout as i64
+        {
+            println!("Peer {}:{} idle for {}s", peer.ip, peer.port, now - peer.last_recv);
+            dead.push(idx);
+        }
+        else if peer.ping_nonce.is_none() && ping_elapsed.map_or(true, |e| e >= config.ping_interval)
+        {
+            let nonce = rand::random::<u64>();
+            if peer.send(&NetworkMessage::Ping(nonce).to_msg())
+            {
+                peer.ping_nonce = Some(nonce);
+                peer.ping_sent = Some(time::Instant::now());
+            }
+        }
+    }
+    for idx in dead.into_iter().rev()
+    {
+        disconnect_peer(idx, peers, clients);
+    }
+
+    let idle: Vec<Token> = clients
+        .iter()
+        .filter(|&(_, c)| now - c.last_recv > config.idle_timeout as i64)
+        .map(|(token, _)| *token)
+        .collect();
+    for token in idle
+    {
+        println!("Closing idle connection {:?}", token);
+        disconnect_client(token, peers, clients);
+    }
+
+    if config.require_handshake()
+    {
+        let unauthenticated: Vec<Token> = clients
+            .iter()
+            .filter(|&(_, c)| c.session.is_none() && now - c.connected > HANDSHAKE_TIMEOUT_SECS as i64)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in unauthenticated
+        {
+            println!("Closing connection {:?}: no handshake", token);
+            disconnect_client(token, peers, clients);
+        }
+    }
+}
+
+fn disconnect_peer(
+    peer_idx: usize,
+    peers: &mut Vec<Peer>,
+    clients: &mut HashMap<Token, Client>)
+{
+    let peer = peers.remove(peer_idx);
+    println!("Disconnecting {}:{}", peer.ip, peer.port);
+    match peer.token
+    {
+        Some(token) => { clients.remove(&token); }
+        None => {}
+    }
+}
+
+fn disconnect_client(
+    token: Token,
+    peers: &mut Vec<Peer>,
+    clients: &mut HashMap<Token, Client>)
+{
+    clients.remove(&token);
+    match peers.iter().position(|p| p.token == Some(token))
+    {
+        Some(peer_idx) => { disconnect_peer(peer_idx, peers, clients); }
+        None => {}
+    }
+}
+
+// RETURNS TRUE IF THE CONNECTION CROSSED THE BAN THRESHOLD AND WAS DROPPED
+fn misbehaving(
+    token: Token,
+    misbehaviour: Misbehaviour,
+    config: &Config,
+    peers: &mut Vec<Peer>,
+    clients: &mut HashMap<Token, Client>,
+    bans: &mut BanList,
+    db: &dyn Store) -> bool
+{
+    let (ip, score) = match clients.get_mut(&token)
+    {
+        Some(client) =>
+        {
+            client.misbehaviour += misbehaviour.score();
+            (client.ip.clone(), client.misbehaviour)
+        }
+        None => { return false; }
+    };
+
+    println!("Misbehaviour from {}: {:?}, score {}", ip, misbehaviour, score);
+
+    if score >= BAN_THRESHOLD
+    {
+        bans.ban(&ip, config.ban_time, db);
+        disconnect_client(token, peers, clients);
+        true
+    }
+    else
+    {
+        false
+    }
+}
+
+// BUILT-IN COMMANDS, FOR start_server. EXTENSIONS register THEIR OWN COMMANDS
+// ON TOP, OR REPLACE A BUILT-IN ONE.
+pub fn default_handlers() -> HandlerRegistry
+{
+    let mut handlers = HandlerRegistry::new();
+    for command in COMMANDS.iter()
+    {
+        handlers.register(command, handle_builtin);
+    }
+    handlers
+}
+
+// READS EVERYTHING THE CONNECTION HAS FOR US AND HANDS EACH MESSAGE TO
+// WHATEVER IS REGISTERED FOR ITS COMMAND
+pub fn handle_message(
+    ctx: &mut NodeContext,
+    handlers: &mut HandlerRegistry)
+{
+    // let mut rng = rand::thread_rng();
+    // let stutter = time::Duration::from_millis(rng.gen_range::<u64>(0, 5000));
+    // thread::sleep(stutter);
+
+    let token = ctx.token;
+    loop
+    {
+        // ONCE THE HANDSHAKE IS DONE EVERYTHING ON THIS CONNECTION IS FRAMED
+        // AND SEALED. THE SOCKET IS NON-BLOCKING, SO A MESSAGE OR FRAME CAN
+        // ARRIVE OVER SEVERAL READS; IT STAYS BUFFERED UNTIL IT'S WHOLE.
+        let read = match ctx.clients.get_mut(&token)
+        {
+            Some(client) =>
+            {
+                match client.session
+                {
+                    Some(ref mut session) => { session.read_msg(&mut client.received) }
+                    None => { Msg::from_buffer(&mut client.received, MAX_PAYLOAD_LEN) }
+                }
+            }
+            None => { break; }
+        };
+        match read
+        {
+            Ok(None) =>
+            {
+                let received = match ctx.clients.get_mut(&token)
+                {
+                    Some(client) => { client.receive() }
+                    None => { break; }
+                };
+                match received
+                {
+                    Ok(true) => {}
+                    Ok(false) => { break; }
+                    Err(e) =>
+                    {
+                        read_failed(token, e, ctx);
+                        break;
+                    }
+                }
+            }
+            Ok(Some(msg)) =>
+            {
+                let now = UTC::now().timestamp();
+                let mut nbytes = MSG_HEADER_LEN + msg.payload.len();
+                match ctx.clients.get_mut(&token)
+                {
+                    Some(client) =>
+                    {
+                        if client.session.is_some() { nbytes += NBYTES_U32 + TAG_LEN; }
+                        client.last_recv = now;
+                        client.traffic.record_recv(&msg.command, nbytes);
+                    }
+                    None => {}
+                }
+                ctx.bandwidth.record_recv(&msg.command, nbytes);
+                match ctx.peer()
+                {
+                    Some(peer) => { peer.last_recv = now; }
+                    None => {}
+                }
+
+                let plaintext = ctx.clients.get(&token).map_or(false, |client| client.session.is_none());
+                if ctx.config.require_handshake() && plaintext && &msg.command != b"hello       " && &msg.command != b"hellofin    "
+                {
+                    println!("Refusing unencrypted connection {:?}", token);
+                    disconnect_client(token, ctx.peers, ctx.clients);
+                    break;
+                }
+
+                match handlers.dispatch(&msg, ctx)
+                {
+                    Some(Ok(())) => {}
+                    Some(Err(misbehaviour)) =>
+                    {
+                        if misbehaving(token, misbehaviour, ctx.config, ctx.peers, ctx.clients, ctx.bans, ctx.db)
+                        {
+                            break;
+                        }
+                    }
+                    None => {
+                        print!("Unknown cmd: {}\n", String::from_utf8_lossy(&msg.command));
+                    }
+                }
+
+                // A HANDLER MAY HAVE DROPPED THE CONNECTION
+                if !ctx.clients.contains_key(&token)
+                {
+                    break;
+                }
+            }
+            Err(e) =>
+            {
+                read_failed(token, e, ctx);
+                break;
+            }
+        }
+    }
+}
+
+fn read_failed(
+    token: Token,
+    e: io::Error,
+    ctx: &mut NodeContext)
+{
+    if e.kind() == ErrorKind::UnexpectedEof
+    {
+        println!("Connection {:?} closed", token);
+        disconnect_client(token, ctx.peers, ctx.clients);
+    }
+    else if e.kind() == ErrorKind::InvalidData
+    {
+        println!("Error decoding message: {}", e);
+        misbehaving(token, Misbehaviour::MalformedMessage, ctx.config, ctx.peers, ctx.clients, ctx.bans, ctx.db);
+    }
+    else
+    {
+        println!("Error reading stream: {}", e);
+    }
+}
+
+fn handle_builtin(
+    msg: &Msg,
+    ctx: &mut NodeContext) -> Result<(), Misbehaviour>
+{
+    match NetworkMessage::from_msg(msg)
+    {
+        Ok(NetworkMessage::Hello(payload)) => { rcv_hello(&payload, ctx) }
+        Ok(NetworkMessage::HelloFin(payload)) => { rcv_hellofin(&payload, ctx) }
+        Ok(NetworkMessage::Ping(nonce)) => { rcv_ping(nonce, ctx); Ok(()) }
+        Ok(NetworkMessage::Pong(nonce)) => { rcv_pong(nonce, ctx); Ok(()) }
+        Ok(NetworkMessage::AddPeer(addr, timestamp)) => { rcv_addp(&addr, timestamp, ctx) }
+        Ok(NetworkMessage::RemovePeer(addr)) => { rcv_remp(&addr, ctx); Ok(()) }
+        Ok(NetworkMessage::GetAddr) => { rcv_getaddr(ctx); Ok(()) }
+        Ok(NetworkMessage::Addr(addrs)) => { rcv_addr(addrs, ctx); Ok(()) }
+        Ok(NetworkMessage::AddTransaction(tx)) => { rcv_addt(tx, ctx) }
+        Ok(NetworkMessage::Mempool) => { rcv_mempool(ctx); Ok(()) }
+        Ok(NetworkMessage::GetBlock(hash, addr)) => { rcv_getb(&hash, &addr, ctx); Ok(()) }
+        Ok(NetworkMessage::AddBlock(block)) => { rcv_addb(block, ctx) }
+        Ok(NetworkMessage::CompactBlock(compact)) => { rcv_cmpctblock(compact, ctx) }
+        Ok(NetworkMessage::GetBlockTxn(hash, indexes)) => { rcv_getblocktxn(hash, indexes, ctx) }
+        Ok(NetworkMessage::BlockTxn(hash, txs)) => { rcv_blocktxn(hash, txs, ctx) }
+        Ok(NetworkMessage::Inv(items)) => { rcv_inv(items, ctx); Ok(()) }
+        Ok(NetworkMessage::GetData(items)) => { rcv_getdata(items, ctx); Ok(()) }
+        Ok(NetworkMessage::NotFound(items)) => { rcv_notfound(items, ctx); Ok(()) }
+        Ok(NetworkMessage::GetHeaders(locator, stop_hash)) => { rcv_getheaders(&locator, &stop_hash, ctx); Ok(()) }
+        Ok(NetworkMessage::Headers(headers)) => { rcv_headers(headers, ctx) }
+        Ok(NetworkMessage::Chat(chat)) => { rcv_chat(chat, ctx) }
+        Ok(NetworkMessage::Query(id, query)) => { rcv_query(id, query, ctx) }
+        Ok(NetworkMessage::Response(id, response)) => { rcv_resp(id, response, ctx); Ok(()) }
+        Ok(NetworkMessage::FilterLoad(filter)) => { rcv_filterload(filter, ctx); Ok(()) }
+        Ok(NetworkMessage::FilterAdd(element)) => { rcv_filteradd(&element, ctx) }
+        Ok(NetworkMessage::FilterClear) => { rcv_filterclear(ctx); Ok(()) }
+        Ok(NetworkMessage::GetCFilters(start, stop_hash)) => { rcv_getcfilters(start, &stop_hash, ctx); Ok(()) }
+        // WE NEVER ASK FOR ANY OF THESE
+        Ok(NetworkMessage::HelloAck(_)) |
+        Ok(NetworkMessage::MerkleBlock(_)) |
+        Ok(NetworkMessage::CFilters(_)) |
+        Err(DecodeError::Malformed) => { Err(Misbehaviour::MalformedMessage) }
+        Err(DecodeError::UnknownCommand) =>
+        {
+            print!("Unknown cmd: {}\n", String::from_utf8_lossy(&msg.command));
+            Ok(())
+        }
+    }
+}
+
+fn rcv_hello(
+    payload: &[u8],
+    ctx: &mut NodeContext) -> Result<(), Misbehaviour>
+{
+    let token = ctx.token;
+    let client = match ctx.clients.get_mut(&token)
+    {
+        Some(client) => { client }
+        None => { return Ok(()); }
+    };
+    if client.handshake.is_some() || client.session.is_some()
+    {
+        return Err(Misbehaviour::MalformedMessage);
+    }
+
+    match Responder::new(ctx.identity, payload)
+    {
+        Ok((responder, ack)) =>
+        {
+            client.handshake = Some(responder);
+            match client.socket.write_all(&NetworkMessage::HelloAck(ack).to_msg().to_vec())
+            {
+                Ok(_) => {}
+                Err(e) => { println!("Error writing to stream: {}", e); }
+            }
+            Ok(())
+        }
+        Err(e) =>
+        {
+            println!("Handshake from {} failed: {:?}", client.ip, e);
+            Err(Misbehaviour::MalformedMessage)
+        }
+    }
+}
+
+fn rcv_hellofin(
+    payload: &[u8],
+    ctx: &mut NodeContext) -> Result<(), Misbehaviour>
+{
+    let token = ctx.token;
+    let client = match ctx.clients.get_mut(&token)
+    {
+        Some(client) => { client }
+        None => { return Ok(()); }
+    };
+
+    match client.handshake.take().map(|responder| responder.finish(payload))
+    {
+        Some(Ok((session, remote))) =>
+        {
+            println!("Encrypted connection from {} as {}", client.ip, to_hex_string(&remote));
+            client.session = Some(session);
+            client.identity = Some(remote);
+            if !ctx.config.is_allowed(&remote)
+            {
+                println!("Refusing connection {:?}: node {} is not on the allowlist", token, to_hex_string(&remote));
+                disconnect_client(token, ctx.peers, ctx.clients);
+            }
+            Ok(())
+        }
+        Some(Err(e)) =>
+        {
+            println!("Handshake from {} failed: {:?}", client.ip, e);
+            Err(Misbehaviour::IdentityMismatch)
+        }
+        None => { Err(Misbehaviour::MalformedMessage) }
+    }
+}
+
+fn rcv_ping(
+    nonce: u64,
+    ctx: &mut NodeContext)
+{
+    ctx.reply(&NetworkMessage::Pong(nonce).to_msg());
+}
+
+fn rcv_pong(
+    nonce: u64,
+    ctx: &mut NodeContext)
+{
+    let token = ctx.token;
+    match ctx.peers.iter_mut().find(|p| p.token == Some(token))
+    {
+        Some(peer) =>
+        {
+            if peer.ping_nonce == Some(nonce)
+            {
+                let elapsed = peer.ping_sent.map(|sent| sent.elapsed());
+                peer.ping_nonce = None;
+                peer.latency_ms = elapsed.map(|e| e.as_secs() * 1000 + (e.subsec_nanos() / 1_000_000) as u64);
+                println!("Latency to {}:{} {}ms", peer.ip, peer.port, peer.latency_ms.unwrap_or(0));
+            }
+        }
+        None => {}
+    }
+}
+
+fn rcv_addp(
+    addr: &SocketAddr,
+    timestamp: i64,
+    ctx: &mut NodeContext) -> Result<(), Misbehaviour>
+{
+    let token = ctx.token;
+    println!("rcv_addp {}", addr);
+
+    // WE ONLY EVER LINK BACK TO WHERE THE CONNECTION COMES FROM, AND ONLY
+    // ONCE, SO addp CAN'T MAKE US DIAL ARBITRARY HOSTS. THAT KEEPS THESE DIALS
+    // WITHIN max-inbound.
+    let ip = match ctx.clients.get(&token)
+    {
+        Some(client) => { client.ip.clone() }
+        None => { return Ok(()); }
+    };
+    let port = addr.port() as i32;
+    if ctx.peers.iter().any(|p| p.token == Some(token))
+    {
+        println!("Connection {:?} is already linked to a peer", token);
+        return Ok(());
+    }
+
+    // A PINNED ADDRESS CAN ONLY BE CLAIMED OVER A HANDSHAKE WITH THE PINNED KEY
+    let remote = ctx.clients.get(&token).and_then(|client| client.identity);
+    if ctx.config.pinned_key(&ip, port).map_or(false, |pinned| remote != Some(pinned))
+    {
+        println!("Connection {:?} is not the node pinned for {}", token, format_addr(&ip, port));
+        return Err(Misbehaviour::IdentityMismatch);
+    }
+
+    // ONE CLOCK PER SOURCE ADDRESS, WHATEVER THE CONNECTION CLAIMS
+    ctx.network_time.add_sample(&ip, timestamp.saturating_sub(UTC::now().timestamp()));
+
+    // THE CONNECTION THIS ARRIVED ON IS THE ONE THAT PEER WRITES TO US ON.
+    // WITHOUT A PEER FOR IT YET, THE EVENT LOOP DIALS IT BACK AND LINKS THEM.
+    match ctx.peers.iter_mut().find(|p| p.ip == ip && p.port == port)
+    {
+        Some(peer) =>
+        {
+            if remote.is_some() && peer.identity.is_some() && remote != peer.identity
+            {
+                println!("Connection {:?} is not the node at {}", token, format_addr(&ip, port));
+                return Err(Misbehaviour::IdentityMismatch);
+            }
+            peer.token = Some(token);
+        }
+        None =>
+        {
+            ctx.dial_requests.push(DialRequest { ip: ip, port: port, purpose: DialPurpose::LinkBack(token) });
+        }
+    }
+    Ok(())
+}
+
+fn rcv_remp(
+    addr: &SocketAddr,
+    ctx: &mut NodeContext)
+{
+    println!("rcv_remp {}", addr);
+
+    let ip = canonical_ip(addr.ip()).to_string();
+    let port = addr.port() as i32;
+    match ctx.peers.iter_mut().position(|p| p.ip == ip && p.port == port)
+    {
+        Some(peer_idx) =>
+        {
+            ctx.peers.remove(peer_idx);
+        }
+        None => {}
+    }
+}
+
+fn rcv_getaddr(
+    ctx: &mut NodeContext)
+{
+    println!("rcv_getaddr");
+
+    let token = ctx.token;
+    match ctx.peers.iter_mut().find(|p| p.token == Some(token))
+    {
+        Some(peer) => { peer.send(&NetworkMessage::Addr(ctx.addrman.sample(MAX_ADDRS)).to_msg()); }
+        None => {}
+    }
+}
+
+fn rcv_addr(
+    addrs: Vec<NetAddr>,
+    ctx: &mut NodeContext)
+{
+    println!("rcv_addr");
+
+    let token = ctx.token;
+    let source = ctx.clients.get(&token).map_or(String::new(), |c| c.ip.clone());
+
+    // SMALL UNSOLICITED BATCHES ARE ANNOUNCEMENTS; PASS ON THE FRESH ONES WE HADN'T HEARD
+    let now = UTC::now().timestamp();
+    let announcement = addrs.len() <= MAX_RELAY_ADDRS;
+    let mut fresh = vec![];
+    for addr in addrs
+    {
+        if ctx.config.is_self(&addr.ip, addr.port) { continue; }
+        if ctx.addrman.add(&addr, &source) && announcement && now - addr.timestamp < ADDR_RELAY_AGE
+        {
+            fresh.push(addr);
+        }
+    }
+    if !fresh.is_empty()
+    {
+        relay_addrs(&fresh, token, ctx.peers);
+    }
+}
+
+// A COUPLE OF RANDOM PEERS IS ENOUGH FOR AN ADDRESS TO REACH THE WHOLE NETWORK
+fn relay_addrs(
+    addrs: &[NetAddr],
+    from: Token,
+    peers: &mut Vec<Peer>)
+{
+    let mut targets: Vec<&mut Peer> = peers
+        .iter_mut()
+        .filter(|p| p.token != Some(from) && p.is_connected())
+        .collect();
+    rand::thread_rng().shuffle(&mut targets);
+
+    let msg = NetworkMessage::Addr(addrs.to_vec()).to_msg();
+    for peer in targets.into_iter().take(ADDR_RELAY_PEERS)
+    {
+        peer.send(&msg);
+    }
+}
+
+fn advertise(
+    config: &Config,
+    peers: &mut Vec<Peer>)
+{
+    let advertised = config.advertised_addr();
+    let addr = NetAddr {
+        ip: canonical_ip(advertised.ip()).to_string(),
+        port: advertised.port() as i32,
+        services: NODE_NETWORK,
+        timestamp: UTC::now().timestamp()
+    };
+    let msg = NetworkMessage::Addr(vec![addr]).to_msg();
+    for peer in peers.iter_mut()
+    {
+        peer.send(&msg);
+    }
+}
+
+// ASKS A RANDOM CONNECTED PEER; THE CALLBACK RUNS WHEN ITS resp ARRIVES
+fn send_query(
+    query: Query,
+    callback: Callback,
+    peers: &mut Vec<Peer>,
+    pending_requests: &mut PendingRequests)
+{
+    let connected: Vec<usize> = (0..peers.len()).filter(|&i| peers[i].is_connected()).collect();
+    match rand::thread_rng().choose(&connected)
+    {
+        Some(&idx) =>
+        {
+            let id = pending_requests.register(&peers[idx], callback);
+            peers[idx].send(&NetworkMessage::Query(id, query).to_msg());
+        }
+        None => { callback(Err(QueryError::NoPeers)); }
+    }
+}
+
+fn rcv_resp(
+    id: RequestId,
+    response: Response,
+    ctx: &mut NodeContext)
+{
+    let token = ctx.token;
+    let answered = match ctx.peers.iter().find(|p| p.token == Some(token))
+    {
+        Some(peer) => { ctx.pending_requests.complete(id, peer, response) }
+        None => { false }
+    };
+    if !answered
+    {
+        println!("Unexpected response {}", id);
+    }
+}
+
+// ANSWERS GO BACK OVER THE CONNECTION THE QUERY CAME IN ON, UNDER THE SAME ID
+fn rcv_query(
+    id: RequestId,
+    query: Query,
+    ctx: &mut NodeContext) -> Result<(), Misbehaviour>
+{
+    let response = match query
+    {
+        Query::Balance(public_key) =>
+        {
+            println!("rcv_blnc");
+            Response::Balance(ctx.db.unspent_outputs(&public_key).iter().fold(0, |sum, txo| sum + txo.amount))
+        }
+        Query::Validate(mut tx) =>
+        {
+            println!("rcv_vldt");
+            Response::Valid(tx.verify())
+        }
+        Query::Height =>
+        {
+            println!("rcv_geth");
+            Response::Height(main_chain(ctx.db).len() as i64 - 1)
+        }
+        Query::Latest =>
+        {
+            // AN EMPTY ANSWER MEANS WE HAVE NO BLOCKS YET
+            println!("rcv_getl");
+            Response::Latest(main_chain(ctx.db).pop())
+        }
+        Query::ListBlocks(start, count) =>
+        {
+            // HASHES OF THE MAIN CHAIN FROM HEIGHT start, AT MOST MAX_LISB_HASHES OF THEM
+            println!("rcv_lisb");
+            let hashes = main_chain(ctx.db)
+                .iter()
+                .skip(start as usize)
+                .take(cmp::min(count, MAX_LISB_HASHES) as usize)
+                .map(|block| block.block_hash)
+                .collect();
+            Response::BlockHashes(start, hashes)
+        }
+        Query::Echo(bytes) =>
+        {
+            println!("rcv_echo");
+            if bytes.len() > MAX_ECHO_LEN { return Err(Misbehaviour::MalformedMessage); }
+            Response::Echo(bytes)
+        }
+    };
+    ctx.reply(&NetworkMessage::Response(id, response).to_msg());
+    Ok(())
+}
+
+fn rcv_chat(
+    chat: ChatMessage,
+    ctx: &mut NodeContext) -> Result<(), Misbehaviour>
+{
+    let token = ctx.token;
+    if !chat.verify() { return Err(Misbehaviour::MalformedMessage); }
+    if !ctx.chat_log.insert(&chat)
+    {
+        return Ok(());
+    }
+
+    match ctx.chat_log.check_rate(&chat, token, UTC::now().timestamp())
+    {
+        Ok(()) =>
+        {
+            println!("<{}> {}", &to_hex_string(&chat.public_key)[..16], chat.text);
+            relay_chat(&chat, Some(token), ctx.peers);
+            Ok(())
+        }
+        Err(ChatFlood::Connection) =>
+        {
+            println!("Too many chat messages on connection {:?}", token);
+            Err(Misbehaviour::ChatFlood)
+        }
+        Err(ChatFlood::Sender) =>
+        {
+            // A RELAY CAN PASS ON A BURST THAT WAS WITHIN THE LIMIT WHERE IT
+            // STARTED, SO ONLY THE SENDER'S OWN CONNECTION IS TO BLAME
+            println!("Too many chat messages from {}", &to_hex_string(&chat.public_key)[..16]);
+            let sender = ctx.clients.get(&token).and_then(|client| client.identity) == Some(chat.public_key);
+            if sender { Err(Misbehaviour::ChatFlood) } else { Ok(()) }
+        }
+    }
+}
+
+// EVERY PEER EXCEPT THE ONE IT CAME FROM; THE CHAT LOG STOPS IT LOOPING
+fn relay_chat(
+    chat: &ChatMessage,
+    from: Option<Token>,
+    peers: &mut Vec<Peer>)
+{
+    let msg = NetworkMessage::Chat(chat.clone()).to_msg();
+    for peer in peers.iter_mut().filter(|p| from.is_none() || p.token != from)
+    {
+        peer.send(&msg);
+    }
+}
+
+pub fn rcv_addt(
+    mut tx: Transaction,
+    ctx: &mut NodeContext) -> Result<(), Misbehaviour>
+{
+    println!("rcv_addt");
+
+    let token = ctx.token;
+    mark_received(&tx.hash, token, ctx.peers, ctx.inv_requests);
+    match ctx.peers.iter_mut().find(|p| p.token == Some(token))
+    {
+        Some(peer) => { peer.mark_known(&tx.hash); }
+        None => {}
+    }
+
+    if tx.verify()
+    {
+        if ctx.db.insert_transaction(&tx).is_ok()
+        {
+            relay_transaction(&tx, ctx.peers, ctx.clients);
+            let _ = ctx.transaction_snd_to_mine.send(tx);
+        }
+        Ok(())
+    }
+    else
+    {
+        println!("Invalid transaction");
+        Err(Misbehaviour::InvalidTransaction)
+    }
+}
+
+pub fn rcv_addb(
+    block: Block,
+    ctx: &mut NodeContext) -> Result<(), Misbehaviour>
+{
+    println!("rcv_addb");
+
+    let token = ctx.token;
+    mark_received(&block.block_hash, token, ctx.peers, ctx.inv_requests);
+    match ctx.peers.iter_mut().find(|p| p.token == Some(token))
+    {
+        Some(peer) => { peer.mark_known(&block.block_hash); }
+        None => {}
+    }
+
+    if ctx.sync.expects(&block.block_hash)
+    {
+        let valid = ctx.sync.rcv_block(block, ctx.db, ctx.block_snd_to_mine);
+        ctx.sync.request_blocks(ctx.peers);
+        if !valid
+        {
+            println!("Invalid block");
+            return Err(Misbehaviour::InvalidBlock);
+        }
+        mark_block_relay(token, ctx.peers);
+        // ONCE CAUGHT UP, THE NEW TIP IS NEWS TO OUR OTHER PEERS; THEY SYNC
+        // THE REST FROM US THE SAME WAY
+        if !ctx.sync.is_syncing()
+        {
+            match main_chain(ctx.db).pop().and_then(|tip| ctx.db.full_block(&tip.block_hash))
+            {
+                Some(tip) => { relay_block(&tip, ctx.peers, ctx.clients); }
+                None => {}
+            }
+        }
+        return Ok(());
+    }
+
+    accept_block(block, token, ctx.peers, ctx.clients, ctx.sync, ctx.db, ctx.block_snd_to_mine)
+}
+
+// A NEW BLOCK OUTSIDE OF HEADER SYNC, RECEIVED WHOLE OR REBUILT FROM A COMPACT BLOCK
+fn accept_block(
+    mut block: Block,
+    token: Token,
+    peers: &mut Vec<Peer>,
+    clients: &mut HashMap<Token, Client>,
+    sync: &mut HeaderSync,
+    db: &dyn Store,
+    block_snd_to_mine: &Sender<Block>) -> Result<(), Misbehaviour>
+{
+    let parent = db.block(&block.parent_hash);
+    let allowed = match parent
+    {
+        Some(ref parent) => { block.target <= max_target(&parent.target) }
+        // AN ORPHAN'S TARGET IS CHECKED WITH ITS HEADER WHEN WE SYNC IT
+        None if block.parent_hash != [0; 32] => { true }
+        None => { accepts_genesis(&block, db) }
+    };
+    // THE PROOF OF WORK AND THE TRANSACTIONS IT COMMITS TO, NOT JUST THE SIGNATURES
+    if allowed && block.verify_header() && block.verify_txs_hash() && block.verify()
+    {
+        let orphan = block.parent_hash != [0; 32] && parent.is_none();
+        if orphan
+        {
+            // WE'RE MISSING SOME OF ITS ANCESTORS; CATCH UP FROM THE PEER THAT SENT IT
+            if !sync.is_syncing()
+            {
+                match peers.iter_mut().find(|p| p.token == Some(token))
+                {
+                    Some(peer) => { sync.request_headers(peer, db); }
+                    None => {}
+                }
+            }
+        }
+        else if db.insert_block(&block).is_ok()
+        {
+            mark_block_relay(token, peers);
+            relay_block(&block, peers, clients);
+            let _ = block_snd_to_mine.send(block);
+        }
+        Ok(())
+    }
+    else
+    {
+        println!("Invalid block");
+        Err(Misbehaviour::InvalidBlock)
+    }
+}
+
+// REMEMBERED SO THAT USEFUL PEERS ARE PROTECTED FROM EVICTION
+fn mark_block_relay(
+    token: Token,
+    peers: &mut Vec<Peer>)
+{
+    match peers.iter_mut().find(|p| p.token == Some(token))
+    {
+        Some(peer) => { peer.last_block = UTC::now().timestamp(); }
+        None => {}
+    }
+}
+
+fn rcv_getheaders(
+    locator: &[[u8; 32]],
+    stop_hash: &[u8; 32],
+    ctx: &mut NodeContext)
+{
+    println!("rcv_getheaders");
+
+    let token = ctx.token;
+    match ctx.peers.iter_mut().find(|p| p.token == Some(token))
+    {
+        Some(peer) =>
         {
-            let ip = String::from_utf8(cmpts[0].to_vec()).unwrap();
-            let port = String::from_utf8(cmpts[1].to_vec()).unwrap().parse::<i32>().unwrap();
+            let headers = headers_after(locator, stop_hash, ctx.db);
+            peer.send(&NetworkMessage::Headers(headers).to_msg());
+        }
+        None => {}
+    }
+}
+
+// FILTERS FOR THE MAIN CHAIN FROM HEIGHT start UP TO AND INCLUDING stop_hash.
+// NOTHING IF stop_hash ISN'T WITHIN MAX_CFILTERS BLOCKS OF start ON OUR CHAIN.
+fn rcv_getcfilters(
+    start: u64,
+    stop_hash: &[u8; 32],
+    ctx: &mut NodeContext)
+{
+    println!("rcv_getcfilters");
+
+    let chain: Vec<Block> = main_chain(ctx.db)
+        .into_iter()
+        .skip(start as usize)
+        .take(MAX_CFILTERS)
+        .collect();
+    let count = match chain.iter().position(|block| block.block_hash == *stop_hash)
+    {
+        Some(idx) => { idx + 1 }
+        None => { 0 }
+    };
+    // THE REPLY STOPS AT THE FIRST BLOCK WE WON'T OR CAN'T SERVE, SO WHAT THE
+    // PEER GETS IS ALWAYS A RUN FROM start WITH NO GAPS
+    let filters: Vec<([u8; 32], GcsFilter)> = chain[..count]
+        .iter()
+        .take_while(|block| serves_block(block, ctx.bandwidth, ctx.network_time))
+        .map(|block| {
+            let filter = ctx.db.block_filter(&block.block_hash).or_else(|| {
+                ctx.db.full_block(&block.block_hash).map(|block| {
+                    let filter = GcsFilter::for_block(&block);
+                    ctx.db.insert_block_filter(&block.block_hash, &filter);
+                    filter
+                })
+            });
+            filter.map(|filter| (block.block_hash, filter))
+        })
+        .take_while(|filter| filter.is_some())
+        .filter_map(|filter| filter)
+        .collect();
+    ctx.reply(&NetworkMessage::CFilters(filters).to_msg());
+}
+
+fn rcv_headers(
+    headers: Vec<Block>,
+    ctx: &mut NodeContext) -> Result<(), Misbehaviour>
+{
+    println!("rcv_headers");
+
+    let token = ctx.token;
+    let valid = match ctx.peers.iter_mut().find(|p| p.token == Some(token))
+    {
+        Some(peer) => { ctx.sync.rcv_headers(headers, peer, ctx.db) }
+        None => { true }
+    };
+    ctx.sync.request_blocks(ctx.peers);
+    if valid
+    {
+        Ok(())
+    }
+    else
+    {
+        println!("Invalid headers");
+        Err(Misbehaviour::InvalidHeaders)
+    }
+}
+
+pub fn rcv_getb(
+    hash: &[u8; 32],
+    addr: &SocketAddr,
+    ctx: &mut NodeContext)
+{
+    let ip = canonical_ip(addr.ip()).to_string();
+    let port = addr.port() as i32;
 
-            match peers.iter_mut().position(|p| p.ip == ip && p.port == port)
+    // ONLY ANSWERED OVER A LINK WE ALREADY HAVE; A REQUEST NEVER MAKES US DIAL
+    match ctx.peers.iter_mut().find(|p| p.ip == ip && p.port == port)
+    {
+        Some(peer) =>
+        {
+            match ctx.db.full_block(hash)
             {
-                Some(peer_idx) =>
+                Some(ref block) if !serves_block(block, ctx.bandwidth, ctx.network_time) =>
                 {
-                    let mut stream = peers[peer_idx].clone().socket.unwrap();
+                    println!("Upload budget spent, not serving block to {}", format_addr(&ip, port));
+                }
+                Some(block) => { peer.send(&NetworkMessage::AddBlock(block).to_msg()); }
+                None => {}
+            }
+        }
+        None => {}
+    }
+}
+
+// ONCE THE UPLOAD BUDGET IS SPENT WE ONLY SERVE BLOCKS NEAR THE TIP, WHICH
+// PEERS NEED TO KEEP UP, AND NOT THE HISTORY A NEW NODE SYNCS FROM
+fn serves_block(
+    block: &Block,
+    bandwidth: &Bandwidth,
+    network_time: &NetworkTime) -> bool
+{
+    !bandwidth.upload_exhausted() || network_time.now() - block.timestamp <= RECENT_BLOCKS * TARGET_FREQ
+}
+
+pub fn publish_block(
+    block: Block,
+    peers: &mut Vec<Peer>,
+    clients: &mut HashMap<Token, Client>)
+{
+    relay_block(&block, peers, clients);
+}
+
+// BLOCKS GO OUT AS COMPACT BLOCKS RATHER THAN AN inv, SINCE PEERS ALREADY HOLD
+// MOST OF THE TRANSACTIONS AND CAN USUALLY REBUILD THEM WITHOUT A ROUND TRIP.
+// LIGHT CLIENTS HOLD NONE OF THEM, SO THEY GET AN inv AND ASK FOR A
+// merkleblock INSTEAD.
+fn relay_block(
+    block: &Block,
+    peers: &mut Vec<Peer>,
+    clients: &mut HashMap<Token, Client>)
+{
+    let msg = NetworkMessage::CompactBlock(CompactBlock::new(block)).to_msg();
+    let inv = NetworkMessage::Inv(vec![InvItem::new(InvKind::Block, &block.block_hash)]).to_msg();
+    for peer in peers.iter_mut()
+    {
+        if !peer.knows(&block.block_hash)
+        {
+            peer.mark_known(&block.block_hash);
+            if peer_filter(peer, clients).is_some() { peer.send(&inv); } else { peer.send(&msg); }
+        }
+    }
+}
+
+fn rcv_cmpctblock(
+    compact: CompactBlock,
+    ctx: &mut NodeContext) -> Result<(), Misbehaviour>
+{
+    println!("rcv_cmpctblock");
+
+    let token = ctx.token;
+    let hash = compact.header.block_hash;
+    match ctx.peers.iter_mut().find(|p| p.token == Some(token))
+    {
+        Some(peer) => { peer.mark_known(&hash); }
+        None => {}
+    }
+
+    if ctx.db.block(&hash).is_some() || ctx.sync.expects(&hash) || ctx.partial_blocks.contains(&hash)
+    {
+        return Ok(());
+    }
+    if !compact.header.verify_header()
+    {
+        println!("Invalid block");
+        return Err(Misbehaviour::InvalidBlock);
+    }
+
+    let partial = compact.reconstruct(&ctx.db.mempool_txs());
+    let missing = partial.missing();
+    if missing.is_empty()
+    {
+        match partial.finish()
+        {
+            Some(block) =>
+            {
+                mark_received(&hash, token, ctx.peers, ctx.inv_requests);
+                return accept_block(block, token, ctx.peers, ctx.clients, ctx.sync, ctx.db, ctx.block_snd_to_mine);
+            }
+            None => { request_full_block(&hash, token, ctx.peers, ctx.inv_requests); }
+        }
+    }
+    else
+    {
+        println!("Compact block {} missing {} of {} transactions", to_hex_string(&hash), missing.len(), compact.short_ids.len());
+        match ctx.peers.iter_mut().find(|p| p.token == Some(token))
+        {
+            Some(peer) =>
+            {
+                ctx.inv_requests.should_request(&hash, &peer.ip, peer.port);
+                peer.send(&NetworkMessage::GetBlockTxn(hash, missing).to_msg());
+            }
+            None => {}
+        }
+        ctx.partial_blocks.insert(token, partial);
+    }
+    Ok(())
+}
 
-                    let msg = Msg::new_validate_response(valid).to_vec();
-                    match stream.write(&msg)
+fn rcv_getblocktxn(
+    hash: [u8; 32],
+    indexes: Vec<u32>,
+    ctx: &mut NodeContext) -> Result<(), Misbehaviour>
+{
+    println!("rcv_getblocktxn");
+
+    let token = ctx.token;
+    match ctx.peers.iter_mut().find(|p| p.token == Some(token))
+    {
+        Some(peer) =>
+        {
+            match ctx.db.full_block(&hash)
+            {
+                Some(ref block) if !serves_block(block, ctx.bandwidth, ctx.network_time) =>
+                {
+                    println!("Upload budget spent, not serving block transactions to {}:{}", peer.ip, peer.port);
+                    peer.send(&NetworkMessage::NotFound(vec![InvItem::new(InvKind::Block, &hash)]).to_msg());
+                }
+                Some(block) =>
+                {
+                    let mut txs = vec![];
+                    for idx in indexes
                     {
-                        Ok(nbytes) => {
-                            println!("resp_vldt bytes written: {} at {}", nbytes, UTC::now().time());
-                        }
-                        Err(e) => {
-                            println!("Error writing to stream: {}", e);
+                        match block.txs.get(idx as usize)
+                        {
+                            Some(tx) => { txs.push(tx.clone()); }
+                            None => { return Err(Misbehaviour::MalformedMessage); }
                         }
                     }
+                    peer.send(&NetworkMessage::BlockTxn(hash, txs).to_msg());
                 }
-                None => {}
+                None => { peer.send(&NetworkMessage::NotFound(vec![InvItem::new(InvKind::Block, &hash)]).to_msg()); }
             }
         }
+        None => {}
     }
+    Ok(())
 }
 
-pub fn rcv_resp_vldt(
-    payload: &[u8])
+fn rcv_blocktxn(
+    hash: [u8; 32],
+    txs: Vec<Transaction>,
+    ctx: &mut NodeContext) -> Result<(), Misbehaviour>
 {
-    let valid = LittleEndian::read_i32(&payload);
-    println!("Valid received: {}", valid);
+    println!("rcv_blocktxn");
+
+    let token = ctx.token;
+    let mut partial = match ctx.partial_blocks.take(token, &hash)
+    {
+        Some(partial) => { partial }
+        None => { return Ok(()); }
+    };
+
+    if !partial.fill(txs)
+    {
+        request_full_block(&hash, token, ctx.peers, ctx.inv_requests);
+        return Err(Misbehaviour::MalformedMessage);
+    }
+    match partial.finish()
+    {
+        Some(block) =>
+        {
+            mark_received(&hash, token, ctx.peers, ctx.inv_requests);
+            accept_block(block, token, ctx.peers, ctx.clients, ctx.sync, ctx.db, ctx.block_snd_to_mine)
+        }
+        None =>
+        {
+            request_full_block(&hash, token, ctx.peers, ctx.inv_requests);
+            Ok(())
+        }
+    }
 }
 
-pub fn rcv_addb(
-    payload: &[u8],
-    db: &Connection,
-    block_snd_to_mine: &Sender<Block>)
+// WHEN A COMPACT BLOCK CAN'T BE REBUILT, GET THE WHOLE BLOCK INSTEAD
+fn request_full_block(
+    hash: &[u8; 32],
+    token: Token,
+    peers: &mut Vec<Peer>,
+    inv_requests: &mut InvRequests)
 {
-    println!("rcv_addb");
-    let mut block = Block::from_slice(payload);
-    if block.verify()
+    println!("Couldn't rebuild block {}, fetching it in full", to_hex_string(hash));
+    match peers.iter_mut().find(|p| p.token == Some(token))
     {
-        if database::insert_block(&block, db).is_ok()
+        Some(peer) =>
         {
-            let _ = block_snd_to_mine.send(block);
+            inv_requests.should_request(hash, &peer.ip, peer.port);
+            peer.send(&NetworkMessage::GetData(vec![InvItem::new(InvKind::Block, hash)]).to_msg());
         }
+        None => {}
     }
-    else
+}
+
+// CLEARS THE REQUEST IF IT CAME FROM THE PEER WE ASKED
+fn mark_received(
+    hash: &[u8; 32],
+    token: Token,
+    peers: &[Peer],
+    inv_requests: &mut InvRequests)
+{
+    match peers.iter().find(|p| p.token == Some(token))
     {
-        println!("Invalid block");
+        Some(peer) => { inv_requests.received(hash, &peer.ip, peer.port); }
+        None => {}
     }
 }
 
-pub fn rcv_getb(
-    payload: &[u8],
-    server: &TcpListener,
+// ANNOUNCE A TRANSACTION TO EVERY PEER THAT ISN'T ALREADY KNOWN TO HAVE IT;
+// THEY'LL ASK FOR IT WITH getdata IF THEY WANT IT. PEERS WITH A FILTER ONLY
+// HEAR OF TRANSACTIONS THAT MATCH IT.
+fn relay_transaction(
+    tx: &Transaction,
     peers: &mut Vec<Peer>,
-    db: &Connection)
+    clients: &mut HashMap<Token, Client>)
+{
+    let msg = NetworkMessage::Inv(vec![InvItem::new(InvKind::Transaction, &tx.hash)]).to_msg();
+    for peer in peers.iter_mut()
+    {
+        let wanted = match peer_filter(peer, clients)
+        {
+            Some(filter) => { filter.matches(tx) }
+            None => { true }
+        };
+        if wanted && !peer.knows(&tx.hash)
+        {
+            peer.mark_known(&tx.hash);
+            peer.send(&msg);
+        }
+    }
+}
+
+// A LIGHT CLIENT LOADS ITS FILTER ON THE CONNECTION IT WRITES TO US ON, SO
+// IT'S KEPT ON THAT CLIENT RATHER THAN THE PEER WE WRITE BACK ON
+fn peer_filter<'a>(
+    peer: &Peer,
+    clients: &'a mut HashMap<Token, Client>) -> Option<&'a mut BloomFilter>
+{
+    match peer.token
+    {
+        Some(token) => { clients.get_mut(&token).and_then(|client| client.filter.as_mut()) }
+        None => { None }
+    }
+}
+
+fn rcv_inv(
+    items: Vec<InvItem>,
+    ctx: &mut NodeContext)
+{
+    println!("rcv_inv");
+
+    let token = ctx.token;
+    match ctx.peers.iter_mut().find(|p| p.token == Some(token))
+    {
+        Some(peer) =>
+        {
+            let mut wanted = vec![];
+            for item in items.iter()
+            {
+                peer.mark_known(&item.hash);
+                let have = match item.kind
+                {
+                    InvKind::Block => { ctx.db.block(&item.hash).is_some() || ctx.sync.expects(&item.hash) }
+                    InvKind::Transaction => { ctx.db.transaction(&item.hash).is_some() }
+                    // ONLY EVER ASKED FOR, NEVER ANNOUNCED
+                    InvKind::FilteredBlock => { true }
+                };
+                if !have && ctx.inv_requests.should_request(&item.hash, &peer.ip, peer.port)
+                {
+                    wanted.push(*item);
+                }
+            }
+            if !wanted.is_empty()
+            {
+                peer.send(&NetworkMessage::GetData(wanted).to_msg());
+            }
+        }
+        None => {}
+    }
+}
+
+// ANNOUNCES EVERY PENDING TRANSACTION SO A NEW PEER MINES ON THE SAME SET;
+// IT FETCHES THE ONES IT LACKS WITH getdata
+fn rcv_mempool(
+    ctx: &mut NodeContext)
+{
+    println!("rcv_mempool");
+
+    let token = ctx.token;
+    let db = ctx.db;
+    match ctx.peers.iter_mut().find(|p| p.token == Some(token))
+    {
+        Some(peer) =>
+        {
+            let mut hashes: Vec<[u8; 32]> = db.mempool_hashes()
+                .into_iter()
+                .filter(|hash| !peer.knows(hash))
+                .collect();
+            if let Some(filter) = ctx.clients.get_mut(&token).and_then(|client| client.filter.as_mut())
+            {
+                hashes.retain(|hash| db.transaction(hash).map_or(false, |tx| filter.matches(&tx)));
+            }
+            let items: Vec<InvItem> = hashes
+                .iter()
+                .map(|hash| InvItem::new(InvKind::Transaction, hash))
+                .collect();
+            for chunk in items.chunks(MAX_INV_ITEMS)
+            {
+                for item in chunk.iter()
+                {
+                    peer.mark_known(&item.hash);
+                }
+                peer.send(&NetworkMessage::Inv(chunk.to_vec()).to_msg());
+            }
+        }
+        None => {}
+    }
+}
+
+// REPLACES ANY FILTER ALREADY LOADED. THE FILTER BELONGS TO THE CONNECTION,
+// WHETHER OR NOT WE HAVE A PEER TO WRITE BACK ON YET.
+fn rcv_filterload(
+    filter: BloomFilter,
+    ctx: &mut NodeContext)
+{
+    println!("rcv_filterload");
+
+    let token = ctx.token;
+    match ctx.clients.get_mut(&token)
+    {
+        Some(client) => { client.filter = Some(filter); }
+        None => { return; }
+    }
+    if ctx.peer().is_none()
+    {
+        println!("Connection {:?} loaded a filter but has no peer to send matches to", token);
+    }
+}
+
+fn rcv_filteradd(
+    element: &[u8],
+    ctx: &mut NodeContext) -> Result<(), Misbehaviour>
 {
-    let payload_cmpts: Vec<&[u8]> = payload.split({|x| *x == ',' as u8}).collect();
-    if payload_cmpts.len() > 1
+    println!("rcv_filteradd");
+
+    let token = ctx.token;
+    match ctx.clients.get_mut(&token)
     {
-        let hash = payload_cmpts[0];
-        let addr = payload_cmpts[1];
-        let addr_cmpts: Vec<&[u8]> = addr.split({|x| *x == ':' as u8}).collect();
-        if addr_cmpts.len() > 1
+        Some(client) =>
         {
-            let ip = String::from_utf8(addr_cmpts[0].to_vec()).unwrap();
-            let port = String::from_utf8(addr_cmpts[1].to_vec()).unwrap().parse::<i32>().unwrap();
+            match client.filter
+            {
+                Some(ref mut filter) => { filter.insert(element); }
+                None => { return Err(Misbehaviour::MalformedMessage); }
+            }
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+fn rcv_filterclear(
+    ctx: &mut NodeContext)
+{
+    println!("rcv_filterclear");
 
-            add_peer(ip.clone(), port, server, peers, db);
+    let token = ctx.token;
+    match ctx.clients.get_mut(&token)
+    {
+        Some(client) => { client.filter = None; }
+        None => {}
+    }
+}
 
-            let peer_idx = peers.iter_mut().position(|p| p.ip == ip && p.port == port).unwrap();
+fn rcv_getdata(
+    items: Vec<InvItem>,
+    ctx: &mut NodeContext)
+{
+    println!("rcv_getdata");
 
-            match database::block(&hash, db)
+    let token = ctx.token;
+    let (bandwidth, network_time) = (ctx.bandwidth, ctx.network_time);
+    match ctx.peers.iter_mut().find(|p| p.token == Some(token))
+    {
+        Some(peer) =>
+        {
+            let mut not_found = vec![];
+            for item in items.iter()
             {
-                Some(block) => {
-                    let msg = Msg::new_add_block(block.to_vec()).to_vec();
-                    let mut stream = peers[peer_idx].clone().socket.unwrap();
-                    match stream.write(&msg)
+                let block = match item.kind
+                {
+                    InvKind::Block | InvKind::FilteredBlock => { ctx.db.full_block(&item.hash) }
+                    InvKind::Transaction => { None }
+                };
+                if block.as_ref().map_or(false, |block| !serves_block(block, bandwidth, network_time))
+                {
+                    println!("Upload budget spent, not serving block to {}:{}", peer.ip, peer.port);
+                    not_found.push(*item);
+                    continue;
+                }
+                let msg = match item.kind
+                {
+                    InvKind::Block => { block.map(NetworkMessage::AddBlock) }
+                    InvKind::Transaction => { ctx.db.transaction(&item.hash).map(NetworkMessage::AddTransaction) }
+                    InvKind::FilteredBlock =>
                     {
-                        Ok(nbytes) => {
-                            println!("Bytes written: {}", nbytes);
-                        }
-                        Err(e) => {
-                            println!("Error writing to stream: {}", e);
+                        match ctx.clients.get_mut(&token).and_then(|client| client.filter.as_mut())
+                        {
+                            Some(filter) => { block.map(|block| NetworkMessage::MerkleBlock(FilteredBlock::new(&block, filter))) }
+                            None => { None }
                         }
                     }
+                };
+                match msg
+                {
+                    Some(msg) =>
+                    {
+                        peer.mark_known(&item.hash);
+                        peer.send(&msg.to_msg());
+                    }
+                    None => { not_found.push(*item); }
                 }
-                None => {}
+            }
+            if !not_found.is_empty()
+            {
+                peer.send(&NetworkMessage::NotFound(not_found).to_msg());
             }
         }
+        None => {}
     }
 }
 
-pub fn publish_block(
-    block: Block,
-    peers: &[Peer])
+fn rcv_notfound(
+    items: Vec<InvItem>,
+    ctx: &mut NodeContext)
 {
-    let msg = Msg::new_add_block(block.to_vec());
-    for peer in peers.iter()
+    println!("rcv_notfound");
+
+    let token = ctx.token;
+    match ctx.peers.iter().find(|p| p.token == Some(token))
     {
-        let p = peer.clone();
-        let mut socket = p.socket.unwrap().try_clone().unwrap();
-        println!("writing block msg {:#?}", &msg.to_vec());
-        match socket.write(&msg.to_vec())
+        Some(peer) =>
         {
-            Ok(nbytes) => {
-                println!("Bytes written: {}", nbytes);
-            }
-            Err(e) => {
-                println!("Error writing to stream: {}", e);
+            for item in items.iter()
+            {
+                ctx.inv_requests.received(&item.hash, &peer.ip, peer.port);
+                ctx.sync.not_found(&item.hash, &peer.ip, peer.port);
             }
         }
-        let _ = socket.flush();
+        None => {}
     }
+    // ASK SOMEONE ELSE RATHER THAN WAIT FOR THE NEXT SYNC TICK
+    ctx.sync.request_blocks(ctx.peers);
 }
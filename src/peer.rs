@@ -1,5 +1,22 @@
 extern crate mio;
-use std::net;
+use self::mio::{Token};
+
+extern crate chrono;
+use self::chrono::*;
+
+use bandwidth::*;
+use bloom::*;
+use message::*;
+use handshake::*;
+use transport::*;
+
+use std::collections::{HashSet};
+use std::io;
+use std::io::{Error, ErrorKind, Read};
+use std::time::{Instant};
+
+const MAX_KNOWN_INVENTORY: usize = 50000;
+const RECEIVE_CHUNK: usize = 64 * 1024;
 
 #[derive(Debug)]
 pub struct Peer
@@ -7,7 +24,77 @@ pub struct Peer
     pub ip:       String,
     pub port:       i32,
     pub timestamp:  i64,
-    pub socket:     Option<net::TcpStream>
+    pub socket:     Option<Box<dyn Transport>>,
+    pub token:      Option<Token>,
+    pub known_inventory: HashSet<[u8; 32]>,
+    pub last_recv:  i64,
+    pub ping_nonce: Option<u64>,
+    pub ping_sent:  Option<Instant>,
+    pub latency_ms: Option<u64>,
+    pub inbound:    bool,
+    pub last_block: i64,
+    pub session:    Option<Session>,
+    pub identity:   Option<[u8; KEY_LEN]>,
+    pub traffic:    Traffic,
+    // THE NODE-WIDE COUNTERS; NONE FOR PEERS THAT AREN'T ON THE NETWORK
+    pub bandwidth:  Option<Bandwidth>
+}
+
+// AN ACCEPTED CONNECTION, WHICH WE ONLY EVER READ FROM
+pub struct Client
+{
+    pub socket:     Box<dyn Stream>,
+    pub ip:         String,
+    pub connected:  i64,
+    pub last_recv:  i64,
+    pub misbehaviour: u32,
+    pub handshake:  Option<Responder>,
+    pub session:    Option<Session>,
+    pub identity:   Option<[u8; KEY_LEN]>,
+    // SET BY A LIGHT CLIENT; WE THEN ONLY RELAY TO ITS PEER WHAT MATCHES
+    pub filter:     Option<BloomFilter>,
+    pub traffic:    Traffic,
+    // WHAT WE'VE READ BUT NOT YET DECODED, UP TO THE END OF A PARTIAL FRAME
+    pub received:   Vec<u8>
+}
+
+impl Client
+{
+    pub fn new(socket: Box<dyn Stream>, ip: String) -> Client
+    {
+        let now = UTC::now().timestamp();
+        Client {
+            socket: socket,
+            ip: ip,
+            connected: now,
+            last_recv: now,
+            misbehaviour: 0,
+            handshake: None,
+            session: None,
+            identity: None,
+            filter: None,
+            traffic: Traffic::new(),
+            received: vec![]
+        }
+    }
+
+    // READS WHAT THE SOCKET HAS INTO received. FALSE WHEN IT HAD NOTHING.
+    pub fn receive(&mut self) -> io::Result<bool>
+    {
+        let mut buf = [0; RECEIVE_CHUNK];
+        match self.socket.read(&mut buf)
+        {
+            Ok(0) => { Err(Error::new(ErrorKind::UnexpectedEof, "connection closed")) }
+            Ok(n) =>
+            {
+                self.received.extend_from_slice(&buf[..n]);
+                Ok(true)
+            }
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => { Ok(true) }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => { Ok(false) }
+            Err(e) => { Err(e) }
+        }
+    }
 }
 
 impl Clone for Peer
@@ -29,7 +116,20 @@ impl Clone for Peer
                     }
                 }
                 None => { None }
-            }
+            },
+            token: self.token,
+            known_inventory: self.known_inventory.clone(),
+            last_recv: self.last_recv,
+            ping_nonce: self.ping_nonce,
+            ping_sent: self.ping_sent,
+            latency_ms: self.latency_ms,
+            inbound: self.inbound,
+            last_block: self.last_block,
+            // A COPY OF THE SESSION WOULD REUSE NONCES, SO CLONES CAN'T SEND ENCRYPTED
+            session: None,
+            identity: self.identity,
+            traffic: self.traffic.clone(),
+            bandwidth: self.bandwidth.clone()
         }
     }
 }
@@ -40,13 +140,81 @@ impl Peer
         ip: String,
         port: i32,
         timestamp: i64,
-        socket: Option<net::TcpStream>) -> Peer
+        socket: Option<Box<dyn Transport>>) -> Peer
     {
         Peer {
             ip: ip,
             port: port,
             timestamp: timestamp,
-            socket: socket
+            socket: socket,
+            token: None,
+            known_inventory: HashSet::new(),
+            last_recv: UTC::now().timestamp(),
+            ping_nonce: None,
+            ping_sent: None,
+            latency_ms: None,
+            inbound: false,
+            last_block: 0,
+            session: None,
+            identity: None,
+            traffic: Traffic::new(),
+            bandwidth: None
         }
     }
+
+    pub fn knows(&self, hash: &[u8; 32]) -> bool
+    {
+        self.known_inventory.contains(hash)
+    }
+
+    pub fn mark_known(&mut self, hash: &[u8; 32])
+    {
+        if self.known_inventory.len() >= MAX_KNOWN_INVENTORY
+        {
+            self.known_inventory.clear();
+        }
+        self.known_inventory.insert(*hash);
+    }
+
+    // A FAILED WRITE DROPS THE SOCKET; THE KEEPALIVE TICK THEN REMOVES THE PEER
+    pub fn send(&mut self, msg: &Msg) -> bool
+    {
+        let sent = match self.socket
+        {
+            Some(ref mut socket) =>
+            {
+                let bytes = match self.session
+                {
+                    Some(ref mut session) => { session.seal(&msg.to_vec()) }
+                    None => { msg.to_vec() }
+                };
+                match socket.write_bytes(&bytes)
+                {
+                    Ok(_) => {
+                        self.traffic.record_sent(&msg.command, bytes.len());
+                        if let Some(ref bandwidth) = self.bandwidth
+                        {
+                            bandwidth.record_sent(&msg.command, bytes.len());
+                        }
+                        true
+                    }
+                    Err(e) => {
+                        println!("Error writing to stream: {}", e);
+                        false
+                    }
+                }
+            }
+            None => { false }
+        };
+        if !sent
+        {
+            self.socket = None;
+        }
+        sent
+    }
+
+    pub fn is_connected(&self) -> bool
+    {
+        self.socket.is_some()
+    }
 }
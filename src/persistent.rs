@@ -0,0 +1,134 @@
+extern crate chrono;
+extern crate rand;
+
+use self::chrono::*;
+
+use self::rand::{Rng};
+
+const BASE_BACKOFF: i64 = 5;
+const MAX_BACKOFF: i64 = 10 * 60;
+// A CONNECTION THAT DROPS SOONER THAN THIS COUNTS AS A FAILED ONE
+pub const MIN_CONNECTED_SECS: i64 = 60;
+
+struct PersistentPeer
+{
+    ip:             String,
+    port:           i32,
+    failures:       u32,
+    next_attempt:   i64,
+    connected_at:   Option<i64>
+}
+
+// PEERS WE ALWAYS WANT A CONNECTION TO, FROM --addnode OR THE addnode COMMAND.
+// AFTER EACH FAILED OR DROPPED CONNECTION WE WAIT TWICE AS LONG AS BEFORE, UP TO
+// MAX_BACKOFF, WITH JITTER SO A RESTARTED NODE ISN'T HIT BY EVERYONE AT ONCE.
+// ONLY A CONNECTION THAT STAYED UP FOR MIN_CONNECTED_SECS CLEARS THE BACKOFF,
+// SO A PEER THAT HANGS UP STRAIGHT AWAY ISN'T REDIALLED ON EVERY TICK.
+pub struct PersistentPeers
+{
+    peers: Vec<PersistentPeer>
+}
+
+impl PersistentPeers
+{
+    pub fn new() -> PersistentPeers
+    {
+        PersistentPeers {
+            peers: vec![]
+        }
+    }
+
+    pub fn add(&mut self, ip: &str, port: i32)
+    {
+        if self.contains(ip, port)
+        {
+            return;
+        }
+        println!("Added persistent peer {}:{}", ip, port);
+        self.peers.push(PersistentPeer {
+            ip: ip.to_string(),
+            port: port,
+            failures: 0,
+            next_attempt: 0,
+            connected_at: None
+        });
+    }
+
+    pub fn contains(&self, ip: &str, port: i32) -> bool
+    {
+        self.peers.iter().any(|p| p.ip == ip && p.port == port)
+    }
+
+    pub fn due(&self, now: i64) -> Vec<(String, i32)>
+    {
+        self.peers
+            .iter()
+            .filter(|p| p.next_attempt <= now)
+            .map(|p| (p.ip.clone(), p.port))
+            .collect()
+    }
+
+    // THE ONES WE HAVE A CONNECTION TO, AS FAR AS WE KNOW
+    pub fn connections(&self) -> Vec<(String, i32)>
+    {
+        self.peers
+            .iter()
+            .filter(|p| p.connected_at.is_some())
+            .map(|p| (p.ip.clone(), p.port))
+            .collect()
+    }
+
+    pub fn connected(&mut self, ip: &str, port: i32, now: i64)
+    {
+        if let Some(peer) = self.peers.iter_mut().find(|p| p.ip == ip && p.port == port)
+        {
+            peer.connected_at = Some(now);
+        }
+    }
+
+    pub fn disconnected(&mut self, ip: &str, port: i32, now: i64)
+    {
+        let lasted = match self.peers.iter_mut().find(|p| p.ip == ip && p.port == port)
+        {
+            Some(peer) =>
+            {
+                match peer.connected_at.take()
+                {
+                    Some(connected_at) if now - connected_at >= MIN_CONNECTED_SECS =>
+                    {
+                        peer.failures = 0;
+                        true
+                    }
+                    Some(_) => { false }
+                    None => { return; }
+                }
+            }
+            None => { return; }
+        };
+        if !lasted
+        {
+            println!("Connection to {}:{} dropped straight away", ip, port);
+            self.failed(ip, port);
+        }
+    }
+
+    pub fn failed(&mut self, ip: &str, port: i32)
+    {
+        let now = UTC::now().timestamp();
+        if let Some(peer) = self.peers.iter_mut().find(|p| p.ip == ip && p.port == port)
+        {
+            let delay = backoff(peer.failures);
+            println!("Retrying {}:{} in {}s", ip, port, delay);
+            peer.failures += 1;
+            peer.next_attempt = now + delay;
+        }
+    }
+}
+
+pub fn backoff(failures: u32) -> i64
+{
+    let delay = if failures >= 16 { MAX_BACKOFF } else { BASE_BACKOFF << failures };
+    let delay = if delay > MAX_BACKOFF { MAX_BACKOFF } else { delay };
+    let jitter = delay / 4;
+    delay - jitter + rand::thread_rng().gen_range(0, 2 * jitter + 1)
+}
@@ -0,0 +1,174 @@
+extern crate chrono;
+
+use self::chrono::*;
+
+use block::*;
+use peer::*;
+use transaction::*;
+use util::{to_hex_string};
+
+use std::collections::{HashMap};
+use std::fmt;
+use std::sync::mpsc;
+
+pub type RequestId = u64;
+
+const QUERY_TIMEOUT: i64 = 10;
+
+// QUESTIONS WE CAN PUT TO A PEER. EACH GOES OUT WITH A REQUEST ID THAT THE
+// PEER ECHOES IN ITS resp, WHICH IS HOW THE ANSWER FINDS ITS WAY BACK.
+#[derive(Clone)]
+pub enum Query
+{
+    Balance([u8; 32]),
+    Validate(Transaction),
+    Height,
+    Latest,
+    ListBlocks(u64, u32),
+    Echo(Vec<u8>)
+}
+
+#[derive(Clone, PartialEq)]
+pub enum Response
+{
+    Balance(i64),
+    Valid(bool),
+    Height(i64),
+    Latest(Option<Block>),
+    BlockHashes(u64, Vec<[u8; 32]>),
+    Echo(Vec<u8>)
+}
+
+impl fmt::Display for Response
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self
+        {
+            Response::Balance(balance) => { write!(f, "Balance: {}", balance) }
+            Response::Valid(valid) => { write!(f, "Valid: {}", valid) }
+            Response::Height(height) => { write!(f, "Height: {}", height) }
+            Response::Latest(None) => { write!(f, "Latest block: none") }
+            Response::Latest(Some(ref header)) => { write!(f, "Latest block: {} at {}", to_hex_string(&header.block_hash), header.timestamp) }
+            Response::BlockHashes(start, ref hashes) =>
+            {
+                write!(f, "Block hashes from height {}:", start)?;
+                for (i, hash) in hashes.iter().enumerate()
+                {
+                    write!(f, "\n{} {}", start + i as u64, to_hex_string(hash))?;
+                }
+                Ok(())
+            }
+            Response::Echo(ref bytes) => { write!(f, "Echo: {}", String::from_utf8_lossy(bytes)) }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueryError
+{
+    NoPeers,
+    Disconnected,
+    Timeout
+}
+
+pub type QueryResult = Result<Response, QueryError>;
+
+// CALLED ON THE NETWORK THREAD ONCE, WITH THE ANSWER OR WHY THERE ISN'T ONE
+pub type Callback = Box<dyn FnOnce(QueryResult) + Send>;
+
+// FOR CALLERS THAT WOULD RATHER WAIT ON THE ANSWER THAN BE CALLED BACK
+pub fn callback_channel() -> (Callback, mpsc::Receiver<QueryResult>)
+{
+    let (snd, rcv) = mpsc::channel();
+    let callback: Callback = Box::new(move |result| { let _ = snd.send(result); });
+    (callback, rcv)
+}
+
+struct PendingRequest
+{
+    ip:         String,
+    port:       i32,
+    deadline:   i64,
+    callback:   Callback
+}
+
+pub struct PendingRequests
+{
+    next_id:    RequestId,
+    pending:    HashMap<RequestId, PendingRequest>
+}
+
+impl PendingRequests
+{
+    pub fn new() -> PendingRequests
+    {
+        PendingRequests {
+            next_id: 1,
+            pending: HashMap::new()
+        }
+    }
+
+    pub fn register(&mut self, peer: &Peer, callback: Callback) -> RequestId
+    {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.insert(id, PendingRequest {
+            ip: peer.ip.clone(),
+            port: peer.port,
+            deadline: UTC::now().timestamp() + QUERY_TIMEOUT,
+            callback: callback
+        });
+        id
+    }
+
+    // FALSE FOR IDS WE DIDN'T ISSUE, THAT ALREADY TIMED OUT, OR THAT WERE SENT
+    // TO A DIFFERENT PEER THAN THE ONE ANSWERING
+    pub fn complete(&mut self, id: RequestId, peer: &Peer, response: Response) -> bool
+    {
+        match self.pending.get(&id)
+        {
+            Some(request) if request.ip == peer.ip && request.port == peer.port => {}
+            _ => { return false; }
+        }
+        match self.pending.remove(&id)
+        {
+            Some(request) =>
+            {
+                (request.callback)(Ok(response));
+                true
+            }
+            None => { false }
+        }
+    }
+
+    // FAILS REQUESTS THAT RAN OUT OF TIME OR WHOSE PEER WENT AWAY
+    pub fn expire(&mut self, peers: &[Peer])
+    {
+        let now = UTC::now().timestamp();
+        let failed: Vec<(RequestId, QueryError)> = self.pending
+            .iter()
+            .filter_map(|(id, request)| {
+                if !peers.iter().any(|p| p.ip == request.ip && p.port == request.port && p.is_connected())
+                {
+                    Some((*id, QueryError::Disconnected))
+                }
+                else if now > request.deadline
+                {
+                    Some((*id, QueryError::Timeout))
+                }
+                else
+                {
+                    None
+                }
+            })
+            .collect();
+        for (id, error) in failed
+        {
+            if let Some(request) = self.pending.remove(&id)
+            {
+                (request.callback)(Err(error));
+            }
+        }
+    }
+}
@@ -0,0 +1,518 @@
+extern crate mio;
+extern crate rand;
+
+use self::mio::{Token};
+use self::mio::channel::{channel, Sender};
+
+use self::rand::{Rng, SeedableRng, XorShiftRng};
+
+use addrman::*;
+use ban::*;
+use bandwidth::*;
+use block::*;
+use chat::*;
+use compact::*;
+use config::*;
+use database::{DatabaseInsertionError, Store};
+use dial::*;
+use gcs::*;
+use handler::*;
+use handshake::*;
+use inventory::*;
+use message::*;
+use network::*;
+use peer::*;
+use query::*;
+use sync::*;
+use timedata::*;
+use transaction::*;
+use transport::*;
+
+use std::cell::{RefCell};
+use std::collections::{HashMap};
+
+// A WHOLE NETWORK IN ONE PROCESS. EVERY NODE RUNS THE REAL HANDLERS AGAINST
+// A MemoryStore, AND TALKS THROUGH MemoryTransports. EVERY PACKET WAITS IN
+// in_flight UNTIL THE VIRTUAL CLOCK REACHES ITS DELIVERY TIME, SO LATENCY,
+// DROPS AND PARTITIONS ALL COME FROM ONE SEEDED RNG AND A RUN CAN BE REPEATED
+// EXACTLY.
+
+const MAX_STEPS: usize = 100000;
+
+// THE blocks, transactions, block_filters AND bans TABLES, KEPT IN MEMORY
+pub struct MemoryStore
+{
+    // IN THE ORDER THEY WERE STORED
+    blocks:     RefCell<Vec<Block>>,
+    txs:        RefCell<Vec<Transaction>>,
+    filters:    RefCell<HashMap<[u8; 32], GcsFilter>>,
+    pub bans:   RefCell<HashMap<String, i64>>
+}
+
+impl MemoryStore
+{
+    pub fn new() -> MemoryStore
+    {
+        MemoryStore {
+            blocks: RefCell::new(vec![]),
+            txs: RefCell::new(vec![]),
+            filters: RefCell::new(HashMap::new()),
+            bans: RefCell::new(HashMap::new())
+        }
+    }
+
+    fn in_block(&self, hash: &[u8; 32]) -> bool
+    {
+        self.blocks.borrow().iter().any(|b| b.txs.iter().any(|tx| tx.hash == *hash))
+    }
+}
+
+impl Store for MemoryStore
+{
+    // LIKE THE blocks TABLE, ORDERED BY TIMESTAMP AND WITHOUT TRANSACTIONS
+    fn blockchain(&self) -> Vec<Block>
+    {
+        let mut blocks: Vec<Block> = self.blocks.borrow().iter().map(|b| {
+            let mut header = b.clone();
+            header.txs = vec![];
+            header
+        }).collect();
+        blocks.sort_by_key(|b| b.timestamp);
+        blocks
+    }
+
+    fn latest_block(&self) -> Option<Block>
+    {
+        self.blockchain().pop()
+    }
+
+    fn block(&self, hash: &[u8]) -> Option<Block>
+    {
+        self.full_block(hash).map(|mut block| {
+            block.txs = vec![];
+            block
+        })
+    }
+
+    fn full_block(&self, hash: &[u8]) -> Option<Block>
+    {
+        self.blocks.borrow().iter().find(|b| b.block_hash[..] == *hash).cloned()
+    }
+
+    fn insert_block(&self, block: &Block) -> Result<(), DatabaseInsertionError>
+    {
+        if self.full_block(&block.block_hash).is_some()
+        {
+            return Err(DatabaseInsertionError::ValueExists);
+        }
+        self.blocks.borrow_mut().push(block.clone());
+        self.insert_block_filter(&block.block_hash, &GcsFilter::for_block(block));
+        for tx in block.txs.iter()
+        {
+            let _ = self.insert_transaction(tx);
+        }
+        Ok(())
+    }
+
+    fn block_filter(&self, hash: &[u8]) -> Option<GcsFilter>
+    {
+        self.filters.borrow().iter().find(|&(h, _)| h[..] == *hash).map(|(_, filter)| filter.clone())
+    }
+
+    fn insert_block_filter(&self, hash: &[u8], filter: &GcsFilter)
+    {
+        let mut key = [0; 32];
+        key.clone_from_slice(hash);
+        self.filters.borrow_mut().entry(key).or_insert_with(|| filter.clone());
+    }
+
+    fn transaction(&self, hash: &[u8]) -> Option<Transaction>
+    {
+        self.txs.borrow().iter().find(|tx| tx.hash[..] == *hash).cloned()
+    }
+
+    fn insert_transaction(&self, tx: &Transaction) -> Result<(), DatabaseInsertionError>
+    {
+        if self.transaction(&tx.hash).is_some()
+        {
+            return Err(DatabaseInsertionError::ValueExists);
+        }
+        self.txs.borrow_mut().push(tx.clone());
+        Ok(())
+    }
+
+    fn mempool_hashes(&self) -> Vec<[u8; 32]>
+    {
+        self.mempool_txs().iter().map(|tx| tx.hash).collect()
+    }
+
+    fn mempool_txs(&self) -> Vec<Transaction>
+    {
+        self.txs.borrow().iter().filter(|tx| !self.in_block(&tx.hash)).cloned().collect()
+    }
+
+    fn unspent_outputs(&self, public_key: &[u8]) -> Vec<TxOutput>
+    {
+        let txs = self.txs.borrow();
+        let mut unspent = vec![];
+        for tx in txs.iter().filter(|tx| self.in_block(&tx.hash))
+        {
+            for (idx, txo) in tx.outputs.iter().enumerate()
+            {
+                let spent = txs.iter().any(|t| t.inputs.iter().any(|txi| txi.src_hash == tx.hash && txi.src_idx == idx as i64));
+                if txo.address[..] == *public_key && !spent
+                {
+                    unspent.push(txo.clone());
+                }
+            }
+        }
+        unspent
+    }
+
+    fn upsert_ban(&self, ip: &str, expiry: i64)
+    {
+        self.bans.borrow_mut().insert(ip.to_string(), expiry);
+    }
+}
+
+// EVERYTHING start_server KEEPS FOR ONE NODE. A NODE'S PEER AND CLIENT FOR
+// NODE i BOTH USE Token(i) AND THE ADDRESS 10.0.0.i:i.
+pub struct SimNode
+{
+    pub clients:            HashMap<Token, Client>,
+    pub peers:              Vec<Peer>,
+    pub addrman:            AddrMan,
+    pub bans:               BanList,
+    pub sync:               HeaderSync,
+    pub inv_requests:       InvRequests,
+    pub partial_blocks:     PartialBlocks,
+    pub chat_log:           ChatLog,
+    pub pending_requests:   PendingRequests,
+    pub config:             Config,
+    pub identity:           Identity,
+    pub db:                 MemoryStore,
+    pub handlers:           HandlerRegistry,
+    pub bandwidth:          Bandwidth,
+    pub network_time:       NetworkTime,
+    // THE SIMULATOR LINKS NODES ITSELF, SO THESE ARE NEVER DIALED
+    pub dial_requests:      Vec<DialRequest>,
+    transaction_snd_to_mine: Sender<Transaction>,
+    block_snd_to_mine:      Sender<Block>
+}
+
+impl SimNode
+{
+    pub fn new(genesis: &Block) -> SimNode
+    {
+        let config = Config::new();
+        let db = MemoryStore::new();
+        let _ = db.insert_block(genesis);
+        // NOTHING MINES IN THE SIMULATOR, SO WHAT WOULD GO TO THE MINER IS DROPPED
+        let (transaction_snd_to_mine, _) = channel::<Transaction>();
+        let (block_snd_to_mine, _) = channel::<Block>();
+        SimNode {
+            clients: HashMap::new(),
+            peers: vec![],
+            addrman: AddrMan::new(),
+            bans: BanList::new(),
+            sync: HeaderSync::new(),
+            inv_requests: InvRequests::new(),
+            partial_blocks: PartialBlocks::new(),
+            chat_log: ChatLog::new(),
+            pending_requests: PendingRequests::new(),
+            bandwidth: Bandwidth::new(config.upload_budget, config.upload_window),
+            config: config,
+            identity: Identity::generate(),
+            db: db,
+            handlers: default_handlers(),
+            network_time: NetworkTime::new(),
+            dial_requests: vec![],
+            transaction_snd_to_mine: transaction_snd_to_mine,
+            block_snd_to_mine: block_snd_to_mine
+        }
+    }
+
+    // WHAT start_server DOES WHEN token's CONNECTION BECOMES READABLE
+    pub fn handle(&mut self, token: Token)
+    {
+        let (mut ctx, handlers) = self.context(token);
+        handle_message(&mut ctx, handlers);
+    }
+
+    // THE CONTEXT A MESSAGE ON token's CONNECTION IS HANDLED IN, AND THE
+    // HANDLERS TO HAND IT TO
+    pub fn context<'a>(&'a mut self, token: Token) -> (NodeContext<'a>, &'a mut HandlerRegistry)
+    {
+        let ctx = NodeContext {
+            token: token,
+            clients: &mut self.clients,
+            peers: &mut self.peers,
+            addrman: &mut self.addrman,
+            bans: &mut self.bans,
+            sync: &mut self.sync,
+            inv_requests: &mut self.inv_requests,
+            partial_blocks: &mut self.partial_blocks,
+            chat_log: &mut self.chat_log,
+            pending_requests: &mut self.pending_requests,
+            config: &self.config,
+            identity: &self.identity,
+            db: &self.db,
+            transaction_snd_to_mine: &self.transaction_snd_to_mine,
+            block_snd_to_mine: &self.block_snd_to_mine,
+            bandwidth: &self.bandwidth,
+            network_time: &self.network_time,
+            dial_requests: &mut self.dial_requests
+        };
+        (ctx, &mut self.handlers)
+    }
+
+    pub fn has_block(&self, hash: &[u8; 32]) -> bool
+    {
+        self.db.block(hash).is_some()
+    }
+
+    pub fn tip(&self) -> [u8; 32]
+    {
+        main_chain(&self.db).last().map_or([0; 32], |b| b.block_hash)
+    }
+
+    pub fn height(&self) -> usize
+    {
+        main_chain(&self.db).len() - 1
+    }
+}
+
+pub fn sim_ip(node: usize) -> String
+{
+    format!("10.0.0.{}", node)
+}
+
+struct InFlight
+{
+    deliver_at: u64,
+    seq:        u64,
+    packet:     Packet
+}
+
+pub struct Simulation
+{
+    pub nodes:      Vec<SimNode>,
+    pub now:        u64,
+    pub delivered:  usize,
+    pub dropped:    usize,
+    outbox:         Outbox,
+    // THE READ END OF EVERY LINK, BY (WRITER, READER)
+    streams:        HashMap<(usize, usize), MemoryStream>,
+    in_flight:      Vec<InFlight>,
+    seq:            u64,
+    rng:            XorShiftRng,
+    // NODES ONLY HEAR EACH OTHER WHILE THEY ARE IN THE SAME GROUP
+    groups:         Vec<usize>,
+    latency:        (u64, u64),
+    drop_rate:      f64,
+    mined:          i64
+}
+
+impl Simulation
+{
+    pub fn new(n: usize, seed: u64) -> Simulation
+    {
+        let mut genesis = Block::new_minable(vec![], &[0; 32], &[0xff; 32], 0);
+        genesis.update_hash();
+        Simulation {
+            nodes: (0..n).map(|_| SimNode::new(&genesis)).collect(),
+            now: 0,
+            delivered: 0,
+            dropped: 0,
+            outbox: Outbox::new(),
+            streams: HashMap::new(),
+            in_flight: vec![],
+            seq: 0,
+            rng: XorShiftRng::from_seed([seed as u32, (seed >> 32) as u32, 0x9e3779b9, 0x243f6a88]),
+            groups: vec![0; n],
+            latency: (1, 1),
+            drop_rate: 0.0,
+            mined: 0
+        }
+    }
+
+    // EACH NODE CONNECTED TO THE NEXT
+    pub fn line(n: usize, seed: u64) -> Simulation
+    {
+        let mut sim = Simulation::new(n, seed);
+        for i in 1..n
+        {
+            sim.connect(i - 1, i);
+        }
+        sim
+    }
+
+    // BOTH NODES END UP AS THEY WOULD AFTER DIALING EACH OTHER AND
+    // EXCHANGING addp: A PEER TO WRITE TO AND A CLIENT TO READ FROM
+    pub fn connect(&mut self, a: usize, b: usize)
+    {
+        for &(from, to) in [(a, b), (b, a)].iter()
+        {
+            let mut peer = Peer::new(
+                sim_ip(to),
+                to as i32,
+                0,
+                Some(Box::new(self.outbox.transport(from, to))));
+            peer.token = Some(Token(to));
+            self.nodes[from].peers.push(peer);
+
+            let stream = MemoryStream::new(&self.outbox, to, from);
+            self.nodes[to].clients.insert(Token(from), Client::new(Box::new(stream.clone()), sim_ip(from)));
+            self.streams.insert((from, to), stream);
+        }
+    }
+
+    // EVERY PACKET TAKES BETWEEN min AND max TICKS, INCLUSIVE
+    pub fn set_latency(&mut self, min: u64, max: u64)
+    {
+        self.latency = (min, max);
+    }
+
+    pub fn set_drop_rate(&mut self, rate: f64)
+    {
+        self.drop_rate = rate;
+    }
+
+    // NODES NOT LISTED STAY IN GROUP 0 WITH WHOEVER IS IN THE FIRST GROUP
+    pub fn partition(&mut self, groups: &[&[usize]])
+    {
+        self.groups = vec![0; self.nodes.len()];
+        for (g, group) in groups.iter().enumerate()
+        {
+            for &node in group.iter()
+            {
+                self.groups[node] = g;
+            }
+        }
+    }
+
+    pub fn heal(&mut self)
+    {
+        self.groups = vec![0; self.nodes.len()];
+    }
+
+    // A NEW BLOCK ON THE NODE'S CURRENT TIP, WITHOUT PROOF OF WORK, HANDED TO
+    // THE NETWORK THE WAY THE MINER HANDS IT TO start_server
+    pub fn mine(&mut self, node: usize) -> [u8; 32]
+    {
+        self.mined += 1;
+        let parent = self.nodes[node].tip();
+        let mut block = Block::new_minable(vec![], &parent, &[0xff; 32], self.mined);
+        block.timestamp = self.now as i64;
+        block.update_hash();
+        let hash = block.block_hash;
+        let _ = self.nodes[node].db.insert_block(&block);
+        let miner = &mut self.nodes[node];
+        publish_block(block, &mut miner.peers, &mut miner.clients);
+        self.collect();
+        hash
+    }
+
+    // AS IF from HAD WRITTEN msg TO to ITSELF
+    pub fn send(&mut self, from: usize, to: usize, msg: &Msg)
+    {
+        match self.nodes[from].peers.iter_mut().find(|p| p.token == Some(Token(to)))
+        {
+            Some(peer) => { peer.send(msg); }
+            None => {}
+        }
+        self.collect();
+    }
+
+    // RAW BYTES IN ONE PACKET, SO A MESSAGE CAN BE SPLIT ACROSS SEVERAL
+    pub fn send_bytes(&mut self, from: usize, to: usize, bytes: &[u8])
+    {
+        match self.nodes[from].peers.iter_mut().find(|p| p.token == Some(Token(to))).and_then(|p| p.socket.as_mut())
+        {
+            Some(socket) => { let _ = socket.write_bytes(bytes); }
+            None => {}
+        }
+        self.collect();
+    }
+
+    // DELIVERS THE NEXT PACKET; FALSE ONCE NOTHING IS IN FLIGHT
+    pub fn step(&mut self) -> bool
+    {
+        let next = (0..self.in_flight.len())
+            .min_by_key(|&i| (self.in_flight[i].deliver_at, self.in_flight[i].seq));
+        let next = match next
+        {
+            Some(i) => { self.in_flight.remove(i) }
+            None => { return false; }
+        };
+        self.now = next.deliver_at;
+
+        let packet = next.packet;
+        let connected = self.nodes[packet.to].clients.contains_key(&Token(packet.from));
+        if self.groups[packet.from] != self.groups[packet.to] || !connected
+        {
+            self.dropped += 1;
+            return true;
+        }
+        match self.streams.get(&(packet.from, packet.to))
+        {
+            Some(stream) => { stream.push(&packet.bytes); }
+            None => {}
+        }
+        self.delivered += 1;
+        self.nodes[packet.to].handle(Token(packet.from));
+        self.collect();
+        true
+    }
+
+    // RUNS UNTIL THE NETWORK GOES QUIET
+    pub fn run(&mut self)
+    {
+        let mut steps = 0;
+        while self.step()
+        {
+            steps += 1;
+            assert!(steps < MAX_STEPS, "simulation did not settle");
+        }
+    }
+
+    // WHAT from HAS WRITTEN TO to THAT HASN'T ARRIVED YET, IN ORDER
+    pub fn in_flight(&self, from: usize, to: usize) -> Vec<NetworkMessage>
+    {
+        let mut packets: Vec<&InFlight> = self.in_flight
+            .iter()
+            .filter(|f| f.packet.from == from && f.packet.to == to)
+            .collect();
+        packets.sort_by_key(|f| (f.deliver_at, f.seq));
+        packets
+            .iter()
+            .filter_map(|f| Msg::from_stream(&mut &f.packet.bytes[..]).ok())
+            .filter_map(|msg| NetworkMessage::from_msg(&msg).ok())
+            .collect()
+    }
+
+    pub fn tips(&self) -> Vec<[u8; 32]>
+    {
+        self.nodes.iter().map(|n| n.tip()).collect()
+    }
+
+    // MOVES WHAT THE NODES JUST WROTE INTO in_flight, DROPPING SOME AT RANDOM
+    fn collect(&mut self)
+    {
+        for packet in self.outbox.drain()
+        {
+            if self.drop_rate > 0.0 && self.rng.gen::<f64>() < self.drop_rate
+            {
+                self.dropped += 1;
+                continue;
+            }
+            let delay = self.rng.gen_range(self.latency.0, self.latency.1 + 1);
+            self.seq += 1;
+            self.in_flight.push(InFlight {
+                deliver_at: self.now + delay,
+                seq: self.seq,
+                packet: packet
+            });
+        }
+    }
+}
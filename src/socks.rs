@@ -0,0 +1,174 @@
+extern crate byteorder;
+
+use self::byteorder::{BigEndian, ByteOrder};
+
+use std::cmp;
+use std::io;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{IpAddr};
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+enum NegotiationState
+{
+    // OUR GREETING IS SENT AND WE'RE WAITING FOR THE PROXY TO PICK NO_AUTH
+    Choice,
+    // OUR CONNECT REQUEST IS SENT AND WE'RE WAITING FOR THE FIRST 5 BYTES OF
+    // THE REPLY, WHICH SAY HOW LONG THE REST IS
+    ReplyHead,
+    ReplyAddr(usize),
+    Done
+}
+
+// ASKS THE SOCKS5 PROXY ON THE OTHER END OF A STREAM, WITHOUT AUTHENTICATION,
+// TO CONNECT IT TO host:port. A HOSTNAME GOES TO THE PROXY UNRESOLVED, SO THE
+// LOOKUP HAPPENS ON THE FAR SIDE, WHICH IS WHAT .onion ADDRESSES NEED.
+// ON A NON-BLOCKING STREAM ready() IS CALLED ON EACH EVENT UNTIL IT RETURNS
+// TRUE; IT ONLY EVER READS WHAT THE PROXY OWES US, SO WHATEVER COMES AFTER THE
+// REPLY IS LEFT ON THE STREAM FOR THE CONNECTION ITSELF.
+pub struct Negotiation
+{
+    request:    Vec<u8>,
+    received:   Vec<u8>,
+    state:      NegotiationState
+}
+
+impl Negotiation
+{
+    pub fn start<S: Write>(stream: &mut S, host: &str, port: u16) -> io::Result<Negotiation>
+    {
+        let request = connect_request(host, port)?;
+        stream.write_all(&[SOCKS_VERSION, 1, NO_AUTH])?;
+        Ok(Negotiation {
+            request: request,
+            received: vec![],
+            state: NegotiationState::Choice
+        })
+    }
+
+    // TRUE ONCE THE PROXY HAS CONNECTED US
+    pub fn ready<S: Read + Write>(&mut self, stream: &mut S) -> io::Result<bool>
+    {
+        loop
+        {
+            let want = match self.state
+            {
+                NegotiationState::Choice => { 2 }
+                NegotiationState::ReplyHead => { 5 }
+                NegotiationState::ReplyAddr(len) => { len }
+                NegotiationState::Done => { return Ok(true); }
+            };
+            if !self.receive(stream, want)?
+            {
+                return Ok(false);
+            }
+            self.state = match self.state
+            {
+                NegotiationState::Choice =>
+                {
+                    if self.received[0] != SOCKS_VERSION || self.received[1] != NO_AUTH
+                    {
+                        return Err(Error::new(ErrorKind::Other, "proxy requires authentication"));
+                    }
+                    // A FEW BYTES AFTER THE PROXY'S ANSWER ALWAYS FIT THE SEND BUFFER
+                    stream.write_all(&self.request)?;
+                    NegotiationState::ReplyHead
+                }
+                NegotiationState::ReplyHead =>
+                {
+                    if self.received[0] != SOCKS_VERSION
+                    {
+                        return Err(Error::new(ErrorKind::InvalidData, "not a SOCKS5 proxy"));
+                    }
+                    if self.received[1] != 0
+                    {
+                        return Err(Error::new(ErrorKind::Other, reply_error(self.received[1])));
+                    }
+                    // THE ADDRESS AND PORT THE PROXY BOUND FOR US, WHICH WE DON'T
+                    // NEED. THE FIFTH BYTE IS THE ADDRESS'S FIRST, ALREADY READ,
+                    // OR A HOSTNAME'S LENGTH.
+                    match self.received[3]
+                    {
+                        ATYP_IPV4 => { NegotiationState::ReplyAddr(4 + 2 - 1) }
+                        ATYP_IPV6 => { NegotiationState::ReplyAddr(16 + 2 - 1) }
+                        ATYP_DOMAIN => { NegotiationState::ReplyAddr(self.received[4] as usize + 2) }
+                        _ => { return Err(Error::new(ErrorKind::InvalidData, "bad address type in proxy reply")); }
+                    }
+                }
+                NegotiationState::ReplyAddr(_) | NegotiationState::Done => { NegotiationState::Done }
+            };
+            self.received.clear();
+        }
+    }
+
+    // READS UNTIL received HOLDS want BYTES, OR THERE'S NOTHING MORE TO READ YET
+    fn receive<S: Read>(&mut self, stream: &mut S, want: usize) -> io::Result<bool>
+    {
+        let mut buf = [0; 256];
+        while self.received.len() < want
+        {
+            let missing = cmp::min(want - self.received.len(), buf.len());
+            match stream.read(&mut buf[..missing])
+            {
+                Ok(0) => { return Err(Error::new(ErrorKind::UnexpectedEof, "proxy closed the connection")); }
+                Ok(n) => { self.received.extend_from_slice(&buf[..n]); }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => { return Ok(false); }
+                Err(e) => { return Err(e); }
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn connect_request(host: &str, port: u16) -> io::Result<Vec<u8>>
+{
+    let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0];
+    match host.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>()
+    {
+        Ok(IpAddr::V4(ip)) =>
+        {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) =>
+        {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) =>
+        {
+            if host.is_empty() || host.len() > 255
+            {
+                return Err(Error::new(ErrorKind::InvalidInput, "hostname too long for SOCKS5"));
+            }
+            request.push(ATYP_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    let mut buf = [0; 2];
+    BigEndian::write_u16(&mut buf, port);
+    request.extend_from_slice(&buf);
+    Ok(request)
+}
+
+fn reply_error(code: u8) -> &'static str
+{
+    match code
+    {
+        1 => { "proxy: general failure" }
+        2 => { "proxy: connection not allowed by ruleset" }
+        3 => { "proxy: network unreachable" }
+        4 => { "proxy: host unreachable" }
+        5 => { "proxy: connection refused" }
+        6 => { "proxy: TTL expired" }
+        7 => { "proxy: command not supported" }
+        8 => { "proxy: address type not supported" }
+        _ => { "proxy: unknown error" }
+    }
+}
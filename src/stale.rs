@@ -0,0 +1,63 @@
+// A TIP THIS MANY BLOCK INTERVALS OLD IS WELL PAST BAD LUCK: EITHER WE'RE CUT
+// OFF FROM THE REST OF THE NETWORK OR EVERY PEER WE HAVE IS STUCK
+const STALE_TIP_INTERVALS: i64 = 10;
+// WHILE THE TIP STAYS STALE, HOW OFTEN WE REACH FOR ANOTHER PEER
+const EXTRA_OUTBOUND_INTERVAL: i64 = 60;
+
+pub struct StaleTipMonitor
+{
+    block_interval: i64,
+    stale:          bool,
+    last_extra:     i64
+}
+
+impl StaleTipMonitor
+{
+    pub fn new(block_interval: i64) -> StaleTipMonitor
+    {
+        StaleTipMonitor {
+            block_interval: block_interval,
+            stale: false,
+            last_extra: 0
+        }
+    }
+
+    pub fn threshold(&self) -> i64
+    {
+        STALE_TIP_INTERVALS * self.block_interval
+    }
+
+    pub fn is_stale(&self) -> bool
+    {
+        self.stale
+    }
+
+    // tip_timestamp AND now ARE BOTH NETWORK TIME. WHILE HEADERS OR BLOCKS ARE
+    // STILL COMING IN AN OLD TIP IS EXPECTED, SO IT DOESN'T COUNT. RETURNS TRUE
+    // WHEN IT'S TIME TO TRY ANOTHER OUTBOUND CONNECTION.
+    pub fn check(&mut self, tip_timestamp: i64, syncing: bool, now: i64) -> bool
+    {
+        let age = now - tip_timestamp;
+        if syncing || age <= self.threshold()
+        {
+            if self.stale
+            {
+                println!("Tip is no longer stale");
+                self.stale = false;
+            }
+            return false;
+        }
+
+        if !self.stale
+        {
+            println!("Warning: no new block for {}s, expected one every {}s. We may be partitioned from the network or all our peers may be stuck.", age, self.block_interval);
+            self.stale = true;
+        }
+        if now - self.last_extra < EXTRA_OUTBOUND_INTERVAL
+        {
+            return false;
+        }
+        self.last_extra = now;
+        true
+    }
+}
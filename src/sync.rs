@@ -0,0 +1,438 @@
+use block::*;
+use database::{Store};
+use message::*;
+use peer::*;
+use inventory::*;
+use util::*;
+
+extern crate mio;
+extern crate chrono;
+
+use self::mio::channel::{Sender};
+
+use self::chrono::*;
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+
+pub const MAX_HEADERS: usize = 2000;
+// HEADERS WE HOLD AT ONCE WHILE THEIR BLOCKS DOWNLOAD; THE REST ARE FETCHED
+// ONCE THOSE ARE DONE
+const MAX_PENDING_HEADERS: usize = 50 * MAX_HEADERS;
+const LOCATOR_DENSE_LEN: usize = 10;
+const DOWNLOAD_WINDOW: usize = 512;
+const MAX_BLOCKS_IN_FLIGHT_PER_PEER: usize = 16;
+const BLOCK_DOWNLOAD_TIMEOUT: i64 = 20;
+const HEADERS_TIMEOUT: i64 = 60;
+
+struct InFlight
+{
+    ip:         String,
+    port:       i32,
+    requested:  i64
+}
+
+// HEADERS ARE FETCHED FROM ONE PEER AT A TIME AND VALIDATED AS A CHAIN BEFORE ANY
+// BODIES ARE REQUESTED. BODIES ARE THEN SPREAD ACROSS ALL PEERS, BUT ONLY WITHIN
+// A WINDOW STARTING AT THE FIRST HEADER WHOSE BLOCK HASN'T BEEN CONNECTED YET,
+// SO A SLOW PEER CAN HOLD UP AT MOST DOWNLOAD_WINDOW BLOCKS.
+pub struct HeaderSync
+{
+    headers:        Vec<Block>,
+    header_hashes:  HashSet<[u8; 32]>,
+    next:           usize,
+    in_flight:      HashMap<[u8; 32], InFlight>,
+    received:       HashMap<[u8; 32], Block>,
+    failed:         HashMap<[u8; 32], (String, i32)>,
+    headers_from:   Option<(String, i32, i64)>,
+    // WE STOPPED TAKING HEADERS AT MAX_PENDING_HEADERS AND THE PEER HAD MORE
+    more_headers:   bool
+}
+
+impl HeaderSync
+{
+    pub fn new() -> HeaderSync
+    {
+        HeaderSync {
+            headers: vec![],
+            header_hashes: HashSet::new(),
+            next: 0,
+            in_flight: HashMap::new(),
+            received: HashMap::new(),
+            failed: HashMap::new(),
+            headers_from: None,
+            more_headers: false
+        }
+    }
+
+    pub fn is_syncing(&self) -> bool
+    {
+        self.headers_from.is_some() || self.next < self.headers.len()
+    }
+
+    pub fn expects(&self, hash: &[u8; 32]) -> bool
+    {
+        self.in_flight.contains_key(hash)
+    }
+
+    pub fn request_headers(
+        &mut self,
+        peer: &mut Peer,
+        db: &dyn Store)
+    {
+        let mut hashes: Vec<[u8; 32]> = main_chain(db).iter().map(|b| b.block_hash).collect();
+        hashes.extend(self.headers[self.next..].iter().map(|h| h.block_hash));
+
+        let msg = NetworkMessage::GetHeaders(locator(&hashes), [0; 32]).to_msg();
+        if peer.send(&msg)
+        {
+            println!("Requested headers from {}:{}", peer.ip, peer.port);
+            self.headers_from = Some((peer.ip.clone(), peer.port, UTC::now().timestamp()));
+        }
+    }
+
+    // RETURNS FALSE IF THE BATCH DOESN'T FORM A CHAIN FROM A KNOWN BLOCK, OR ANY
+    // HEADER FAILS ITS PROOF OF WORK OR CLAIMS A TARGET ITS PARENT DOESN'T ALLOW
+    pub fn rcv_headers(
+        &mut self,
+        headers: Vec<Block>,
+        from: &mut Peer,
+        db: &dyn Store) -> bool
+    {
+        self.headers_from = None;
+
+        let mut prev: Option<&Block> = None;
+        let mut new_headers = vec![];
+        for header in headers.iter()
+        {
+            if !header.verify_header()
+            {
+                println!("Header {} fails proof of work", to_hex_string(&header.block_hash));
+                return false;
+            }
+            let parent_target = match prev
+            {
+                Some(parent) if header.parent_hash == parent.block_hash => { Some(parent.target) }
+                Some(_) => { None }
+                None => { self.target_of(&header.parent_hash, db) }
+            };
+            match parent_target
+            {
+                Some(parent_target) =>
+                {
+                    if header.target > max_target(&parent_target)
+                    {
+                        println!("Header {} claims too easy a target", to_hex_string(&header.block_hash));
+                        return false;
+                    }
+                }
+                None if prev.is_none() && header.parent_hash == [0; 32] && accepts_genesis(header, db) => {}
+                None =>
+                {
+                    println!("Headers from {}:{} don't connect", from.ip, from.port);
+                    return false;
+                }
+            }
+            if !self.knows(&header.block_hash, db)
+            {
+                new_headers.push(header.clone());
+            }
+            prev = Some(header);
+        }
+
+        println!("Received {} headers, {} new", headers.len(), new_headers.len());
+        let room = MAX_PENDING_HEADERS.saturating_sub(self.headers.len());
+        let full = new_headers.len() > room;
+        if full
+        {
+            println!("Holding {} headers already; fetching the rest once their blocks are in", self.headers.len());
+            new_headers.truncate(room);
+        }
+        for header in new_headers
+        {
+            self.header_hashes.insert(header.block_hash);
+            self.headers.push(header);
+        }
+
+        if full
+        {
+            self.more_headers = true;
+        }
+        else if headers.len() == MAX_HEADERS
+        {
+            self.request_headers(from, db);
+        }
+        true
+    }
+
+    // ONLY THE PEER WE ASKED CAN TELL US IT DOESN'T HAVE THE BLOCK
+    pub fn not_found(&mut self, hash: &[u8; 32], ip: &str, port: i32)
+    {
+        let from_requested = match self.in_flight.get(hash)
+        {
+            Some(f) => { f.ip == ip && f.port == port }
+            None => { false }
+        };
+        if from_requested
+        {
+            if let Some(f) = self.in_flight.remove(hash)
+            {
+                println!("Block not found at {}:{}", f.ip, f.port);
+                self.failed.insert(*hash, (f.ip, f.port));
+            }
+        }
+    }
+
+    pub fn request_blocks(
+        &mut self,
+        peers: &mut Vec<Peer>)
+    {
+        if peers.is_empty() { return; }
+
+        let end = cmp::min(self.next + DOWNLOAD_WINDOW, self.headers.len());
+        let mut peer_idx = 0;
+        for i in self.next..end
+        {
+            let hash = self.headers[i].block_hash;
+            if self.received.contains_key(&hash) || self.in_flight.contains_key(&hash)
+            {
+                continue;
+            }
+
+            let failed = self.failed.get(&hash).cloned();
+            let candidate = (0..peers.len())
+                .map(|k| (peer_idx + k) % peers.len())
+                .filter(|&k| peers.len() == 1 || failed.as_ref().map_or(true, |f| f.0 != peers[k].ip || f.1 != peers[k].port))
+                .find(|&k| self.in_flight_count(&peers[k]) < MAX_BLOCKS_IN_FLIGHT_PER_PEER);
+
+            match candidate
+            {
+                Some(k) =>
+                {
+                    let item = InvItem::new(InvKind::Block, &hash);
+                    if peers[k].send(&NetworkMessage::GetData(vec![item]).to_msg())
+                    {
+                        self.in_flight.insert(
+                            hash,
+                            InFlight {
+                                ip: peers[k].ip.clone(),
+                                port: peers[k].port,
+                                requested: UTC::now().timestamp()
+                            });
+                    }
+                    peer_idx = k + 1;
+                }
+                None => { break; }
+            }
+        }
+    }
+
+    // RETURNS FALSE IF THE BLOCK DOESN'T MATCH THE HEADER WE REQUESTED IT FOR,
+    // OR ITS TRANSACTIONS AREN'T THE ONES THE HEADER COMMITS TO
+    pub fn rcv_block(
+        &mut self,
+        mut block: Block,
+        db: &dyn Store,
+        block_snd_to_mine: &Sender<Block>) -> bool
+    {
+        let in_flight = self.in_flight.remove(&block.block_hash);
+        let matches_header = self.headers[self.next..]
+            .iter()
+            .any(|h| h.header_to_vec() == block.header_to_vec());
+
+        if !matches_header || !block.verify_txs_hash() || !block.verify()
+        {
+            if let Some(f) = in_flight
+            {
+                self.failed.insert(block.block_hash, (f.ip, f.port));
+            }
+            return false;
+        }
+
+        self.failed.remove(&block.block_hash);
+        self.received.insert(block.block_hash, block);
+
+        while self.next < self.headers.len()
+        {
+            let hash = self.headers[self.next].block_hash;
+            match self.received.remove(&hash)
+            {
+                Some(block) =>
+                {
+                    let _ = db.insert_block(&block);
+                    let _ = block_snd_to_mine.send(block);
+                    self.next += 1;
+                }
+                None => { break; }
+            }
+        }
+
+        if self.next == self.headers.len() && self.headers_from.is_none()
+        {
+            println!("Block download complete");
+            self.headers.clear();
+            self.header_hashes.clear();
+            self.next = 0;
+        }
+        true
+    }
+
+    pub fn tick(
+        &mut self,
+        peers: &mut Vec<Peer>,
+        db: &dyn Store)
+    {
+        let now = UTC::now().timestamp();
+
+        let stalled: Vec<[u8; 32]> = self.in_flight
+            .iter()
+            .filter(|&(_, f)| now - f.requested > BLOCK_DOWNLOAD_TIMEOUT)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in stalled
+        {
+            if let Some(f) = self.in_flight.remove(&hash)
+            {
+                println!("Block download from {}:{} timed out", f.ip, f.port);
+                self.failed.insert(hash, (f.ip, f.port));
+            }
+        }
+
+        let headers_stalled = match self.headers_from
+        {
+            Some((ref ip, port, requested)) if now - requested > HEADERS_TIMEOUT => {
+                Some((ip.clone(), port))
+            }
+            _ => { None }
+        };
+        if let Some((ip, port)) = headers_stalled
+        {
+            println!("Header download from {}:{} timed out", ip, port);
+            self.headers_from = None;
+            if let Some(peer) = peers.iter_mut().find(|p| p.ip != ip || p.port != port)
+            {
+                self.request_headers(peer, db);
+            }
+        }
+
+        if self.more_headers && !self.is_syncing()
+        {
+            if let Some(peer) = peers.first_mut()
+            {
+                self.more_headers = false;
+                self.request_headers(peer, db);
+            }
+        }
+
+        self.request_blocks(peers);
+    }
+
+    fn target_of(&self, hash: &[u8; 32], db: &dyn Store) -> Option<[u8; 32]>
+    {
+        if self.header_hashes.contains(hash)
+        {
+            return self.headers.iter().rev().find(|h| h.block_hash == *hash).map(|h| h.target);
+        }
+        db.block(hash).map(|b| b.target)
+    }
+
+    fn knows(&self, hash: &[u8; 32], db: &dyn Store) -> bool
+    {
+        self.header_hashes.contains(hash) || db.block(hash).is_some()
+    }
+
+    fn in_flight_count(&self, peer: &Peer) -> usize
+    {
+        self.in_flight.values().filter(|f| f.ip == peer.ip && f.port == peer.port).count()
+    }
+}
+
+// THE BEST CHAIN IS THE LONGEST ONE, FROM GENESIS TO TIP
+// A BLOCK WITH NO PARENT STARTS A CHAIN OF ITS OWN, SO THE ONLY ONE WE TAKE IS
+// OUR OWN GENESIS, OR ANY WHILE WE HAVE NO BLOCKS YET
+pub fn accepts_genesis(block: &Block, db: &dyn Store) -> bool
+{
+    db.block(&block.block_hash).is_some() || db.latest_block().is_none()
+}
+
+pub fn main_chain(db: &dyn Store) -> Vec<Block>
+{
+    main_chain_of(&db.blockchain())
+}
+
+// THE LONGEST CHAIN AMONG blocks, WHICH MUST BE IN THE ORDER THEY WERE
+// STORED; ON A TIE THE BRANCH SEEN FIRST WINS
+pub fn main_chain_of(blocks: &[Block]) -> Vec<Block>
+{
+    let mut heights: HashMap<[u8; 32], usize> = HashMap::new();
+    let mut idxs: HashMap<[u8; 32], usize> = HashMap::new();
+    let mut tip: Option<usize> = None;
+    let mut tip_height = 0;
+    for (i, block) in blocks.iter().enumerate()
+    {
+        let height = heights.get(&block.parent_hash).map_or(0, |h| h + 1);
+        heights.insert(block.block_hash, height);
+        idxs.insert(block.block_hash, i);
+        if tip.is_none() || height > tip_height
+        {
+            tip = Some(i);
+            tip_height = height;
+        }
+    }
+
+    let mut chain = vec![];
+    let mut next = tip;
+    while let Some(i) = next
+    {
+        if chain.len() > blocks.len() { break; }
+        chain.push(blocks[i].clone());
+        next = idxs.get(&blocks[i].parent_hash).cloned();
+    }
+    chain.reverse();
+    chain
+}
+
+// DENSE FOR THE MOST RECENT BLOCKS, THEN EXPONENTIALLY SPARSER BACK TO GENESIS
+pub fn locator(hashes: &[[u8; 32]]) -> Vec<[u8; 32]>
+{
+    let mut locator = vec![];
+    let mut step = 1;
+    let mut idx = hashes.len() as isize - 1;
+    while idx >= 0
+    {
+        locator.push(hashes[idx as usize]);
+        if locator.len() >= LOCATOR_DENSE_LEN { step *= 2; }
+        idx -= step;
+    }
+    if let Some(genesis) = hashes.first()
+    {
+        if locator.last() != Some(genesis)
+        {
+            locator.push(*genesis);
+        }
+    }
+    locator
+}
+
+pub fn headers_after(
+    locator: &[[u8; 32]],
+    stop_hash: &[u8; 32],
+    db: &dyn Store) -> Vec<Block>
+{
+    let chain = main_chain(db);
+    let idxs: HashMap<[u8; 32], usize> = chain.iter().enumerate().map(|(i, b)| (b.block_hash, i)).collect();
+
+    let start = locator
+        .iter()
+        .filter_map(|hash| idxs.get(hash))
+        .next()
+        .map_or(0, |idx| idx + 1);
+
+    let mut headers = vec![];
+    for block in chain[start..].iter().take(MAX_HEADERS)
+    {
+        headers.push(block.clone());
+        if &block.block_hash == stop_hash { break; }
+    }
+    headers
+}
@@ -0,0 +1,79 @@
+extern crate chrono;
+use self::chrono::*;
+
+use addrman::*;
+
+fn net_addr(ip: &str, port: i32, timestamp: i64) -> NetAddr
+{
+    NetAddr {
+        ip: ip.to_string(),
+        port: port,
+        services: NODE_NETWORK,
+        timestamp: timestamp
+    }
+}
+
+#[test]
+fn test_addrman_add()
+{
+    let now = UTC::now().timestamp();
+    let mut addrman = AddrMan::new();
+    assert!(addrman.add(&net_addr("10.1.0.1", 9001, now - 60), "10.2.0.1"));
+    assert!(!addrman.add(&net_addr("10.1.0.1", 9001, now - 60), "10.3.0.1"));
+    assert!(addrman.add(&net_addr("10.1.0.1", 9001, now), "10.3.0.1"));
+    assert!(addrman.add(&net_addr("10.1.0.1", 9002, now), "10.2.0.1"));
+    assert!(addrman.len() == 2);
+    assert!(addrman.tried_len() == 0);
+
+    addrman.good("10.1.0.1", 9001);
+    assert!(addrman.len() == 2);
+    assert!(addrman.tried_len() == 1);
+
+    let selected = addrman.select(|info| info.port == 9002).unwrap();
+    assert!(selected.ip == "10.1.0.1" && selected.port == 9001 && selected.tried);
+    assert!(addrman.select(|_| true).is_none());
+}
+
+#[test]
+fn test_addrman_source_flood()
+{
+    // ONE SOURCE GROUP CAN ONLY EVER FILL A FEW OF THE NEW BUCKETS
+    let now = UTC::now().timestamp();
+    let mut addrman = AddrMan::new();
+    for i in 0..5000
+    {
+        let ip = format!("10.{}.{}.1", i / 250, i % 250);
+        addrman.add(&net_addr(&ip, 9001, now), "192.168.0.1");
+    }
+    assert!(addrman.len() <= 32 * BUCKET_SIZE);
+    assert!(addrman.len() > BUCKET_SIZE);
+}
+
+#[test]
+fn test_addrman_tried_eviction()
+{
+    // ONE GROUP ONLY GETS A FEW TRIED BUCKETS; WHO LOSES A SLOT THERE GOES BACK TO NEW
+    let mut addrman = AddrMan::new();
+    for i in 0..1000
+    {
+        addrman.good(&format!("10.1.{}.{}", i / 250, i % 250), 9001);
+    }
+    assert!(addrman.tried_len() <= 8 * BUCKET_SIZE);
+    assert!(addrman.tried_len() > BUCKET_SIZE);
+    assert!(addrman.len() > addrman.tried_len());
+    assert!(addrman.select(|info| !info.tried).map_or(false, |info| info.tried));
+}
+
+#[test]
+fn test_addr_roundtrip()
+{
+    let addrs = vec![
+        net_addr("10.1.0.1", 9001, 1500000000),
+        net_addr("2001:db8::1", 9002, 1500000001)
+    ];
+    let bytes = addrs_to_vec(&addrs);
+    assert!(bytes.len() == 2 + 2 * NET_ADDR_LEN);
+    assert!(addrs_from_slice(&bytes) == Some(addrs));
+    assert!(addrs_from_slice(&bytes[..bytes.len()-1]).is_none());
+    assert!(addrs_from_slice(&[]).is_none());
+}
@@ -0,0 +1,35 @@
+use bandwidth::*;
+
+#[test]
+fn test_traffic()
+{
+    let mut traffic = Traffic::new();
+    traffic.record_sent(b"addb        ", 1000);
+    traffic.record_sent(b"inv         ", 60);
+    traffic.record_recv(b"inv         ", 60);
+    traffic.record_recv(b"getb        ", 80);
+    assert!(traffic.sent == 1060);
+    assert!(traffic.recv == 140);
+    let commands = traffic.commands();
+    assert!(commands[0] == (*b"addb        ", 1000, 0));
+    assert!(commands[1] == (*b"inv         ", 60, 60));
+}
+
+#[test]
+fn test_upload_budget()
+{
+    let unlimited = Bandwidth::new(0, 60);
+    unlimited.record_sent(b"addb        ", 1 << 30);
+    assert!(!unlimited.upload_exhausted());
+
+    let bandwidth = Bandwidth::new(1000, 60);
+    let now = 1000000;
+    bandwidth.record_sent_at(b"addb        ", 600, now);
+    assert!(!bandwidth.upload_exhausted_at(now));
+    bandwidth.record_sent_at(b"cmpctblock  ", 400, now + 1);
+    assert!(bandwidth.upload_exhausted_at(now + 1));
+
+    // A NEW WINDOW STARTS THE BUDGET OVER BUT NOT THE TOTALS
+    assert!(!bandwidth.upload_exhausted_at(now + 61));
+    assert!(bandwidth.totals().sent == 1000);
+}
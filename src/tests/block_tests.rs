@@ -1,5 +1,8 @@
-// use transaction::*;
-// use block::*;
+use block::*;
+use crypto;
+use transaction::*;
+use tests::tx;
+
 // use util::*;
 
 #[ignore]
@@ -20,3 +23,26 @@ fn test_block()
     // println!("block hash: {:?}", to_hex_string(&block.block_hash));
     // println!("txs hash: {:?}", to_hex_string(&block.txs_hash));
 }
+
+#[test]
+fn test_flat_txs_hash_before_merkle_time()
+{
+    let txs: Vec<Transaction> = (1..4).map(tx).collect();
+    let flat: Vec<u8> = txs.iter().flat_map(|tx| tx.hash.to_vec()).collect();
+    let mut block = Block::new_minable(txs, &[0; 32], &[0xff; 32], 0);
+    let merkle = block.txs_hash;
+    assert!(merkle.to_vec() != crypto::digest_sha256(&flat));
+
+    // A BLOCK MINED BEFORE THE SWITCH STILL VERIFIES WITH THE HASH IT WAS MINED WITH
+    block.txs_hash.clone_from_slice(&crypto::digest_sha256(&flat));
+    block.timestamp = MERKLE_TXS_HASH_TIME - 1;
+    assert!(block.verify_txs_hash());
+    block.timestamp = MERKLE_TXS_HASH_TIME;
+    assert!(!block.verify_txs_hash());
+
+    // THE MERKLE ROOT IS GOOD EITHER SIDE OF IT
+    block.txs_hash = merkle;
+    assert!(block.verify_txs_hash());
+    block.timestamp = MERKLE_TXS_HASH_TIME - 1;
+    assert!(block.verify_txs_hash());
+}
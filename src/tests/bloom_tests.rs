@@ -0,0 +1,57 @@
+use bloom::*;
+use block::*;
+use transaction::*;
+use tests::{tx, tx_to};
+
+#[test]
+fn test_murmur3()
+{
+    assert!(murmur3(0, &[]) == 0);
+    assert!(murmur3(0xfba4c795, &[]) == 0x6a396f08);
+    assert!(murmur3(0, &[0x00]) == 0x514e28b7);
+    assert!(murmur3(0, &[0x00, 0x11, 0x22]) == 0x8eb51c3d);
+    assert!(murmur3(0, &[0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77]) == 0x8034d2a0);
+}
+
+#[test]
+fn test_bloom_filter()
+{
+    let mut filter = BloomFilter::new(10, 0.0001, 5);
+    filter.insert(&[7; 32]);
+    assert!(filter.contains(&[7; 32]));
+    assert!(!filter.contains(&[8; 32]));
+    assert!(BloomFilter::from_slice(&filter.to_vec()) == Some(filter.clone()));
+    assert!(BloomFilter::from_slice(&filter.to_vec()[..8]).is_none());
+
+    // A MATCHED OUTPUT'S SPEND MATCHES TOO
+    assert!(filter.matches(&tx_to(1, 7)));
+    assert!(!filter.matches(&tx_to(2, 9)));
+    let mut spend = tx_to(3, 9);
+    spend.inputs.push(TxInput::new(&[1; 32], 0));
+    assert!(filter.matches(&spend));
+}
+
+#[test]
+fn test_filtered_block()
+{
+    let txs: Vec<Transaction> = (1..6).map(tx).collect();
+    let mut block = Block::new_minable(txs, &[0; 32], &[0xff; 32], 0);
+    block.update_hash();
+    assert!(block.verify_txs_hash());
+
+    let mut filter = BloomFilter::new(10, 0.0001, 0);
+    filter.insert(&[2; 32]);
+    filter.insert(&[5; 32]);
+    let filtered = FilteredBlock::new(&block, &mut filter);
+    assert!(filtered.tx_count == 5);
+    assert!(filtered.txs.iter().map(|&(_, ref proof)| proof.idx).collect::<Vec<u32>>() == vec![1, 4]);
+    assert!(filtered.verify());
+
+    let parsed = FilteredBlock::from_slice(&filtered.to_vec()).unwrap();
+    assert!(parsed.header.block_hash == block.block_hash);
+    assert!(parsed.verify());
+
+    let mut forged = filtered.clone();
+    forged.txs[0].0.hash = [9; 32];
+    assert!(!forged.verify());
+}
@@ -0,0 +1,65 @@
+use chat::*;
+use handshake::*;
+
+extern crate mio;
+use self::mio::{Token};
+
+#[test]
+fn test_chat_message()
+{
+    let identity = Identity::generate();
+    let chat = ChatMessage::new("hello network", &identity);
+    assert!(chat.verify());
+
+    let parsed = ChatMessage::from_slice(&chat.to_vec()).unwrap();
+    assert!(parsed == chat);
+    assert!(parsed.verify());
+
+    let mut forged = chat.clone();
+    forged.text = "goodbye network".to_string();
+    assert!(!forged.verify());
+
+    assert!(ChatMessage::from_slice(&chat.to_vec()[1..]).is_none());
+}
+
+#[test]
+fn test_chat_log()
+{
+    let identity = Identity::generate();
+    let chat = ChatMessage::new("hi", &identity);
+    let mut log = ChatLog::new();
+    assert!(log.insert(&chat));
+    assert!(!log.insert(&chat));
+
+    let mut stale = ChatMessage::new("hi", &identity);
+    stale.timestamp -= 60 * 60;
+    assert!(!log.insert(&stale));
+}
+
+#[test]
+fn test_chat_rate()
+{
+    let identity = Identity::generate();
+    let chat = ChatMessage::new("hi", &identity);
+    let mut log = ChatLog::new();
+    let now = 1500000000;
+
+    for _ in 0..MAX_CHATS_PER_SENDER
+    {
+        assert!(log.check_rate(&chat, Token(0), now).is_ok());
+    }
+    assert!(log.check_rate(&chat, Token(0), now) == Err(ChatFlood::Sender));
+    // THE WINDOW SLIDES
+    assert!(log.check_rate(&chat, Token(0), now + 60).is_ok());
+
+    // ONE CONNECTION RELAYING FOR MANY SENDERS
+    let mut log = ChatLog::new();
+    for _ in 0..MAX_CHATS_PER_CONNECTION
+    {
+        let other = ChatMessage::new("hi", &Identity::generate());
+        assert!(log.check_rate(&other, Token(0), now).is_ok());
+    }
+    let other = ChatMessage::new("hi", &Identity::generate());
+    assert!(log.check_rate(&other, Token(0), now) == Err(ChatFlood::Connection));
+    assert!(log.check_rate(&other, Token(1), now).is_ok());
+}
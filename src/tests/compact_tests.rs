@@ -0,0 +1,39 @@
+use compact::*;
+use block::*;
+use tests::tx;
+
+#[test]
+fn test_compact_block_roundtrip()
+{
+    let mut block = Block::new_minable(vec![tx(1), tx(2), tx(3)], &[0; 32], &[0xff; 32], 42);
+    block.update_hash();
+
+    let compact = CompactBlock::new(&block);
+    let parsed = CompactBlock::from_slice(&compact.to_vec()).unwrap();
+    assert!(parsed.header.block_hash == block.block_hash);
+    assert!(parsed.nonce == compact.nonce);
+    assert!(parsed.short_ids == compact.short_ids);
+    assert!(CompactBlock::from_slice(&compact.to_vec()[1..]).is_none());
+}
+
+#[test]
+fn test_compact_block_reconstruct()
+{
+    let mut block = Block::new_minable(vec![tx(1), tx(2), tx(3)], &[0; 32], &[0xff; 32], 42);
+    block.update_hash();
+    let compact = CompactBlock::new(&block);
+
+    let mut partial = compact.reconstruct(&[tx(3), tx(1), tx(9)]);
+    assert!(partial.missing() == vec![1]);
+    assert!(!partial.fill(vec![]));
+    assert!(partial.fill(vec![tx(2)]));
+    assert!(partial.finish().unwrap().txs == block.txs);
+
+    // THE WRONG TRANSACTION IN A SLOT FAILS THE txs_hash CHECK
+    let mut partial = compact.reconstruct(&[tx(1), tx(3)]);
+    assert!(partial.fill(vec![tx(4)]));
+    assert!(partial.finish().is_none());
+
+    let (hash, indexes) = indexes_from_slice(&indexes_to_vec(&block.block_hash, &[1, 5])).unwrap();
+    assert!(hash == block.block_hash && indexes == vec![1, 5]);
+}
@@ -0,0 +1,89 @@
+use config::*;
+
+fn args(line: &str) -> Vec<String>
+{
+    line.split_whitespace().map(|s| s.to_string()).collect()
+}
+
+#[test]
+fn test_config_from_args()
+{
+    let config = Config::from_args(args("9002 --idle-timeout 30 --ping-interval 5").into_iter()).unwrap();
+    assert!(config.port == 9002);
+    assert!(config.idle_timeout == 30);
+    assert!(config.ping_interval == 5);
+    assert!(config.ping_timeout == Config::new().ping_timeout);
+
+    assert!(Config::from_args(args("--ping-timeout nope").into_iter()).err() == Some(ConfigError::InvalidValue("ping-timeout".to_string(), "nope".to_string())));
+    assert!(Config::from_args(args("9002 --encrypt yes").into_iter()).err() == Some(ConfigError::InvalidValue("encrypt".to_string(), "yes".to_string())));
+
+    assert!(Config::from_args(args("90o2").into_iter()).err() == Some(ConfigError::InvalidPort("90o2".to_string())));
+    assert!(Config::from_args(args("70000").into_iter()).is_err());
+}
+
+#[test]
+fn test_config_listen_addrs()
+{
+    let config = Config::from_args(args("9002").into_iter()).unwrap();
+    assert!(config.listen_addrs() == vec!["127.0.0.1:9002".parse().unwrap()]);
+    assert!(config.advertised_addr() == "127.0.0.1:9002".parse().unwrap());
+
+    let config = Config::from_args(args("9002 --listen 0.0.0.0 --listen [::]:9003 --external 203.0.113.5").into_iter()).unwrap();
+    assert!(config.listen_addrs() == vec!["0.0.0.0:9002".parse().unwrap(), "[::]:9003".parse().unwrap()]);
+    assert!(config.advertised_addr() == "203.0.113.5:9002".parse().unwrap());
+    assert!(config.is_self("203.0.113.5", 9002));
+    assert!(config.is_self("::1", 9003));
+    assert!(config.is_self("::ffff:127.0.0.1", 9002));
+    assert!(!config.is_self("127.0.0.1", 9004));
+
+    let config = Config::from_args(args("9002 --listen [::]").into_iter()).unwrap();
+    assert!(config.advertised_addr() == "[::1]:9002".parse().unwrap());
+}
+
+#[test]
+fn test_config_pins()
+{
+    let key = "ab".repeat(32);
+    let config = Config::from_args(args(&format!("9002 --encrypt true --pin 10.0.0.1:9001={} --pin 10.0.0.2:9001=abcd", key)).into_iter()).unwrap();
+    assert!(config.encrypt);
+    assert!(config.pinned_key("10.0.0.1", 9001) == Some([0xab; 32]));
+    assert!(config.pinned_key("10.0.0.1", 9002) == None);
+    assert!(config.pinned_key("10.0.0.2", 9001) == None);
+}
+
+#[test]
+fn test_config_allowlist()
+{
+    let config = Config::from_args(args("9002").into_iter()).unwrap();
+    assert!(!config.permissioned());
+    assert!(config.is_allowed(&[1; 32]));
+
+    let config = Config::from_args(args(&format!("9002 --allow {} --allow {}", "01".repeat(32), "02".repeat(32))).into_iter()).unwrap();
+    assert!(config.permissioned());
+    assert!(config.require_handshake());
+    assert!(config.is_allowed(&[1; 32]));
+    assert!(config.is_allowed(&[2; 32]));
+    assert!(!config.is_allowed(&[3; 32]));
+}
+
+#[test]
+fn test_config_upload_budget()
+{
+    let config = Config::from_args(args("9002").into_iter()).unwrap();
+    assert!(config.upload_budget == 0);
+
+    let config = Config::from_args(args("9002 --upload-budget 5000000 --upload-window 3600").into_iter()).unwrap();
+    assert!(config.upload_budget == 5000000);
+    assert!(config.upload_window == 3600);
+}
+
+#[test]
+fn test_config_proxy()
+{
+    assert!(Config::from_args(args("9002").into_iter()).unwrap().proxy == None);
+    let config = Config::from_args(args("9002 --proxy 127.0.0.1").into_iter()).unwrap();
+    assert!(config.proxy == Some("127.0.0.1:1080".parse().unwrap()));
+    let config = Config::from_args(args("9002 --proxy 127.0.0.1:9050").into_iter()).unwrap();
+    assert!(config.proxy == Some("127.0.0.1:9050".parse().unwrap()));
+    assert!(Config::from_args(args("9002 --proxy localhost:9050").into_iter()).err() == Some(ConfigError::InvalidProxy("localhost:9050".to_string())));
+}
@@ -0,0 +1,18 @@
+use discovery::*;
+
+#[test]
+fn test_beacon_roundtrip()
+{
+    let beacon = Beacon {
+        magic: BEACON_MAGIC,
+        port: 9001,
+        node_id: 0x0123456789abcdef
+    };
+    let bytes = beacon.to_vec();
+    assert!(bytes.len() == BEACON_LEN);
+    assert!(Beacon::from_slice(&bytes) == Some(beacon));
+    assert!(Beacon::from_slice(&bytes[1..]).is_none());
+
+    let other_network = Beacon { magic: 0, port: 9001, node_id: 1 };
+    assert!(Beacon::from_slice(&other_network.to_vec()).is_none());
+}
@@ -0,0 +1,42 @@
+extern crate mio;
+use self::mio::{Token};
+
+use eviction::*;
+
+fn candidate(token: usize, ip: &str, connected: i64, latency_ms: Option<u64>, last_block: i64) -> EvictionCandidate
+{
+    EvictionCandidate {
+        token: Token(token),
+        ip: ip.to_string(),
+        connected: connected,
+        latency_ms: latency_ms,
+        last_block: last_block
+    }
+}
+
+#[test]
+fn test_select_eviction_prefers_crowded_ip()
+{
+    let mut candidates = vec![];
+    for i in 0..4
+    {
+        candidates.push(candidate(10 + i, "10.0.0.1", 100, Some(5), 0));
+        candidates.push(candidate(20 + i, "10.0.0.2", 100, None, 50));
+    }
+    candidates.push(candidate(30, "10.0.0.3", 200, None, 0));
+    candidates.push(candidate(31, "10.0.0.4", 150, None, 0));
+    candidates.push(candidate(32, "10.0.0.4", 300, None, 0));
+
+    assert!(select_eviction(candidates) == Some(Token(32)));
+}
+
+#[test]
+fn test_select_eviction_protected()
+{
+    let candidates = vec![
+        candidate(10, "10.0.0.1", 100, Some(5), 0),
+        candidate(11, "10.0.0.1", 200, None, 50)
+    ];
+    assert!(select_eviction(candidates) == None);
+    assert!(select_eviction(vec![]) == None);
+}
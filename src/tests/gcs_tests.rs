@@ -0,0 +1,44 @@
+use gcs::*;
+use block::*;
+use bloom::*;
+use message::*;
+use transaction::*;
+
+#[test]
+fn test_gcs_filter()
+{
+    let items: Vec<Vec<u8>> = (0..100u8).map(|n| vec![n; 32]).collect();
+    let filter = GcsFilter::new(&[1; 32], &items);
+    assert!(filter.n == 100);
+    for item in items.iter()
+    {
+        assert!(filter.contains(&[1; 32], item));
+    }
+    assert!(!filter.contains(&[1; 32], &[200; 32]));
+    assert!(filter.match_any(&[1; 32], &[vec![200; 32], vec![50; 32]]));
+    assert!(!filter.match_any(&[1; 32], &[vec![200; 32], vec![201; 32]]));
+    assert!(GcsFilter::from_slice(&filter.to_vec()) == Some(filter.clone()));
+    assert!(!GcsFilter::new(&[1; 32], &[]).contains(&[1; 32], &[0; 32]));
+}
+
+#[test]
+fn test_block_filter()
+{
+    let mut tx = Transaction::new_with_hash(&[1; 32], &[0; 32], 0);
+    tx.inputs.push(TxInput::new(&[2; 32], 3));
+    tx.outputs.push(TxOutput::new(100, &[4; 32]));
+    let mut block = Block::new_minable(vec![tx], &[0; 32], &[0xff; 32], 0);
+    block.update_hash();
+
+    let filter = GcsFilter::for_block(&block);
+    assert!(filter.n == 2);
+    assert!(filter.contains(&block.block_hash, &[4; 32]));
+    assert!(filter.contains(&block.block_hash, &outpoint(&[2; 32], 3)));
+    assert!(!filter.contains(&block.block_hash, &[5; 32]));
+
+    match NetworkMessage::from_msg(&NetworkMessage::CFilters(vec![(block.block_hash, filter.clone())]).to_msg())
+    {
+        Ok(NetworkMessage::CFilters(filters)) => { assert!(filters == vec![(block.block_hash, filter)]); }
+        _ => { panic!("expected cfilters"); }
+    }
+}
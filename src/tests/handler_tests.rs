@@ -0,0 +1,84 @@
+use ban::*;
+use handler::*;
+use message::*;
+use network::*;
+use sim::*;
+
+extern crate mio;
+use self::mio::{Token};
+
+use std::sync::{Arc, Mutex};
+
+fn ignore(_: &Msg, _: &mut NodeContext) -> Result<(), Misbehaviour>
+{
+    Ok(())
+}
+
+#[test]
+fn test_default_handlers()
+{
+    let mut handlers = default_handlers();
+    for command in COMMANDS.iter()
+    {
+        assert!(handlers.register(command, ignore));
+    }
+    assert!(!handlers.register(b"datasync    ", ignore));
+}
+
+#[test]
+fn test_register_handler()
+{
+    let mut handlers = HandlerRegistry::new();
+    assert!(!handlers.register(b"datasync    ", |msg: &Msg, _: &mut NodeContext| -> Result<(), Misbehaviour> {
+        if msg.payload.is_empty() { Err(Misbehaviour::MalformedMessage) } else { Ok(()) }
+    }));
+    assert!(handlers.register(b"datasync    ", ignore));
+}
+
+#[test]
+fn test_dispatch()
+{
+    let mut sim = Simulation::line(2, 5);
+    let seen = Arc::new(Mutex::new(vec![]));
+    let handler_seen = seen.clone();
+    sim.nodes[1].handlers.register(b"datasync    ", move |msg: &Msg, ctx: &mut NodeContext| -> Result<(), Misbehaviour> {
+        handler_seen.lock().unwrap().push((ctx.token, msg.payload.clone()));
+        if msg.payload.is_empty() { Err(Misbehaviour::MalformedMessage) } else { Ok(()) }
+    });
+
+    {
+        let (mut ctx, handlers) = sim.nodes[1].context(Token(0));
+        let ok = handlers.dispatch(&Msg::new(b"datasync    ", vec![1, 2, 3]), &mut ctx);
+        assert!(ok == Some(Ok(())));
+        let err = handlers.dispatch(&Msg::new(b"datasync    ", vec![]), &mut ctx);
+        assert!(err == Some(Err(Misbehaviour::MalformedMessage)));
+        assert!(handlers.dispatch(&Msg::new(b"unknown     ", vec![]), &mut ctx).is_none());
+    }
+    assert!(*seen.lock().unwrap() == vec![(Token(0), vec![1, 2, 3]), (Token(0), vec![])]);
+
+    // OVER THE WIRE THE Err COUNTS AGAINST THE SENDER
+    sim.send(0, 1, &Msg::new(b"datasync    ", vec![]));
+    sim.run();
+    assert!(seen.lock().unwrap().len() == 3);
+    assert!(sim.nodes[1].clients[&Token(0)].misbehaviour == Misbehaviour::MalformedMessage.score());
+}
+
+#[test]
+fn test_split_message()
+{
+    let mut sim = Simulation::line(2, 6);
+
+    // HALF A MESSAGE WAITS FOR THE REST INSTEAD OF FAILING ITS LENGTH CHECK
+    let bytes = NetworkMessage::Ping(7).to_msg().to_vec();
+    sim.send_bytes(0, 1, &bytes[..MSG_HEADER_LEN + 3]);
+    sim.step();
+    assert!(sim.in_flight(1, 0).is_empty());
+    sim.send_bytes(0, 1, &bytes[MSG_HEADER_LEN + 3..]);
+    sim.step();
+    match sim.in_flight(1, 0).first()
+    {
+        Some(&NetworkMessage::Pong(7)) => {}
+        _ => { panic!("expected pong"); }
+    }
+    assert!(sim.nodes[1].clients[&Token(0)].misbehaviour == 0);
+}
@@ -0,0 +1,73 @@
+use handshake::*;
+use message::*;
+
+fn handshake(
+    initiator: &Identity,
+    responder: &Identity,
+    pin: Option<&[u8; KEY_LEN]>) -> Result<(Session, Session), HandshakeError>
+{
+    let (init, hello) = Initiator::new(initiator)?;
+    let (resp, ack) = Responder::new(responder, &hello)?;
+    let (sending, fin, remote) = init.finish(&ack, pin)?;
+    assert!(remote == responder.public_key);
+    let (receiving, remote) = resp.finish(&fin)?;
+    assert!(remote == initiator.public_key);
+    Ok((sending, receiving))
+}
+
+#[test]
+fn test_handshake_session()
+{
+    let a = Identity::generate();
+    let b = Identity::generate();
+    let (mut sending, mut receiving) = handshake(&a, &b, Some(&b.public_key)).unwrap();
+
+    let ping = sending.seal(&NetworkMessage::Ping(7).to_msg().to_vec());
+    let mut frames = ping.clone();
+    frames.extend_from_slice(&sending.seal(&NetworkMessage::Pong(7).to_msg().to_vec()));
+    assert!(&receiving.read_msg(&mut frames).unwrap().unwrap().command == b"ping        ");
+    assert!(&receiving.read_msg(&mut frames).unwrap().unwrap().command == b"pong        ");
+    assert!(frames.is_empty());
+
+    // A FLIPPED BIT OR A REPLAYED FRAME FAILS AUTHENTICATION
+    let mut frame = sending.seal(b"payload");
+    frame[6] ^= 1;
+    assert!(receiving.open(&mut frame).is_err());
+    assert!(receiving.open(&mut ping.clone()).is_err());
+}
+
+#[test]
+fn test_session_split_frame()
+{
+    let a = Identity::generate();
+    let b = Identity::generate();
+    let (mut sending, mut receiving) = handshake(&a, &b, None).unwrap();
+
+    // A FRAME THAT ARRIVES A BYTE AT A TIME ONLY OPENS ONCE IT'S ALL THERE,
+    // AND THE ONE AFTER IT STILL AUTHENTICATES
+    let mut buf = vec![];
+    for byte in sending.seal(&NetworkMessage::Ping(1).to_msg().to_vec())
+    {
+        assert!(receiving.read_msg(&mut buf).unwrap().is_none());
+        buf.push(byte);
+    }
+    assert!(&receiving.read_msg(&mut buf).unwrap().unwrap().command == b"ping        ");
+    let mut pong = sending.seal(&NetworkMessage::Pong(1).to_msg().to_vec());
+    assert!(&receiving.read_msg(&mut pong).unwrap().unwrap().command == b"pong        ");
+}
+
+#[test]
+fn test_handshake_rejects_wrong_key()
+{
+    let a = Identity::generate();
+    let b = Identity::generate();
+    let c = Identity::generate();
+    assert!(handshake(&a, &b, Some(&c.public_key)).err() == Some(HandshakeError::PinMismatch));
+
+    let (_, hello) = Initiator::new(&a).unwrap();
+    let (resp, _) = Responder::new(&b, &hello).unwrap();
+    let (init, hello) = Initiator::new(&c).unwrap();
+    let (_, ack) = Responder::new(&b, &hello).unwrap();
+    let (_, fin, _) = init.finish(&ack, None).unwrap();
+    assert!(resp.finish(&fin).err() == Some(HandshakeError::BadSignature));
+}
@@ -0,0 +1,33 @@
+use inventory::*;
+
+#[test]
+fn test_inventory_roundtrip()
+{
+    let items = vec![
+        InvItem::new(InvKind::Block, &[1; 32]),
+        InvItem::new(InvKind::Transaction, &[2; 32])
+    ];
+    let bytes = items_to_vec(&items);
+    assert!(bytes.len() == 4 + 2 * INV_ITEM_LEN);
+    assert!(items_from_slice(&bytes) == Some(items));
+
+    assert!(items_from_slice(&bytes[..bytes.len()-1]).is_none());
+    assert!(items_from_slice(&[]).is_none());
+
+    let mut bad_kind = bytes.clone();
+    bad_kind[4] = 9;
+    assert!(items_from_slice(&bad_kind).is_none());
+}
+
+#[test]
+fn test_inventory_requests()
+{
+    let mut requests = InvRequests::new();
+    assert!(requests.should_request(&[1; 32], "1.2.3.4", 9001));
+    assert!(!requests.should_request(&[1; 32], "5.6.7.8", 9001));
+    // SOMEONE WE DIDN'T ASK CAN'T CANCEL THE REQUEST
+    requests.received(&[1; 32], "5.6.7.8", 9001);
+    assert!(!requests.should_request(&[1; 32], "5.6.7.8", 9001));
+    requests.received(&[1; 32], "1.2.3.4", 9001);
+    assert!(requests.should_request(&[1; 32], "5.6.7.8", 9001));
+}
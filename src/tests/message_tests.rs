@@ -0,0 +1,109 @@
+use message::*;
+use query::*;
+use transaction::*;
+
+fn roundtrip(message: NetworkMessage) -> NetworkMessage
+{
+    let msg = message.to_msg();
+    assert!(msg.length as usize == msg.payload.len());
+    NetworkMessage::from_msg(&msg).unwrap()
+}
+
+#[test]
+fn test_message_roundtrip()
+{
+    match roundtrip(NetworkMessage::Ping(7))
+    {
+        NetworkMessage::Ping(nonce) => { assert!(nonce == 7); }
+        _ => { panic!("expected ping"); }
+    }
+    match roundtrip(NetworkMessage::AddPeer("127.0.0.1:8333".parse().unwrap(), 1500000000))
+    {
+        NetworkMessage::AddPeer(addr, timestamp) => { assert!(addr.to_string() == "127.0.0.1:8333" && timestamp == 1500000000); }
+        _ => { panic!("expected addp"); }
+    }
+    match roundtrip(NetworkMessage::GetHeaders(vec![[1; 32], [2; 32]], [3; 32]))
+    {
+        NetworkMessage::GetHeaders(locator, stop_hash) =>
+        {
+            assert!(locator == vec![[1; 32], [2; 32]]);
+            assert!(stop_hash == [3; 32]);
+        }
+        _ => { panic!("expected getheaders"); }
+    }
+    match roundtrip(NetworkMessage::Query(9, Query::ListBlocks(5, 10)))
+    {
+        NetworkMessage::Query(id, Query::ListBlocks(start, count)) => { assert!(id == 9 && start == 5 && count == 10); }
+        _ => { panic!("expected lisb"); }
+    }
+    match roundtrip(NetworkMessage::Mempool)
+    {
+        NetworkMessage::Mempool => {}
+        _ => { panic!("expected mempool"); }
+    }
+}
+
+#[test]
+fn test_response_roundtrip()
+{
+    let responses = vec![
+        Response::Balance(-3),
+        Response::Valid(true),
+        Response::Height(42),
+        Response::Latest(None),
+        Response::BlockHashes(5, vec![[1; 32], [2; 32]]),
+        Response::Echo(b"ping".to_vec())
+    ];
+    for (id, response) in responses.into_iter().enumerate()
+    {
+        match roundtrip(NetworkMessage::Response(id as u64, response.clone()))
+        {
+            NetworkMessage::Response(rid, decoded) => { assert!(rid == id as u64 && decoded == response); }
+            _ => { panic!("expected resp"); }
+        }
+    }
+}
+
+// THE REQUEST USED TO GO OUT AS vdlt WHILE ITS ANSWER CAME BACK AS vldt
+#[test]
+fn test_validate_kind_matches()
+{
+    let tx = Transaction::new(vec![], vec![TxOutput::new(42, &[1; 32])], 0);
+    let request = NetworkMessage::Query(1, Query::Validate(tx)).to_msg();
+    assert!(&request.command == b"vldt        ");
+    let response = NetworkMessage::Response(1, Response::Valid(false)).to_msg();
+    assert!(&response.payload[..12] == b"vldt        ");
+}
+
+#[test]
+fn test_message_rejects_malformed()
+{
+    let mut msg = NetworkMessage::Ping(7).to_msg();
+    msg.length = 20;
+    assert!(NetworkMessage::from_msg(&msg).err() == Some(DecodeError::Malformed));
+
+    assert!(NetworkMessage::from_msg(&Msg::new(b"ping        ", vec![0; 4])).err() == Some(DecodeError::Malformed));
+    assert!(NetworkMessage::from_msg(&Msg::new(b"mempool     ", vec![0; 4])).err() == Some(DecodeError::Malformed));
+    assert!(NetworkMessage::from_msg(&Msg::new(b"geth        ", vec![0; 4])).err() == Some(DecodeError::Malformed));
+    assert!(NetworkMessage::from_msg(&Msg::new(b"resp        ", vec![0; 4])).err() == Some(DecodeError::Malformed));
+    assert!(NetworkMessage::from_msg(&Msg::new(b"vdlt        ", vec![0; 8])).err() == Some(DecodeError::UnknownCommand));
+}
+
+#[test]
+fn test_message_from_buffer()
+{
+    let bytes = NetworkMessage::Ping(7).to_msg().to_vec();
+    let mut buf = bytes[..MSG_HEADER_LEN + 3].to_vec();
+    assert!(Msg::from_buffer(&mut buf, MAX_PAYLOAD_LEN).unwrap().is_none());
+    assert!(buf.len() == MSG_HEADER_LEN + 3);
+
+    // THE REST, AND THE START OF THE NEXT MESSAGE
+    buf.extend_from_slice(&bytes[MSG_HEADER_LEN + 3..]);
+    buf.extend_from_slice(&bytes[..4]);
+    let msg = Msg::from_buffer(&mut buf, MAX_PAYLOAD_LEN).unwrap().unwrap();
+    assert!(NetworkMessage::from_msg(&msg).is_ok());
+    assert!(buf == bytes[..4].to_vec());
+
+    assert!(Msg::from_buffer(&mut bytes.clone(), 4).is_err());
+    assert!(Msg::from_stream(&mut &bytes[..bytes.len()-1]).is_err());
+}
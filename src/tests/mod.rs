@@ -1,3 +1,21 @@
+#[cfg(test)]
+use transaction::*;
+
+// A TRANSACTION WITH THE MADE UP HASH [n; 32], PAYING 100 TO address
+#[cfg(test)]
+pub fn tx_to(n: u8, address: u8) -> Transaction
+{
+    let mut tx = Transaction::new_with_hash(&[n; 32], &[0; 32], n as i64);
+    tx.outputs.push(TxOutput::new(100, &[address; 32]));
+    tx
+}
+
+#[cfg(test)]
+pub fn tx(n: u8) -> Transaction
+{
+    tx_to(n, n)
+}
+
 #[cfg(test)]
 mod transaction_tests;
 
@@ -9,3 +27,66 @@ mod network_tests;
 
 #[cfg(test)]
 mod crypto_tests;
+
+#[cfg(test)]
+mod sync_tests;
+
+#[cfg(test)]
+mod inventory_tests;
+
+#[cfg(test)]
+mod config_tests;
+
+#[cfg(test)]
+mod eviction_tests;
+
+#[cfg(test)]
+mod util_tests;
+
+#[cfg(test)]
+mod addrman_tests;
+
+#[cfg(test)]
+mod persistent_tests;
+
+#[cfg(test)]
+mod discovery_tests;
+
+#[cfg(test)]
+mod handshake_tests;
+
+#[cfg(test)]
+mod compact_tests;
+
+#[cfg(test)]
+mod chat_tests;
+
+#[cfg(test)]
+mod query_tests;
+
+#[cfg(test)]
+mod message_tests;
+
+#[cfg(test)]
+mod handler_tests;
+
+#[cfg(test)]
+mod sim_tests;
+
+#[cfg(test)]
+mod bloom_tests;
+
+#[cfg(test)]
+mod gcs_tests;
+
+#[cfg(test)]
+mod bandwidth_tests;
+
+#[cfg(test)]
+mod socks_tests;
+
+#[cfg(test)]
+mod timedata_tests;
+
+#[cfg(test)]
+mod stale_tests;
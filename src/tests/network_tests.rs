@@ -1,30 +1,251 @@
-use network::*;
+use bandwidth::*;
 use block::*;
-use transaction::*;
-use std::env;
+use bloom::*;
+use database::{Store};
+use inventory::*;
+use message::*;
+use sim::*;
+use tests::tx;
 
-extern crate mio;
-use self::mio::channel::{channel};
+extern crate chrono;
+use self::chrono::*;
 
-use std::thread;
+extern crate mio;
+use self::mio::{Token};
 
-#[ignore]
 #[test]
 fn test_network()
 {
-    let (transaction_snd_to_mine, _) = channel::<Transaction>();
-    let (block_snd_to_mine, _) = channel::<Block>();
-    let (_, block_rcv_from_mine) = channel::<Block>();
-    let (quit_snd, quit_rcv) = channel::<()>();
-    let network_child = thread::spawn(move || {
-        start_server(
-            env::args().nth(1),
-            quit_rcv,
-            transaction_snd_to_mine,
-            block_snd_to_mine,
-            block_rcv_from_mine);
-    });
-
-    let _ = quit_snd.send(());
-    let _ = network_child.join();
+    let mut sim = Simulation::new(3, 3);
+    sim.set_latency(1, 5);
+    sim.connect(0, 1);
+    for _ in 0..5
+    {
+        sim.mine(0);
+        sim.run();
+    }
+    assert_eq!(sim.nodes[1].height(), 5);
+
+    // NODE 2 ONLY HEARS OF THE NEXT BLOCK, AND SYNCS THE HEADERS AND BODIES
+    // BEFORE IT FROM NODE 1
+    sim.connect(1, 2);
+    let tip = sim.mine(0);
+    sim.run();
+    assert_eq!(sim.tips(), vec![tip; 3]);
+    assert_eq!(sim.nodes[2].height(), 6);
+    assert!(!sim.nodes[2].sync.is_syncing());
+}
+
+#[test]
+fn test_invalid_block_bans_sender()
+{
+    let mut sim = Simulation::line(2, 4);
+    let mut block = Block::new_minable(vec![], &sim.nodes[0].tip(), &[0xff; 32], 1);
+    block.update_hash();
+    block.nonce += 1;
+    sim.send(0, 1, &NetworkMessage::AddBlock(block).to_msg());
+    sim.run();
+
+    assert!(sim.nodes[1].db.bans.borrow().contains_key(&sim_ip(0)));
+    assert!(!sim.nodes[1].clients.contains_key(&Token(0)));
+    assert!(sim.nodes[1].peers.is_empty());
+    assert_eq!(sim.nodes[1].height(), 0);
+}
+
+#[test]
+fn test_synced_block_must_match_txs_hash()
+{
+    let mut sim = Simulation::line(2, 4);
+    let mut block = Block::new_minable(vec![tx(1)], &sim.nodes[0].tip(), &[0xff; 32], 1);
+    block.update_hash();
+    let mut header = block.clone();
+    header.txs = vec![];
+
+    // NODE 1 ASKS FOR THE BODY, BUT GETS ONE WITH OTHER TRANSACTIONS FIRST
+    sim.send(0, 1, &NetworkMessage::Headers(vec![header]).to_msg());
+    sim.step();
+    assert!(sim.nodes[1].sync.expects(&block.block_hash));
+    block.txs = vec![tx(2)];
+    sim.send(0, 1, &NetworkMessage::AddBlock(block).to_msg());
+    sim.run();
+
+    assert!(sim.nodes[1].db.bans.borrow().contains_key(&sim_ip(0)));
+    assert_eq!(sim.nodes[1].height(), 0);
+}
+
+// A BLOCK ON parent WHOSE PROOF OF WORK MEETS target
+fn mined(parent: &[u8; 32], target: &[u8; 32], nonce: i64) -> Block
+{
+    let mut block = Block::new_minable(vec![], parent, target, nonce);
+    block.update_hash();
+    while !block.verify_header()
+    {
+        block.nonce += 1;
+        block.update_hash();
+    }
+    block
+}
+
+#[test]
+fn test_headers_from_another_genesis()
+{
+    let mut sim = Simulation::line(2, 5);
+    let genesis = mined(&[0; 32], &[0xff; 32], 7);
+    let _ = sim.nodes[0].db.insert_block(&genesis);
+    sim.send(0, 1, &NetworkMessage::Headers(vec![genesis.clone()]).to_msg());
+    sim.run();
+    assert!(sim.nodes[1].db.block(&genesis.block_hash).is_none());
+}
+
+#[test]
+fn test_headers_must_meet_parent_target()
+{
+    let mut sim = Simulation::line(2, 6);
+    let hard = mined(&sim.nodes[0].tip(), &[0x0f; 32], 1);
+    for node in sim.nodes.iter()
+    {
+        let _ = node.db.insert_block(&hard);
+    }
+
+    let next = mined(&hard.block_hash, &max_target(&hard.target), 2);
+    let easy = mined(&hard.block_hash, &[0xff; 32], 3);
+    let _ = sim.nodes[0].db.insert_block(&next);
+    let _ = sim.nodes[0].db.insert_block(&easy);
+    sim.send(0, 1, &NetworkMessage::Headers(vec![next.clone()]).to_msg());
+    sim.run();
+    assert!(sim.nodes[1].db.block(&next.block_hash).is_some());
+    sim.send(0, 1, &NetworkMessage::Headers(vec![easy.clone()]).to_msg());
+    sim.run();
+    assert!(sim.nodes[1].db.block(&easy.block_hash).is_none());
+}
+
+// THE HASHES node ANNOUNCES WHEN from ASKS FOR ITS MEMPOOL
+fn mempool_inv(sim: &mut Simulation, from: usize, node: usize) -> Vec<[u8; 32]>
+{
+    sim.send(from, node, &NetworkMessage::Mempool.to_msg());
+    sim.step();
+    let mut hashes = vec![];
+    for message in sim.in_flight(node, from)
+    {
+        match message
+        {
+            NetworkMessage::Inv(items) => { hashes.extend(items.iter().map(|item| item.hash)); }
+            _ => { panic!("expected inv"); }
+        }
+    }
+    hashes
+}
+
+#[test]
+fn test_mempool_inventory()
+{
+    let mut sim = Simulation::line(2, 6);
+    for n in 1..4
+    {
+        let _ = sim.nodes[1].db.insert_transaction(&tx(n));
+    }
+    let mut block = Block::new_minable(vec![tx(4)], &sim.nodes[1].tip(), &[0xff; 32], 1);
+    block.update_hash();
+    let _ = sim.nodes[1].db.insert_block(&block);
+    sim.nodes[1].peers[0].mark_known(&[3; 32]);
+
+    // ONLY WHAT'S PENDING AND NOT ALREADY KNOWN TO THE PEER IS ANNOUNCED
+    assert!(mempool_inv(&mut sim, 0, 1) == vec![[1; 32], [2; 32]]);
+
+    // A PEER WITH A FILTER ONLY HEARS OF WHAT MATCHES IT
+    let mut sim = Simulation::line(2, 6);
+    for n in 1..4
+    {
+        let _ = sim.nodes[1].db.insert_transaction(&tx(n));
+    }
+    let mut filter = BloomFilter::new(10, 0.0001, 5);
+    filter.insert(&[2; 32]);
+    sim.send(0, 1, &NetworkMessage::FilterLoad(filter).to_msg());
+    sim.step();
+    assert!(mempool_inv(&mut sim, 0, 1) == vec![[2; 32]]);
+}
+
+#[test]
+fn test_filterload_without_peer()
+{
+    // A LIGHT CLIENT BEHIND NAT: WE CAN'T DIAL IT BACK, SO THERE'S NO PEER
+    let mut sim = Simulation::line(2, 7);
+    sim.nodes[1].peers.clear();
+    let mut filter = BloomFilter::new(10, 0.0001, 5);
+    filter.insert(&[2; 32]);
+    sim.send(0, 1, &NetworkMessage::FilterLoad(filter).to_msg());
+    sim.step();
+    assert!(sim.nodes[1].clients[&Token(0)].filter.is_some());
+
+    sim.send(0, 1, &NetworkMessage::FilterClear.to_msg());
+    sim.step();
+    assert!(sim.nodes[1].clients[&Token(0)].filter.is_none());
+}
+
+// WHAT node ANSWERS WHEN from ASKS IT FOR hash
+fn getdata(sim: &mut Simulation, from: usize, node: usize, hash: &[u8; 32]) -> Vec<NetworkMessage>
+{
+    sim.run();
+    sim.send(from, node, &NetworkMessage::GetData(vec![InvItem::new(InvKind::Block, hash)]).to_msg());
+    sim.step();
+    sim.in_flight(node, from)
+}
+
+#[test]
+fn test_upload_budget_serves_only_recent_blocks()
+{
+    let mut sim = Simulation::line(2, 8);
+    let old = sim.mine(1);
+    sim.run();
+    let mut recent = Block::new_minable(vec![], &old, &[0xff; 32], 99);
+    recent.timestamp = UTC::now().timestamp();
+    recent.update_hash();
+    let _ = sim.nodes[1].db.insert_block(&recent);
+
+    sim.nodes[1].bandwidth = Bandwidth::new(1000, 60);
+    sim.nodes[1].bandwidth.record_sent(b"addb        ", 1000);
+
+    match getdata(&mut sim, 0, 1, &old).as_slice()
+    {
+        [NetworkMessage::NotFound(items)] => { assert!(items[0].hash == old); }
+        _ => { panic!("expected notfound"); }
+    }
+    match getdata(&mut sim, 0, 1, &recent.block_hash).as_slice()
+    {
+        [NetworkMessage::AddBlock(block)] => { assert!(block.block_hash == recent.block_hash); }
+        _ => { panic!("expected addb"); }
+    }
+}
+
+// THE FILTERS node SENDS BACK FOR THE CHAIN FROM start UP TO stop
+fn getcfilters(sim: &mut Simulation, from: usize, node: usize, start: u64, stop: &[u8; 32]) -> Vec<[u8; 32]>
+{
+    sim.run();
+    sim.send(from, node, &NetworkMessage::GetCFilters(start, *stop).to_msg());
+    sim.step();
+    match sim.in_flight(node, from).as_slice()
+    {
+        [NetworkMessage::CFilters(filters)] => { filters.iter().map(|&(hash, _)| hash).collect() }
+        _ => { panic!("expected cfilters"); }
+    }
+}
+
+#[test]
+fn test_upload_budget_cfilters_have_no_gaps()
+{
+    let mut sim = Simulation::line(2, 8);
+    let genesis = sim.nodes[1].tip();
+    let old = sim.mine(1);
+    sim.run();
+    let mut recent = Block::new_minable(vec![], &old, &[0xff; 32], 99);
+    recent.timestamp = UTC::now().timestamp();
+    recent.update_hash();
+    let _ = sim.nodes[1].db.insert_block(&recent);
+    assert!(getcfilters(&mut sim, 0, 1, 0, &recent.block_hash) == vec![genesis, old, recent.block_hash]);
+
+    // THE OLD BLOCKS AREN'T SERVED, SO NEITHER IS THE RECENT ONE AFTER THEM
+    sim.nodes[1].bandwidth = Bandwidth::new(1000, 60);
+    sim.nodes[1].bandwidth.record_sent(b"addb        ", 1000);
+    assert!(getcfilters(&mut sim, 0, 1, 0, &recent.block_hash).is_empty());
+    assert!(getcfilters(&mut sim, 0, 1, 2, &recent.block_hash) == vec![recent.block_hash]);
 }
@@ -0,0 +1,62 @@
+extern crate chrono;
+use self::chrono::*;
+
+use persistent::*;
+
+#[test]
+fn test_backoff()
+{
+    for _ in 0..100
+    {
+        let first = backoff(0);
+        assert!(first >= 4 && first <= 6);
+        let third = backoff(2);
+        assert!(third >= 15 && third <= 25);
+        let capped = backoff(40);
+        assert!(capped >= 450 && capped <= 750);
+    }
+}
+
+#[test]
+fn test_persistent_peers_due()
+{
+    let now = UTC::now().timestamp();
+    let mut persistent = PersistentPeers::new();
+    persistent.add("10.0.0.1", 9001);
+    persistent.add("10.0.0.1", 9001);
+    persistent.add("10.0.0.2", 9001);
+    assert!(persistent.due(now).len() == 2);
+
+    persistent.failed("10.0.0.1", 9001);
+    assert!(persistent.due(now) == vec![("10.0.0.2".to_string(), 9001)]);
+    assert!(persistent.due(now + 10).len() == 2);
+
+    persistent.connected("10.0.0.1", 9001, now);
+    assert!(persistent.connections() == vec![("10.0.0.1".to_string(), 9001)]);
+    persistent.disconnected("10.0.0.1", 9001, now + MIN_CONNECTED_SECS);
+    assert!(persistent.connections().is_empty());
+    persistent.failed("10.0.0.1", 9001);
+    assert!(persistent.due(now + 10).len() == 2);
+}
+
+#[test]
+fn test_persistent_peers_quick_drop()
+{
+    let now = UTC::now().timestamp();
+    let mut persistent = PersistentPeers::new();
+    persistent.add("10.0.0.1", 9001);
+
+    // EVERY CONNECTION THAT DROPS STRAIGHT AWAY BACKS OFF FURTHER
+    for failures in 0..3
+    {
+        persistent.connected("10.0.0.1", 9001, now);
+        persistent.disconnected("10.0.0.1", 9001, now + 1);
+        let delay = backoff(failures);
+        assert!(persistent.due(now + delay / 2).is_empty());
+    }
+    assert!(persistent.due(now + 60).len() == 1);
+
+    // A DROP WE NEVER SAW CONNECT CHANGES NOTHING
+    persistent.disconnected("10.0.0.1", 9001, now + 1);
+    assert!(persistent.due(now + 60).len() == 1);
+}
@@ -0,0 +1,24 @@
+use peer::*;
+use query::*;
+
+#[test]
+fn test_pending_requests()
+{
+    let peer = Peer::new("127.0.0.1".to_string(), 8333, 0, None);
+    let other = Peer::new("127.0.0.2".to_string(), 8333, 0, None);
+    let mut pending = PendingRequests::new();
+
+    let (callback, answer) = callback_channel();
+    let id = pending.register(&peer, callback);
+    assert!(!pending.complete(id, &other, Response::Height(1)));
+    assert!(!pending.complete(id + 1, &peer, Response::Height(1)));
+    assert!(pending.complete(id, &peer, Response::Height(1)));
+    assert!(answer.try_recv().unwrap() == Ok(Response::Height(1)));
+    assert!(!pending.complete(id, &peer, Response::Height(1)));
+
+    let (callback, answer) = callback_channel();
+    let id = pending.register(&peer, callback);
+    pending.expire(&[peer.clone()]);
+    assert!(answer.try_recv().unwrap() == Err(QueryError::Disconnected));
+    assert!(!pending.complete(id, &peer, Response::Height(1)));
+}
@@ -0,0 +1,60 @@
+use database::{Store};
+use sim::*;
+
+#[test]
+fn test_propagation()
+{
+    let mut sim = Simulation::line(5, 1);
+    sim.set_latency(1, 10);
+    let hash = sim.mine(0);
+    sim.run();
+    for node in sim.nodes.iter()
+    {
+        assert_eq!(node.tip(), hash);
+        assert_eq!(node.height(), 1);
+    }
+}
+
+#[test]
+fn test_partition_fork_and_reorg()
+{
+    let mut sim = Simulation::line(4, 2);
+    sim.set_latency(1, 5);
+    sim.mine(0);
+    sim.run();
+
+    sim.partition(&[&[0, 1], &[2, 3]]);
+    sim.mine(0);
+    let short = sim.mine(3);
+    sim.run();
+    let long = sim.mine(0);
+    sim.run();
+    assert_eq!(sim.tips(), vec![long, long, short, short]);
+    assert!(sim.nodes[0].db.latest_block().map(|b| b.block_hash) == Some(long));
+    assert!(sim.nodes[3].db.latest_block().map(|b| b.block_hash) == Some(short));
+
+    sim.heal();
+    let tip = sim.mine(1);
+    sim.run();
+    assert_eq!(sim.tips(), vec![tip; 4]);
+    assert_eq!(sim.nodes[3].height(), 4);
+    assert!(sim.nodes[3].has_block(&short));
+}
+
+#[test]
+fn test_deterministic()
+{
+    let run = |seed: u64| {
+        let mut sim = Simulation::line(6, seed);
+        sim.set_latency(1, 20);
+        sim.set_drop_rate(0.2);
+        for i in 0..10
+        {
+            sim.mine(i % 6);
+            sim.step();
+        }
+        sim.run();
+        (sim.tips(), sim.delivered, sim.dropped, sim.now)
+    };
+    assert!(run(7) == run(7));
+}
@@ -0,0 +1,73 @@
+use socks;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration};
+
+// A ONE-CONNECTION SOCKS5 PROXY THAT ACCEPTS ANY CONNECT, REPORTS WHAT WAS
+// ASKED FOR AND THEN ECHOES
+fn local_proxy(reply: u8) -> (::std::net::SocketAddr, thread::JoinHandle<Vec<u8>>)
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut greeting = [0; 3];
+        stream.read_exact(&mut greeting).unwrap();
+        assert!(greeting == [5, 1, 0]);
+        stream.write_all(&[5, 0]).unwrap();
+
+        let mut head = [0; 5];
+        stream.read_exact(&mut head).unwrap();
+        assert!(head[..4] == [5, 1, 0, 3]);
+        let mut target = vec![0; head[4] as usize + 2];
+        stream.read_exact(&mut target).unwrap();
+        stream.write_all(&[5, reply, 0, 1, 127, 0, 0, 1, 0, 0]).unwrap();
+
+        let mut buf = [0; 4];
+        if reply == 0 && stream.read_exact(&mut buf).is_ok()
+        {
+            stream.write_all(&buf).unwrap();
+        }
+        target
+    });
+    (addr, handle)
+}
+
+fn negotiate(stream: &mut TcpStream, host: &str, port: u16) -> ::std::io::Result<()>
+{
+    let mut negotiation = socks::Negotiation::start(stream, host, port)?;
+    while !negotiation.ready(stream)?
+    {
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_socks_connect()
+{
+    let (proxy, handle) = local_proxy(0);
+    let mut stream = TcpStream::connect(proxy).unwrap();
+    stream.set_nonblocking(true).unwrap();
+    negotiate(&mut stream, "node.example", 9001).unwrap();
+    stream.set_nonblocking(false).unwrap();
+    stream.write_all(b"ping").unwrap();
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf).unwrap();
+    assert!(&buf == b"ping");
+
+    let target = handle.join().unwrap();
+    assert!(&target[..12] == b"node.example");
+    assert!(target[12..] == [0x23, 0x29]);
+}
+
+#[test]
+fn test_socks_refused()
+{
+    let (proxy, handle) = local_proxy(5);
+    let mut stream = TcpStream::connect(proxy).unwrap();
+    assert!(negotiate(&mut stream, "node.example", 9001).is_err());
+    handle.join().unwrap();
+}
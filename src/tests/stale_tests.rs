@@ -0,0 +1,29 @@
+use stale::*;
+
+#[test]
+fn test_stale_tip()
+{
+    let mut monitor = StaleTipMonitor::new(10);
+    let tip = 1000000;
+    assert!(!monitor.check(tip, false, tip + monitor.threshold()));
+    assert!(!monitor.is_stale());
+
+    // STALE: ONE EXTRA CONNECTION NOW, THE NEXT ONLY AFTER A WHILE
+    assert!(monitor.check(tip, false, tip + monitor.threshold() + 1));
+    assert!(monitor.is_stale());
+    assert!(!monitor.check(tip, false, tip + monitor.threshold() + 2));
+    assert!(monitor.check(tip, false, tip + monitor.threshold() + 61));
+
+    // A NEW BLOCK CLEARS IT
+    let tip = tip + 500;
+    assert!(!monitor.check(tip, false, tip + 5));
+    assert!(!monitor.is_stale());
+}
+
+#[test]
+fn test_stale_tip_while_syncing()
+{
+    let mut monitor = StaleTipMonitor::new(10);
+    assert!(!monitor.check(0, true, 1000000));
+    assert!(!monitor.is_stale());
+}
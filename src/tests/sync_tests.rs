@@ -0,0 +1,53 @@
+use sync::*;
+use block::*;
+
+#[test]
+fn test_locator()
+{
+    let hashes: Vec<[u8; 32]> = (0..100).map(|i| [i as u8; 32]).collect();
+    let hashes_locator = locator(&hashes);
+
+    assert!(hashes_locator[0] == [99; 32]);
+    assert!(hashes_locator[9] == [90; 32]);
+    assert!(hashes_locator[10] == [88; 32]);
+    assert!(hashes_locator[11] == [84; 32]);
+    assert!(*hashes_locator.last().unwrap() == [0; 32]);
+    assert!(hashes_locator.len() < 20);
+
+    assert!(locator(&[]).is_empty());
+    assert!(locator(&[[7; 32]]) == vec![[7; 32]]);
+}
+
+#[test]
+fn test_max_target()
+{
+    let mut target = [0; 32];
+    target[2] = 0x7f;
+    let mut max = [0; 32];
+    max[1] = 0x01;
+    max[2] = 0xfc;
+    assert!(max_target(&target) == max);
+    assert!(max_target(&[0x80; 32]) == [0xff; 32]);
+}
+
+#[test]
+fn test_header_roundtrip()
+{
+    let mut block = Block::new_minable(
+        vec![],
+        &[1; 32],
+        &[<u8>::max_value(); 32],
+        0);
+    block.timestamp = 1234;
+    block.update_hash();
+
+    let bytes = block.header_to_vec();
+    assert!(bytes.len() == HEADER_LEN);
+
+    let header = Block::header_from_slice(&bytes);
+    assert!(header == block);
+    assert!(header.verify_header());
+
+    block.nonce += 1;
+    assert!(!block.verify_header());
+}
@@ -0,0 +1,92 @@
+use timedata::*;
+
+#[test]
+fn test_network_time_median()
+{
+    let time = NetworkTime::new();
+    for (i, offset) in [10, -20, 30, 40].iter().enumerate()
+    {
+        time.add_sample(&format!("10.0.0.{}", i), *offset);
+    }
+    // TOO FEW PEERS TO TRUST YET
+    assert!(time.offset() == 0);
+    time.add_sample("10.0.0.4", 50);
+    assert!(time.offset() == 30);
+    time.add_sample("10.0.0.5", 60);
+    assert!(time.offset() == 35);
+}
+
+#[test]
+fn test_network_time_one_sample_per_source()
+{
+    let time = NetworkTime::new();
+    for i in 0..5
+    {
+        time.add_sample("10.0.0.1", 1000 + i);
+    }
+    assert!(time.samples() == 1);
+    assert!(time.offset() == 0);
+}
+
+#[test]
+fn test_network_time_ignores_large_offsets()
+{
+    let time = NetworkTime::new();
+    for i in 0..5
+    {
+        time.add_sample(&format!("10.0.0.{}", i), 2 * 60 * 60);
+    }
+    assert!(time.samples() == 5);
+    assert!(time.offset() == 0);
+
+    let time = NetworkTime::new();
+    for i in 0..5
+    {
+        time.add_sample(&format!("10.0.0.{}", i), -30 * 60);
+    }
+    assert!(time.offset() == -30 * 60);
+}
+
+#[test]
+fn test_network_time_replaces_old_samples()
+{
+    let time = NetworkTime::new();
+    for i in 0..200
+    {
+        time.add_sample(&format!("10.0.{}.{}", i / 256, i % 256), 60 * 60);
+    }
+    assert!(time.offset() == 60 * 60);
+    // NEWER PEERS PUSH THE OLD SAMPLES OUT INSTEAD OF BEING IGNORED
+    for i in 0..200
+    {
+        time.add_sample(&format!("10.1.{}.{}", i / 256, i % 256), 0);
+    }
+    assert!(time.samples() == 200);
+    assert!(time.offset() == 0);
+    // AN EVICTED SOURCE CAN BE SAMPLED AGAIN
+    time.add_sample("10.0.0.0", 10);
+    assert!(time.samples() == 200);
+}
+
+#[test]
+fn test_network_time_extreme_offsets()
+{
+    let time = NetworkTime::new();
+    for i in 0..3
+    {
+        time.add_sample(&format!("10.0.0.{}", i), i64::max_value());
+    }
+    for i in 3..6
+    {
+        time.add_sample(&format!("10.0.0.{}", i), i64::min_value());
+    }
+    assert!(time.samples() == 6);
+    assert!(time.offset() == 0);
+
+    let time = NetworkTime::new();
+    for i in 0..5
+    {
+        time.add_sample(&format!("10.0.0.{}", i), i64::min_value());
+    }
+    assert!(time.offset() == 0);
+}
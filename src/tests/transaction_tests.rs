@@ -4,6 +4,7 @@ use mining;
 use wallet;
 use crypto;
 use database;
+use timedata::*;
 
 extern crate chrono;
 use self::chrono::{UTC};
@@ -55,7 +56,45 @@ fn test_transactions()
         &[<u8>::max_value(); 32], // MAXIMUM TARGET == ZERO MINING DIFFICULTY
         0);
 
-    assert!(mining::mine(&mut block)); // FAILING TO MINE THIS BLOCK SHOULD BE IMPOSSIBLE
+    assert!(mining::mine(&mut block, &NetworkTime::new())); // FAILING TO MINE THIS BLOCK SHOULD BE IMPOSSIBLE
     assert!(database::insert_block(&block, &db).is_ok());
     assert!(wallet::balance(&wallet::get_public_key()) == 21);
 }
+
+#[test]
+fn test_block_txs_in_mined_order()
+{
+    let db = database::conn();
+
+    // NEWEST FIRST, SO ORDERING BY TIMESTAMP WOULD REVERSE THEM
+    let now = UTC::now().timestamp();
+    let txs: Vec<Transaction> = (0..3)
+        .map(|i| Transaction::new(vec![], vec![TxOutput::new(i + 1, &[7; 32])], now - i))
+        .collect();
+    let mut block = Block::new_minable(txs.clone(), &[0; 32], &[<u8>::max_value(); 32], 0);
+    assert!(mining::mine(&mut block, &NetworkTime::new()));
+    assert!(database::insert_block(&block, &db).is_ok());
+
+    let stored = database::full_block(&block.block_hash, &db).unwrap();
+    assert!(stored.txs == txs);
+    assert!(stored.verify_txs_hash());
+}
+
+#[test]
+fn test_transaction_try_from_slice()
+{
+    let tx = Transaction::new(
+        vec![TxInput::new(&[1; 32], 0)],
+        vec![TxOutput::new(42, &[2; 32])],
+        UTC::now().timestamp());
+    let bytes = tx.to_vec();
+
+    assert!(Transaction::try_from_slice(&bytes) == Some(tx.clone()));
+    assert!(Transaction::try_from_slice(&bytes[..bytes.len()-1]).is_none());
+    assert!(Transaction::try_from_slice(&bytes[..10]).is_none());
+
+    let block = Block::new_minable(vec![tx], &[0; 32], &[<u8>::max_value(); 32], 0);
+    let bytes = block.to_vec();
+    assert!(Block::try_from_slice(&bytes) == Some(block));
+    assert!(Block::try_from_slice(&bytes[..bytes.len()-1]).is_none());
+}
@@ -0,0 +1,38 @@
+use util::*;
+
+#[test]
+fn test_parse_addr()
+{
+    assert!(parse_addr(b"10.0.0.1:9001") == Some(("10.0.0.1".to_string(), 9001)));
+    assert!(parse_addr(b"[2001:db8::1]:9001") == Some(("2001:db8::1".to_string(), 9001)));
+    assert!(parse_addr(b"[::ffff:10.0.0.1]:9001") == Some(("10.0.0.1".to_string(), 9001)));
+    assert!(parse_addr(b"2001:db8::1:9001") == None);
+    assert!(parse_addr(b"10.0.0.1") == None);
+
+    assert!(format_addr("10.0.0.1", 9001) == "10.0.0.1:9001");
+    assert!(format_addr("2001:db8::1", 9001) == "[2001:db8::1]:9001");
+    assert!(parse_ip("::1") == Some("::1".to_string()));
+    assert!(parse_ip("localhost") == None);
+}
+
+#[test]
+fn test_parse_host_addr()
+{
+    assert!(parse_host_addr("10.0.0.1:9001") == Some(("10.0.0.1".to_string(), 9001)));
+    assert!(parse_host_addr("[2001:db8::1]:9001") == Some(("2001:db8::1".to_string(), 9001)));
+    assert!(parse_host_addr("Node.Example:9001") == Some(("node.example".to_string(), 9001)));
+    assert!(parse_host_addr("abcdefghijklmnop.onion:9001") == Some(("abcdefghijklmnop.onion".to_string(), 9001)));
+    assert!(parse_host_addr("2001:db8::1:9001") == None);
+    assert!(parse_host_addr("node.example") == None);
+    assert!(parse_host_addr("node example:9001") == None);
+    assert!(parse_host_addr(":9001") == None);
+}
+
+#[test]
+fn test_hex_string()
+{
+    assert!(from_hex_string("00ff10") == Some(vec![0x00, 0xff, 0x10]));
+    assert!(from_hex_string(&to_hex_string(&[1, 2, 254])) == Some(vec![1, 2, 254]));
+    assert!(from_hex_string("abc") == None);
+    assert!(from_hex_string("zz") == None);
+}
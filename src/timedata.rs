@@ -0,0 +1,115 @@
+extern crate chrono;
+
+use self::chrono::*;
+
+use std::cmp;
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+// ENOUGH PEERS THAT A FEW LIARS CAN'T MOVE THE MEDIAN. ONCE FULL THE OLDEST
+// SAMPLE MAKES ROOM, SO EARLY LIARS DON'T STICK AROUND FOREVER.
+const MIN_TIME_SAMPLES: usize = 5;
+const MAX_TIME_SAMPLES: usize = 200;
+// FURTHER OFF THAN THIS AND IT'S OUR CLOCK OR THE NETWORK THAT'S BROKEN, AND
+// WE DON'T FOLLOW IT
+const MAX_TIME_ADJUSTMENT: i64 = 70 * 60;
+const CLOCK_WARNING_SECS: i64 = 5 * 60;
+// A SAMPLE FURTHER OFF THAN THIS IS KEPT AS THIS FAR, WHICH IS ALREADY TOO FAR
+// TO FOLLOW, SO NO TIMESTAMP A PEER SENDS CAN OVERFLOW THE MEDIAN
+const MAX_SAMPLE_OFFSET: i64 = 24 * 60 * 60;
+
+#[derive(Debug)]
+struct TimeData
+{
+    sources:    HashSet<String>,
+    samples:    VecDeque<(String, i64)>,
+    offset:     i64,
+    warned:     bool
+}
+
+// OUR CLOCK CORRECTED BY THE MEDIAN OF WHAT OUR PEERS' CLOCKS SAY. THE
+// NETWORK THREAD ADDS SAMPLES AND THE MINER READS THE TIME, SO BOTH HOLD A
+// CLONE.
+#[derive(Clone, Debug)]
+pub struct NetworkTime
+{
+    data: Arc<Mutex<TimeData>>
+}
+
+impl NetworkTime
+{
+    pub fn new() -> NetworkTime
+    {
+        NetworkTime {
+            data: Arc::new(Mutex::new(TimeData {
+                sources: HashSet::new(),
+                samples: VecDeque::new(),
+                offset: 0,
+                warned: false
+            }))
+        }
+    }
+
+    // offset IS THE PEER'S CLOCK MINUS OURS. ONE SAMPLE PER SOURCE IP, SO A
+    // PEER RECONNECTING OVER AND OVER ONLY COUNTS ONCE.
+    pub fn add_sample(&self, source: &str, offset: i64)
+    {
+        let mut data = self.data.lock().unwrap();
+        if !data.sources.insert(source.to_string())
+        {
+            return;
+        }
+        if data.samples.len() >= MAX_TIME_SAMPLES
+        {
+            match data.samples.pop_front()
+            {
+                Some((oldest, _)) => { data.sources.remove(&oldest); }
+                None => {}
+            }
+        }
+        let offset = cmp::max(-MAX_SAMPLE_OFFSET, cmp::min(offset, MAX_SAMPLE_OFFSET));
+        data.samples.push_back((source.to_string(), offset));
+        if data.samples.len() < MIN_TIME_SAMPLES
+        {
+            return;
+        }
+
+        let offsets: Vec<i64> = data.samples.iter().map(|&(_, offset)| offset).collect();
+        let median = median(&offsets);
+        data.offset = if median.abs() <= MAX_TIME_ADJUSTMENT { median } else { 0 };
+
+        if median.abs() > CLOCK_WARNING_SECS && !data.warned
+        {
+            data.warned = true;
+            println!("Warning: the median peer clock is {}s off from ours. Please check that your computer's date and time are correct.", median);
+            if data.offset == 0
+            {
+                println!("The offset is more than {}s, so it is being ignored", MAX_TIME_ADJUSTMENT);
+            }
+        }
+    }
+
+    pub fn offset(&self) -> i64
+    {
+        self.data.lock().unwrap().offset
+    }
+
+    pub fn samples(&self) -> usize
+    {
+        self.data.lock().unwrap().samples.len()
+    }
+
+    // WHAT EVERYTHING THAT HAS TO AGREE WITH OTHER NODES USES AS "NOW"
+    pub fn now(&self) -> i64
+    {
+        UTC::now().timestamp() + self.offset()
+    }
+}
+
+fn median(values: &[i64]) -> i64
+{
+    let mut sorted = values.to_vec();
+    sorted.sort();
+    let mid = sorted.len() / 2;
+    if sorted.len() % 2 == 0 { sorted[mid - 1] + (sorted[mid] - sorted[mid - 1]) / 2 } else { sorted[mid] }
+}
@@ -250,6 +250,18 @@ impl Transaction
         return valid
     }
 
+    // CHECKS THE LENGTHS FROM_SLICE RELIES ON BEFORE PARSING UNTRUSTED BYTES
+    pub fn try_from_slice(bytes: &[u8]) -> Option<Transaction>
+    {
+        if bytes.len() < 76 { return None; }
+        let ninputs = LittleEndian::read_u32(&bytes[72..76]) as usize;
+        let outputs_idx = 76 + ninputs * size_of::<TxInput>();
+        if bytes.len() < outputs_idx + 4 { return None; }
+        let noutputs = LittleEndian::read_u32(&bytes[outputs_idx..outputs_idx+4]) as usize;
+        if bytes.len() != outputs_idx + 4 + noutputs * size_of::<TxOutput>() { return None; }
+        Some(Transaction::from_slice(bytes))
+    }
+
     pub fn from_slice(bytes: &[u8]) -> Transaction
     {
         let hash = &bytes[..32];
@@ -0,0 +1,188 @@
+extern crate mio;
+
+use std::cmp;
+use std::collections::{VecDeque};
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::net;
+use std::sync::{Arc, Mutex};
+
+// WHERE A PEER'S OUTGOING BYTES GO. OVER THE NETWORK THAT'S A TCP STREAM; IN
+// TESTS AND THE SIMULATOR IT'S A QUEUE IN MEMORY.
+pub trait Transport: fmt::Debug + Send
+{
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()>;
+
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>>;
+}
+
+impl Transport for net::TcpStream
+{
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()>
+    {
+        self.write_all(bytes)?;
+        self.flush()
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>>
+    {
+        net::TcpStream::try_clone(self).map(|stream| Box::new(stream) as Box<dyn Transport>)
+    }
+}
+
+// AN ACCEPTED CONNECTION: WHAT THE REMOTE SENDS US IS READ FROM IT, AND THE
+// HANDSHAKE ANSWERS OVER IT. OVER THE NETWORK THAT'S THE NON-BLOCKING mio
+// STREAM; IN TESTS AND THE SIMULATOR IT'S A MemoryStream.
+pub trait Stream: Read + Write + fmt::Debug + Send
+{
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>>;
+}
+
+impl Stream for mio::tcp::TcpStream
+{
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>>
+    {
+        mio::tcp::TcpStream::try_clone(self).map(|stream| Box::new(stream) as Box<dyn Stream>)
+    }
+}
+
+// ONE WRITE, AS IT WAS HANDED TO A MemoryTransport
+#[derive(Clone, Debug, PartialEq)]
+pub struct Packet
+{
+    pub from:   usize,
+    pub to:     usize,
+    pub bytes:  Vec<u8>
+}
+
+// SHARED BY EVERY MemoryTransport WRITING INTO IT; WHOEVER OWNS IT DECIDES
+// WHEN, AND WHETHER, EACH PACKET ARRIVES
+#[derive(Clone, Debug)]
+pub struct Outbox
+{
+    packets: Arc<Mutex<VecDeque<Packet>>>
+}
+
+impl Outbox
+{
+    pub fn new() -> Outbox
+    {
+        Outbox {
+            packets: Arc::new(Mutex::new(VecDeque::new()))
+        }
+    }
+
+    pub fn transport(&self, from: usize, to: usize) -> MemoryTransport
+    {
+        MemoryTransport {
+            from: from,
+            to: to,
+            outbox: self.clone(),
+            closed: false
+        }
+    }
+
+    pub fn drain(&self) -> Vec<Packet>
+    {
+        self.packets.lock().unwrap().drain(..).collect()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MemoryTransport
+{
+    from:       usize,
+    to:         usize,
+    outbox:     Outbox,
+    pub closed: bool
+}
+
+impl Transport for MemoryTransport
+{
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()>
+    {
+        if self.closed
+        {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "transport closed"));
+        }
+        self.outbox.packets.lock().unwrap().push_back(Packet {
+            from: self.from,
+            to: self.to,
+            bytes: bytes.to_vec()
+        });
+        Ok(())
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>>
+    {
+        Ok(Box::new(self.outbox.transport(self.from, self.to)))
+    }
+}
+
+// THE RECEIVING END OF A MemoryTransport. WHOEVER DELIVERS PACKETS push()ES
+// THEM IN; ONCE IT'S EMPTY A READ WOULD BLOCK, JUST LIKE A DRAINED SOCKET.
+// WRITES GO BACK TO THE REMOTE THROUGH THE OUTBOX.
+#[derive(Clone, Debug)]
+pub struct MemoryStream
+{
+    inbox:  Arc<Mutex<VecDeque<u8>>>,
+    reply:  MemoryTransport
+}
+
+impl MemoryStream
+{
+    // from IS THE NODE THAT HOLDS THIS END, to THE ONE THAT WRITES INTO IT
+    pub fn new(outbox: &Outbox, from: usize, to: usize) -> MemoryStream
+    {
+        MemoryStream {
+            inbox: Arc::new(Mutex::new(VecDeque::new())),
+            reply: outbox.transport(from, to)
+        }
+    }
+
+    pub fn push(&self, bytes: &[u8])
+    {
+        self.inbox.lock().unwrap().extend(bytes.iter());
+    }
+}
+
+impl Read for MemoryStream
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        let mut inbox = self.inbox.lock().unwrap();
+        if inbox.is_empty() && !buf.is_empty()
+        {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "nothing to read"));
+        }
+        let n = cmp::min(buf.len(), inbox.len());
+        for (dst, src) in buf.iter_mut().zip(inbox.drain(..n))
+        {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MemoryStream
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+    {
+        self.reply.write_bytes(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        Ok(())
+    }
+}
+
+impl Stream for MemoryStream
+{
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>>
+    {
+        Ok(Box::new(self.clone()))
+    }
+}
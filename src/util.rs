@@ -1,3 +1,6 @@
+use std::net::{IpAddr, SocketAddr};
+use std::str;
+
 pub const NBYTES_U64: usize = 8;
 pub const NBYTES_U32: usize = 4;
 
@@ -6,3 +9,84 @@ pub fn to_hex_string(bytes: &[u8]) -> String
   let strs: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
   strs.join("")
 }
+
+pub fn from_hex_string(hex: &str) -> Option<Vec<u8>>
+{
+    if hex.len() % 2 != 0 { return None; }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i+2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+// IPV4 PEERS REACHING A DUAL-STACK LISTENER SHOW UP AS ::ffff:a.b.c.d, SO
+// MAP THOSE BACK TO PLAIN IPV4 BEFORE COMPARING OR STORING THEM
+pub fn canonical_ip(ip: IpAddr) -> IpAddr
+{
+    match ip
+    {
+        IpAddr::V6(v6) =>
+        {
+            let s = v6.segments();
+            if s[0] == 0 && s[1] == 0 && s[2] == 0 && s[3] == 0 && s[4] == 0 && s[5] == 0xffff
+            {
+                match v6.to_ipv4()
+                {
+                    Some(v4) => { IpAddr::V4(v4) }
+                    None => { ip }
+                }
+            }
+            else
+            {
+                ip
+            }
+        }
+        IpAddr::V4(_) => { ip }
+    }
+}
+
+pub fn parse_ip(ip: &str) -> Option<String>
+{
+    ip.parse::<IpAddr>().ok().map(|ip| canonical_ip(ip).to_string())
+}
+
+// IPV6 ADDRESSES ARE BRACKETED SO THE PORT SEPARATOR IS UNAMBIGUOUS
+pub fn format_addr(ip: &str, port: i32) -> String
+{
+    match ip.parse::<IpAddr>()
+    {
+        Ok(IpAddr::V6(_)) => { format!("[{}]:{}", ip, port) }
+        _ => { format!("{}:{}", ip, port) }
+    }
+}
+
+// LIKE parse_addr, BUT ALSO TAKES hostname:port. HOSTNAMES ARE KEPT AS THEY
+// ARE SO A PROXY CAN RESOLVE THEM.
+pub fn parse_host_addr(addr: &str) -> Option<(String, i32)>
+{
+    if let Some(parsed) = parse_addr(addr.as_bytes())
+    {
+        return Some(parsed);
+    }
+    let mut parts = addr.rsplitn(2, ':');
+    let port = parts.next().and_then(|port| port.parse::<u16>().ok());
+    let host = parts.next().unwrap_or("");
+    let valid = !host.is_empty() && host.len() <= 255 &&
+        host.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.') &&
+        host.chars().any(|c| c.is_ascii_alphabetic());
+    match port
+    {
+        Some(port) if valid => { Some((host.to_lowercase(), port as i32)) }
+        _ => { None }
+    }
+}
+
+pub fn parse_addr(bytes: &[u8]) -> Option<(String, i32)>
+{
+    let addr = match str::from_utf8(bytes).ok().and_then(|addr| addr.parse::<SocketAddr>().ok())
+    {
+        Some(addr) => { addr }
+        None => { return None; }
+    };
+    Some((canonical_ip(addr.ip()).to_string(), addr.port() as i32))
+}